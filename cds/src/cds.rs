@@ -4,6 +4,8 @@ use std::{
     thread::{self, JoinHandle},
 };
 
+use udp_connection::{Discovery, DiscoveryConfig};
+
 use crate::cds_worker::{CdsWorker, Cell, PeerMapItem};
 
 pub struct Cds {
@@ -14,6 +16,39 @@ pub struct Cds {
 
 impl Cds {
     pub fn new(client_id: u32, address: String, peer_map: Vec<PeerMapItem>) -> Result<Cds, String> {
+        Cds::spawn(client_id, address, peer_map, None)
+    }
+
+    /// Like [`Cds::new`], additionally announcing this node to the nodes of
+    /// `cluster` on the LAN and connecting to the ones it discovers.
+    ///
+    /// Fails without a [`DiscoveryConfig::secret`], anybody on the LAN could
+    /// announce a node otherwise.
+    pub fn with_discovery(
+        client_id: u32,
+        address: String,
+        peer_map: Vec<PeerMapItem>,
+        cluster: &str,
+        config: DiscoveryConfig,
+    ) -> Result<Cds, String> {
+        if config.secret.is_empty() {
+            return Err("Discovery needs a cluster secret".to_string());
+        }
+        let listen = address
+            .parse()
+            .map_err(|x| format!("Bad address {address}: {x}"))?;
+        let discovery = Discovery::new(client_id, cluster, listen, config)
+            .map_err(|x| format!("Discovery {}", x))?;
+
+        Cds::spawn(client_id, address, peer_map, Some(discovery))
+    }
+
+    fn spawn(
+        client_id: u32,
+        address: String,
+        peer_map: Vec<PeerMapItem>,
+        discovery: Option<Discovery>,
+    ) -> Result<Cds, String> {
         let collection = Arc::new(Mutex::new(HashMap::new()));
 
         let collection_thread = Arc::clone(&collection);
@@ -22,7 +57,10 @@ impl Cds {
 
         let worker_handle = thread::spawn(move || {
             let cds_worker = CdsWorker::new(client_id, collection_thread, rx, address, peer_map);
-            if let Ok(cds_worker) = cds_worker {
+            if let Ok(mut cds_worker) = cds_worker {
+                if let Some(discovery) = discovery {
+                    cds_worker = cds_worker.with_discovery(discovery);
+                }
                 cds_worker.work();
             }
         });
@@ -5,13 +5,20 @@ use std::{
         Arc, Mutex,
         mpsc::{Receiver, TryRecvError},
     },
+    time::Duration,
 };
 
-use serde::de::value;
-use udp_connection::socket_worker_handshake::receive_handshake_nonblocking;
+use udp_connection::{
+    Discovery, DiscoveryEvent, socket_worker_handshake::receive_handshake_nonblocking,
+    wait_readable,
+};
 
 use crate::peer::{Peer, PeerResult};
 
+/// Longest sleep between two passes, so keys set locally and discovery
+/// don't wait for a socket to become readable.
+const MAX_WAIT: Duration = Duration::from_millis(10);
+
 pub struct CdsWorker {
     pub client_id: u32,
     peer_map: Vec<PeerMapItem>,
@@ -19,6 +26,7 @@ pub struct CdsWorker {
     peers: Vec<Peer>,
     rx: Receiver<(String, String)>,
     new_peer_socket: UdpSocket,
+    discovery: Option<Discovery>,
 }
 
 impl CdsWorker {
@@ -42,9 +50,16 @@ impl CdsWorker {
             peers: vec![],
             rx,
             new_peer_socket,
+            discovery: None,
         })
     }
 
+    /// Adds the peers `discovery` finds on the LAN to the peer map.
+    pub fn with_discovery(mut self, discovery: Discovery) -> CdsWorker {
+        self.discovery = Some(discovery);
+        self
+    }
+
     pub fn set_key_foreign(
         &self,
         key: String,
@@ -124,6 +139,24 @@ impl CdsWorker {
                     i += 1;
                 }
             }
+
+            self.wait();
+        }
+    }
+
+    /// Sleeps until a peer or a new peer sends something, a peer has a
+    /// timer due, or [`MAX_WAIT`] passed.
+    fn wait(&self) {
+        let timeout = self
+            .peers
+            .iter()
+            .filter_map(Peer::next_timeout)
+            .fold(MAX_WAIT, Duration::min);
+        let mut sockets: Vec<_> = self.peers.iter().map(Peer::socket).collect();
+        sockets.push(&self.new_peer_socket);
+
+        if let Err(e) = wait_readable(&sockets, Some(timeout)) {
+            eprintln!("Wait error: {}", e);
         }
     }
 
@@ -146,7 +179,7 @@ impl CdsWorker {
                 Ok((key, value)) => {
                     self.set_key(key, value)?;
                 }
-                Err(err) if err == TryRecvError::Empty => break,
+                Err(TryRecvError::Empty) => break,
                 // TODO: TryRecvError::Disconnected kill the worker!
                 Err(err) => eprintln!("Error channel {}", err),
             }
@@ -167,7 +200,7 @@ impl CdsWorker {
         if let Some(cell) = cell {
             cell.client_id = self.client_id;
             ver = cell.version;
-            cell.version = cell.version + 1;
+            cell.version += 1;
             cell.value = value.clone();
         } else {
             collection.insert(key.clone(), Cell::new(self.client_id, value.clone()));
@@ -181,12 +214,46 @@ impl CdsWorker {
     }
 
     fn regenerate_peers(&mut self) -> Result<(), String> {
+        self.discover_peers();
         self.accept_new_peer()?;
         self.regenerate_from_map()?;
 
         Ok(())
     }
 
+    fn discover_peers(&mut self) {
+        let Some(discovery) = &mut self.discovery else {
+            return;
+        };
+
+        for event in discovery.work() {
+            match event {
+                Ok(DiscoveryEvent::Discovered(beacon)) => {
+                    let address = beacon.address.to_string();
+                    let item = self
+                        .peer_map
+                        .iter_mut()
+                        .find(|i| i.client_id == beacon.node_id);
+
+                    if let Some(item) = item {
+                        item.address = address;
+                        item.state = PeerMapState::Ok;
+                    } else {
+                        self.peer_map
+                            .push(PeerMapItem::new(address, beacon.node_id));
+                    }
+                }
+                Ok(DiscoveryEvent::Expired(beacon)) => {
+                    let address = beacon.address.to_string();
+                    if let Some(item) = self.peer_map.iter_mut().find(|i| i.address == address) {
+                        item.state = PeerMapState::Inactive;
+                    }
+                }
+                Err(e) => eprintln!("Discovery error: {}", e),
+            }
+        }
+    }
+
     fn accept_new_peer(&mut self) -> Result<(), String> {
         let worker = receive_handshake_nonblocking(&self.new_peer_socket, |_| ())
             .map_err(|x| format!("col lock!\n{}", x))?;
@@ -195,21 +262,18 @@ impl CdsWorker {
 
         let map_item = self.peer_map.iter().find(|i| i.address == address);
 
-        if let None = map_item {
+        if map_item.is_none() {
             return Err(format!(
                 "accept_new_peer: Address {address} is not in the map!"
             ));
             // TODO: reevaluate after automatic peer id implementation!
-        } else if let Some(map_item) = map_item {
-            if self.dont_have_peer_with_addr(&address) {
-                self.peers
-                    .push(Peer::new_from_worker(address, map_item.client_id, worker));
-            }
+        }
 
-            return Ok(());
+        if self.dont_have_peer_with_addr(&address) {
+            self.peers.push(Peer::new_from_worker(address, worker));
         }
 
-        Err("Wtf".to_string())
+        Ok(())
     }
 
     fn regenerate_from_map(&mut self) -> Result<(), String> {
@@ -222,8 +286,10 @@ impl CdsWorker {
 
             let item = &self.peer_map[i];
 
-            if self.dont_have_peer_with_addr(&item.address) {
-                let peer = Peer::new(item.address.clone(), item.client_id)?;
+            if !matches!(item.state, PeerMapState::Inactive)
+                && self.dont_have_peer_with_addr(&item.address)
+            {
+                let peer = Peer::new(item.address.clone())?;
                 self.peers.push(peer);
                 self.peer_map[i].state = PeerMapState::Ok;
             }
@@ -272,7 +338,6 @@ impl PeerMapItem {
 }
 
 enum PeerMapState {
-    Unknown,
     Ok,
     Inactive,
 }
@@ -1,3 +1,5 @@
+use udp_connection::DiscoveryConfig;
+
 use crate::{cds::Cds, cds_worker::PeerMapItem};
 
 mod cds;
@@ -7,7 +9,22 @@ mod cds_worker;
 
 fn main() -> Result<(), String> {
     let remote = PeerMapItem::new("localhost:3001".to_string(), 2);
-    let cds = Cds::new(1, "127.0.0.1:3000".to_string(), vec![remote])?;
+
+    // `cds <cluster> <secret>` finds the other nodes of the cluster on the
+    // LAN, those with the same secret. The unspecified IP is announced as
+    // the one the beacons come from
+    let mut args = std::env::args().skip(1);
+    let cds = match args.next() {
+        Some(cluster) => {
+            let secret = args.next().ok_or("Usage: cds [<cluster> <secret>]")?;
+            let config = DiscoveryConfig {
+                secret: secret.into_bytes(),
+                ..DiscoveryConfig::default()
+            };
+            Cds::with_discovery(1, "0.0.0.0:3000".to_string(), vec![remote], &cluster, config)?
+        }
+        None => Cds::new(1, "127.0.0.1:3000".to_string(), vec![remote])?,
+    };
 
     let val = cds.get_key("A".to_string())?;
 
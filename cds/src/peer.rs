@@ -1,33 +1,35 @@
-use udp_connection::{SocketWorker, send_handshake};
+use std::{net::UdpSocket, time::Duration};
+
+use udp_connection::{
+    ConnectionError, Event, Json, SocketWorker, TypedConnection, TypedEvent,
+    send_handshake_timeout,
+};
 
 use crate::kv_message::KVMessage;
 
+/// How long the worker waits for a peer to answer, a stale address mustn't
+/// block it.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
 pub struct Peer {
     pub address: String,
-    pub id: u32,
     pub is_dead: bool,
-    connect: SocketWorker,
+    connect: TypedConnection<KVMessage, Json>,
 }
 
 impl Peer {
-    pub(crate) fn new(address: String, id: u32) -> Result<Peer, String> {
-        let connect =
-            send_handshake(address.clone(), |_| {}).map_err(|x| format!("send_handshake {}", x))?;
+    pub(crate) fn new(address: String) -> Result<Peer, String> {
+        let connect = send_handshake_timeout(address.clone(), |_| {}, HANDSHAKE_TIMEOUT)
+            .map_err(|x| format!("send_handshake {}", x))?;
 
-        Ok(Peer {
-            address,
-            id,
-            is_dead: false,
-            connect,
-        })
+        Ok(Peer::new_from_worker(address, connect))
     }
 
-    pub(crate) fn new_from_worker(address: String, id: u32, worker: SocketWorker) -> Peer {
+    pub(crate) fn new_from_worker(address: String, worker: SocketWorker) -> Peer {
         Peer {
             address,
-            id,
             is_dead: false,
-            connect: worker,
+            connect: TypedConnection::new(worker, Json),
         }
     }
 
@@ -40,26 +42,42 @@ impl Peer {
     ) -> Result<(), String> {
         let message = KVMessage::new(key, value, client_id, version);
 
-        let message = serde_json::to_string(&message).map_err(|x| format!("To JSON! {}", x))?;
-
         self.connect
-            .send_message(message.as_bytes().to_vec().into_boxed_slice());
+            .send(&message)
+            .map_err(|x| format!("send_message {}", x))?;
 
         Ok(())
     }
 
+    pub(crate) fn socket(&self) -> &UdpSocket {
+        self.connect.worker().socket()
+    }
+
+    /// How long the connection can wait for the socket, see
+    /// [`SocketWorker::next_timeout`].
+    pub(crate) fn next_timeout(&self) -> Option<Duration> {
+        self.connect.worker().next_timeout()
+    }
+
     pub(crate) fn work(&mut self) -> Result<Vec<PeerResult>, String> {
         let msgs = self.connect.work();
         let mut results = vec![];
 
         for msg in msgs {
             match msg {
-                Ok(msg) => results.push(process_message(msg)?),
-                Err(e) => {
-                    eprintln!("Error from peer {e}");
+                Ok(TypedEvent::Message(msg)) => results.push(process_message(msg)),
+                Ok(TypedEvent::Other(Event::StateChanged(state))) if state.is_final() => {
+                    eprintln!("Connection to peer {} {state}", self.address);
+                    self.die();
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) if is_fatal(&e) => {
+                    eprintln!("Error from peer {}: {e}", self.address);
                     self.die();
                     break;
                 }
+                Err(e) => eprintln!("Ignoring error from peer {}: {e}", self.address),
             }
         }
 
@@ -71,18 +89,23 @@ impl Peer {
     }
 }
 
-fn process_message(msg: Box<[u8]>) -> Result<PeerResult, String> {
-    let msg = String::from_utf8(msg.to_vec()).map_err(|x| format!("To String! {}", x))?;
-    let msg: KVMessage = serde_json::from_str(&msg).map_err(|x| format!("From JSON! {}", x))?;
+/// A garbled message or a cancelled stream leaves the connection usable,
+/// a socket error or a closed connection doesn't.
+fn is_fatal(e: &ConnectionError) -> bool {
+    !matches!(
+        e,
+        ConnectionError::Malformed(_)
+            | ConnectionError::Codec(_)
+            | ConnectionError::StreamCancelled(_)
+            | ConnectionError::TooLarge { .. }
+            | ConnectionError::QueueFull
+    )
+}
 
+fn process_message(msg: KVMessage) -> PeerResult {
     // TODO: Check for client id? Mb it is forward?
 
-    Ok(PeerResult::KeyUpdate(
-        msg.key,
-        msg.value,
-        msg.version,
-        msg.client_id,
-    ))
+    PeerResult::KeyUpdate(msg.key, msg.value, msg.version, msg.client_id)
 }
 
 #[allow(dead_code)]
@@ -0,0 +1,113 @@
+use std::rc::Rc;
+
+use bytes::{Bytes, BytesMut};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use udp_connection::{Integrity, Message};
+
+/// The way messages were serialized before `Bytes`: a fresh allocation
+/// through an iterator chain on every (re)send.
+fn legacy_serialize(message: &Message) -> Box<[u8]> {
+    message
+        .id
+        .to_be_bytes()
+        .iter()
+        .chain(message.hash.iter())
+        .chain(message.data.iter())
+        .copied()
+        .collect::<Vec<u8>>()
+        .into_boxed_slice()
+}
+
+/// The way datagrams were received before `Bytes`: hash and payload copied
+/// into new boxes, kept behind an `Rc`, then the payload copied once more
+/// for the caller.
+fn legacy_receive(ser: &[u8]) -> Box<[u8]> {
+    let id = u64::from_be_bytes(ser[0..8].try_into().unwrap());
+    let hash = ser[8..40].to_vec().into_boxed_slice();
+    let data = ser[40..].to_vec().into_boxed_slice();
+    let msg = Rc::new((id, hash, data));
+    let payload = msg.2.to_vec().into_boxed_slice();
+    black_box(msg);
+    payload
+}
+
+fn serialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialize");
+    for size in [64, 1024] {
+        let message = Message::new(1, vec![0x42; size]);
+        let mut buf = BytesMut::with_capacity(2048);
+
+        group.bench_with_input(BenchmarkId::new("legacy", size), &message, |b, m| {
+            b.iter(|| legacy_serialize(black_box(m)))
+        });
+        group.bench_with_input(BenchmarkId::new("serialize", size), &message, |b, m| {
+            b.iter(|| black_box(m).serialize())
+        });
+        group.bench_with_input(BenchmarkId::new("reused_buffer", size), &message, |b, m| {
+            b.iter(|| {
+                buf.clear();
+                black_box(m).serialize_into(&mut buf);
+            })
+        });
+    }
+    group.finish();
+}
+
+/// From the socket's receive buffer to the payload handed to the caller.
+fn receive(c: &mut Criterion) {
+    let mut group = c.benchmark_group("receive");
+    for size in [64, 1024] {
+        let datagram = Message::new(1, vec![0x42; size]).serialize().to_vec();
+
+        group.bench_with_input(BenchmarkId::new("legacy", size), &datagram, |b, d| {
+            b.iter(|| legacy_receive(black_box(d)))
+        });
+        // Mirrors SocketWorker::receive: one copy out of the receive buffer,
+        // shared with the messages inside the datagram from then on.
+        group.bench_with_input(BenchmarkId::new("shared", size), &datagram, |b, d| {
+            b.iter(|| Message::deserialize(Bytes::copy_from_slice(black_box(d))).data)
+        });
+    }
+    group.finish();
+}
+
+fn batch(c: &mut Criterion) {
+    let messages: Vec<_> = (1..=10).map(|id| Message::new(id, vec![0x42; 64])).collect();
+    let mut buf = BytesMut::with_capacity(2048);
+
+    let mut group = c.benchmark_group("batch");
+    group.bench_function("new_batch", |b| {
+        b.iter(|| {
+            let serialized: Vec<Bytes> = messages.iter().map(Message::serialize).collect();
+            Message::new_batch(&serialized).serialize()
+        })
+    });
+    group.bench_function("reused_buffer", |b| {
+        b.iter(|| {
+            buf.clear();
+            Message::begin_batch_into(&mut buf);
+            for message in &messages {
+                message.serialize_batched_into(&mut buf);
+            }
+        })
+    });
+    group.finish();
+}
+
+/// Sealing on send plus checking on receive, per integrity check.
+fn integrity(c: &mut Criterion) {
+    let mut group = c.benchmark_group("integrity");
+    for &integrity in Integrity::supported() {
+        let mut message = Message::new(1, vec![0x42; 1024]);
+        group.bench_function(integrity.name(), |b| {
+            b.iter(|| {
+                message.seal_with(integrity, &[7; 32]);
+                black_box(&message).check_seal_with(integrity, &[7; 32])
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, serialize, receive, batch, integrity);
+criterion_main!(benches);
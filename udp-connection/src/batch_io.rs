@@ -0,0 +1,276 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+};
+
+/// Datagrams read or written per call at most.
+pub(crate) const BATCH_SIZE: usize = 32;
+
+/// Reported as the sender when the kernel gave an address family we don't know.
+const UNKNOWN_ADDR: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// Receive buffers for up to [`BATCH_SIZE`] datagrams of at most `slot`
+/// bytes each.
+pub(crate) struct RecvBatch {
+    buf: Box<[u8]>,
+    slot: usize,
+    lens: [usize; BATCH_SIZE],
+    addrs: [SocketAddr; BATCH_SIZE],
+    count: usize,
+}
+
+impl RecvBatch {
+    pub(crate) fn new(slot: usize) -> RecvBatch {
+        RecvBatch {
+            buf: vec![0; slot * BATCH_SIZE].into_boxed_slice(),
+            slot,
+            lens: [0; BATCH_SIZE],
+            addrs: [UNKNOWN_ADDR; BATCH_SIZE],
+            count: 0,
+        }
+    }
+
+    /// The `i`-th received datagram and its sender.
+    pub(crate) fn get(&self, i: usize) -> (&[u8], SocketAddr) {
+        let start = i * self.slot;
+        (&self.buf[start..start + self.lens[i]], self.addrs[i])
+    }
+
+    fn slot_mut(&mut self, i: usize) -> &mut [u8] {
+        let start = i * self.slot;
+        &mut self.buf[start..start + self.slot]
+    }
+}
+
+/// Reads as many datagrams as are waiting, up to [`BATCH_SIZE`].
+///
+/// Uses a single `recvmmsg` call on Linux when `batched` is set, one
+/// `recv_from` per datagram otherwise.
+///
+/// # Errors
+///
+/// `WouldBlock` if no datagram was waiting.
+pub(crate) fn recv_batch(
+    socket: &UdpSocket,
+    batch: &mut RecvBatch,
+    batched: bool,
+) -> io::Result<usize> {
+    batch.count = 0;
+
+    #[cfg(target_os = "linux")]
+    if batched {
+        return linux::recvmmsg(socket, batch);
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = batched;
+
+    while batch.count < BATCH_SIZE {
+        let i = batch.count;
+        match socket.recv_from(batch.slot_mut(i)) {
+            Ok((len, addr)) => {
+                batch.lens[i] = len;
+                batch.addrs[i] = addr;
+                batch.count += 1;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock && batch.count > 0 => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(batch.count)
+}
+
+/// Sends the datagrams packed back to back in `buf`, each ending at the
+/// matching offset in `ends`.
+///
+/// Uses `sendmmsg` on Linux when `batched` is set, one `send_to` per
+/// datagram otherwise.
+///
+/// # Returns
+///
+/// Number of datagrams handed to the kernel; fewer than requested if the
+/// socket buffer filled up.
+pub(crate) fn send_batch(
+    socket: &UdpSocket,
+    buf: &[u8],
+    ends: &[usize],
+    addr: SocketAddr,
+    batched: bool,
+) -> io::Result<usize> {
+    #[cfg(target_os = "linux")]
+    if batched {
+        return linux::sendmmsg(socket, buf, ends, addr);
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = batched;
+
+    let mut start = 0;
+    for (sent, &end) in ends.iter().enumerate() {
+        match socket.send_to(&buf[start..end], addr) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(sent),
+            Err(e) => return Err(e),
+        }
+        start = end;
+    }
+
+    Ok(ends.len())
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        io, mem,
+        net::{SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+        os::fd::AsRawFd,
+        ptr,
+    };
+
+    use super::{RecvBatch, BATCH_SIZE};
+
+    pub(super) fn recvmmsg(socket: &UdpSocket, batch: &mut RecvBatch) -> io::Result<usize> {
+        // SAFETY: all-zero is a valid value for these plain C structs.
+        let mut names: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+        for i in 0..BATCH_SIZE {
+            let slot = batch.slot_mut(i);
+            iovecs[i].iov_base = slot.as_mut_ptr().cast();
+            iovecs[i].iov_len = slot.len();
+            msgs[i].msg_hdr.msg_name = ptr::addr_of_mut!(names[i]).cast();
+            msgs[i].msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+            msgs[i].msg_hdr.msg_iov = ptr::addr_of_mut!(iovecs[i]);
+            msgs[i].msg_hdr.msg_iovlen = 1;
+        }
+
+        // SAFETY: every header points at a live slot of `batch` and a live
+        // address buffer, all outliving the call.
+        let ret = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                BATCH_SIZE as _,
+                libc::MSG_DONTWAIT,
+                ptr::null_mut(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let count = ret as usize;
+        for i in 0..count {
+            batch.lens[i] = (msgs[i].msg_len as usize).min(batch.slot);
+            batch.addrs[i] = from_sockaddr(&names[i]).unwrap_or(super::UNKNOWN_ADDR);
+        }
+        batch.count = count;
+
+        Ok(count)
+    }
+
+    pub(super) fn sendmmsg(
+        socket: &UdpSocket,
+        buf: &[u8],
+        ends: &[usize],
+        addr: SocketAddr,
+    ) -> io::Result<usize> {
+        let (mut name, name_len) = to_sockaddr(addr);
+        let mut sent = 0;
+
+        while sent < ends.len() {
+            let chunk = &ends[sent..ends.len().min(sent + BATCH_SIZE)];
+            // SAFETY: all-zero is a valid value for these plain C structs.
+            let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+            let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+            let mut start = if sent == 0 { 0 } else { ends[sent - 1] };
+            for (i, &end) in chunk.iter().enumerate() {
+                iovecs[i].iov_base = buf[start..end].as_ptr() as *mut libc::c_void;
+                iovecs[i].iov_len = end - start;
+                msgs[i].msg_hdr.msg_name = ptr::addr_of_mut!(name).cast();
+                msgs[i].msg_hdr.msg_namelen = name_len;
+                msgs[i].msg_hdr.msg_iov = ptr::addr_of_mut!(iovecs[i]);
+                msgs[i].msg_hdr.msg_iovlen = 1;
+                start = end;
+            }
+
+            // SAFETY: the headers point into `buf` and `name`, which outlive
+            // the call; the kernel only reads from them.
+            let ret = unsafe {
+                libc::sendmmsg(
+                    socket.as_raw_fd(),
+                    msgs.as_mut_ptr(),
+                    chunk.len() as _,
+                    libc::MSG_DONTWAIT,
+                )
+            };
+            if ret < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    break;
+                }
+                return Err(e);
+            }
+
+            sent += ret as usize;
+            if (ret as usize) < chunk.len() {
+                break;
+            }
+        }
+
+        Ok(sent)
+    }
+
+    fn to_sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        // SAFETY: all-zero is a valid sockaddr_storage.
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                // SAFETY: sockaddr_storage is large and aligned enough for
+                // any socket address.
+                let sin = unsafe { &mut *ptr::addr_of_mut!(storage).cast::<libc::sockaddr_in>() };
+                sin.sin_family = libc::AF_INET as _;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                // SAFETY: as above.
+                let sin6 = unsafe { &mut *ptr::addr_of_mut!(storage).cast::<libc::sockaddr_in6>() };
+                sin6.sin6_family = libc::AF_INET6 as _;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                sin6.sin6_scope_id = addr.scope_id();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+
+        (storage, len as libc::socklen_t)
+    }
+
+    fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                // SAFETY: the family says the storage holds a sockaddr_in.
+                let sin = unsafe { &*ptr::addr_of!(*storage).cast::<libc::sockaddr_in>() };
+                Some(SocketAddr::V4(SocketAddrV4::new(
+                    sin.sin_addr.s_addr.to_ne_bytes().into(),
+                    u16::from_be(sin.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                // SAFETY: the family says the storage holds a sockaddr_in6.
+                let sin6 = unsafe { &*ptr::addr_of!(*storage).cast::<libc::sockaddr_in6>() };
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    sin6.sin6_addr.s6_addr.into(),
+                    u16::from_be(sin6.sin6_port),
+                    sin6.sin6_flowinfo,
+                    sin6.sin6_scope_id,
+                )))
+            }
+            _ => None,
+        }
+    }
+}
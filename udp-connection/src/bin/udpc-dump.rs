@@ -0,0 +1,299 @@
+//! Decodes capture files written by [`udp_connection::Capture`].
+//!
+//! ```text
+//! udpc-dump <capture> [--conn <addr>] [--id <message id>] [--full]
+//! ```
+//!
+//! `--conn` keeps datagrams whose local or peer address is `addr`, `--id`
+//! keeps datagrams carrying or acknowledging that message. Payloads that are
+//! text, like the JSON encoded CDS `KVMessage`s, are printed as text.
+
+use std::{net::SocketAddr, process};
+
+use udp_connection::{
+    CaptureReader, CaptureRecord, Compression, ControlMessage, Direction, Integrity, Message,
+    WireFormat,
+};
+
+/// Payload characters shown without `--full`.
+const PREVIEW_LEN: usize = 96;
+
+struct Args {
+    path: String,
+    conn: Option<SocketAddr>,
+    id: Option<u64>,
+    full: bool,
+}
+
+/// A decoded datagram, or a message inside a batch.
+enum Frame {
+    Data {
+        id: u64,
+        compressed: bool,
+        stream: bool,
+        fragment: bool,
+        unreliable: bool,
+        /// Sent with the header from before it had a version
+        legacy: bool,
+        /// The unkeyed check of the tag's length matched; MAC sealed traffic
+        /// needs a key the capture does not have
+        hash_ok: bool,
+        valid: bool,
+        payload: Vec<u8>,
+    },
+    Ack { id: u64 },
+    Batch(Vec<Frame>),
+    Probe { size: usize, received: usize },
+    ProbeAck { size: usize },
+    Ping { token: u64 },
+    Pong { token: u64 },
+    Close,
+    Hello(String),
+    Connect(String),
+    Reject(String),
+    Unknown(usize),
+}
+
+fn main() {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            eprintln!("Usage: udpc-dump <capture> [--conn <addr>] [--id <message id>] [--full]");
+            process::exit(2);
+        }
+    };
+
+    let reader = CaptureReader::open(&args.path).unwrap_or_else(|e| {
+        eprintln!("Can't open {}: {e}", args.path);
+        process::exit(1);
+    });
+
+    let mut start = None;
+    for record in reader {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                eprintln!("Broken capture: {e}");
+                process::exit(1);
+            }
+        };
+        let start = *start.get_or_insert(record.time);
+
+        if let Some(conn) = args.conn {
+            if record.local != conn && record.peer != conn {
+                continue;
+            }
+        }
+
+        let frame = decode(&record);
+        if let Some(id) = args.id {
+            if !frame.mentions(id) {
+                continue;
+            }
+        }
+
+        print_record(&record, start, &frame, args.full);
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut path = None;
+    let mut conn = None;
+    let mut id = None;
+    let mut full = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--conn" => {
+                let value = args.next().ok_or("--conn needs an address")?;
+                conn = Some(value.parse().map_err(|e| format!("Bad address '{value}': {e}"))?);
+            }
+            "--id" => {
+                let value = args.next().ok_or("--id needs a message id")?;
+                id = Some(value.parse().map_err(|e| format!("Bad id '{value}': {e}"))?);
+            }
+            "--full" => full = true,
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => return Err(format!("Unexpected argument '{arg}'")),
+        }
+    }
+
+    Ok(Args {
+        path: path.ok_or("No capture file given")?,
+        conn,
+        id,
+        full,
+    })
+}
+
+fn decode(record: &CaptureRecord) -> Frame {
+    let data = &record.data;
+    if let Some(frame) = decode_handshake(data) {
+        return frame;
+    }
+
+    match Message::try_deserialize(data.clone()) {
+        Ok(msg) => decode_message(msg),
+        Err(_) => Frame::Unknown(data.len()),
+    }
+}
+
+/// Handshake messages are text, which doesn't parse as a header.
+fn decode_handshake(data: &[u8]) -> Option<Frame> {
+    let text = String::from_utf8_lossy(data);
+    if let Some(rest) = text.strip_prefix("Hello") {
+        Some(Frame::Hello(rest.trim().to_string()))
+    } else if let Some(rest) = text.strip_prefix("Connect port ") {
+        Some(Frame::Connect(rest.trim().to_string()))
+    } else {
+        text.strip_prefix("Reject ")
+            .map(|reason| Frame::Reject(reason.trim().to_string()))
+    }
+}
+
+fn decode_message(msg: Message) -> Frame {
+    if msg.is_control() {
+        let len = msg.serialized_len();
+        return match msg.try_get_control() {
+            Ok(ControlMessage::Acc { id }) => Frame::Ack { id },
+            Ok(ControlMessage::Batch { messages }) => {
+                Frame::Batch(messages.into_iter().map(decode_message).collect())
+            }
+            Ok(ControlMessage::Probe { size, received }) => Frame::Probe { size, received },
+            Ok(ControlMessage::ProbeAck { size }) => Frame::ProbeAck { size },
+            Ok(ControlMessage::Ping { token }) => Frame::Ping { token },
+            Ok(ControlMessage::Pong { token }) => Frame::Pong { token },
+            Ok(ControlMessage::Close) => Frame::Close,
+            Err(_) => Frame::Unknown(len),
+        };
+    }
+
+    let payload = if msg.compressed {
+        decompress(&msg.data)
+    } else {
+        Some(msg.data.to_vec())
+    };
+
+    Frame::Data {
+        id: msg.id,
+        compressed: msg.compressed,
+        stream: msg.stream,
+        fragment: msg.fragment,
+        unreliable: msg.unreliable,
+        legacy: msg.format == WireFormat::Legacy,
+        hash_ok: check_unkeyed(&msg),
+        valid: payload.is_some(),
+        payload: payload.unwrap_or_else(|| msg.data.to_vec()),
+    }
+}
+
+/// The capture doesn't say which check was agreed on, but the tag length
+/// narrows it down to one without a key.
+fn check_unkeyed(msg: &Message) -> bool {
+    Integrity::supported()
+        .iter()
+        .filter(|i| !i.is_keyed() && i.tag_len() == msg.hash.len())
+        .any(|i| msg.check_seal_with(*i, &[0; 32]))
+}
+
+/// The capture doesn't say which algorithm was agreed on, try them all.
+fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+    Compression::supported()
+        .iter()
+        .find_map(|c| c.decompress(data).ok())
+        .map(Vec::from)
+}
+
+impl Frame {
+    fn mentions(&self, needle: u64) -> bool {
+        match self {
+            Frame::Data { id, .. } | Frame::Ack { id } => *id == needle,
+            Frame::Batch(frames) => frames.iter().any(|f| f.mentions(needle)),
+            _ => false,
+        }
+    }
+}
+
+fn print_record(record: &CaptureRecord, start: std::time::SystemTime, frame: &Frame, full: bool) {
+    let elapsed = record.time.duration_since(start).unwrap_or_default();
+    let arrow = match record.direction {
+        Direction::Sent => "->",
+        Direction::Received => "<-",
+    };
+    println!(
+        "{:>12.6} {} {} {} {} bytes",
+        elapsed.as_secs_f64(),
+        record.local,
+        arrow,
+        record.peer,
+        record.data.len()
+    );
+    print_frame(frame, 1, full);
+}
+
+fn print_frame(frame: &Frame, depth: usize, full: bool) {
+    let indent = "    ".repeat(depth);
+    match frame {
+        Frame::Data {
+            id,
+            compressed,
+            stream,
+            fragment,
+            unreliable,
+            legacy,
+            hash_ok,
+            valid,
+            payload,
+        } => {
+            let mut flags = String::new();
+            if !*hash_ok {
+                flags.push_str(" unverified");
+            }
+            if *legacy {
+                flags.push_str(" legacy");
+            }
+            if *stream {
+                flags.push_str(" stream");
+            }
+            if *fragment {
+                flags.push_str(" fragment");
+            }
+            if *unreliable {
+                flags.push_str(" unreliable");
+            }
+            if *compressed {
+                flags.push_str(if *valid { " compressed" } else { " compressed (undecodable)" });
+            }
+            println!("{indent}#{id}{flags} {}", preview(payload, full));
+        }
+        Frame::Ack { id } => println!("{indent}ACK #{id}"),
+        Frame::Batch(frames) => {
+            println!("{indent}BATCH of {}", frames.len());
+            for frame in frames {
+                print_frame(frame, depth + 1, full);
+            }
+        }
+        Frame::Probe { size, received } => println!("{indent}PROBE {size} ({received} received)"),
+        Frame::ProbeAck { size } => println!("{indent}PROBE-ACK {size}"),
+        Frame::Ping { token } => println!("{indent}PING {token}"),
+        Frame::Pong { token } => println!("{indent}PONG {token}"),
+        Frame::Close => println!("{indent}CLOSE"),
+        Frame::Hello(algs) => println!("{indent}HELLO [{algs}]"),
+        Frame::Connect(args) => println!("{indent}CONNECT port {args}"),
+        Frame::Reject(reason) => println!("{indent}REJECT {reason}"),
+        Frame::Unknown(len) => println!("{indent}UNKNOWN {len} bytes"),
+    }
+}
+
+fn preview(payload: &[u8], full: bool) -> String {
+    match std::str::from_utf8(payload) {
+        Ok(text) if full || text.chars().count() <= PREVIEW_LEN => format!("{text:?}"),
+        Ok(text) => {
+            let cut: String = text.chars().take(PREVIEW_LEN).collect();
+            format!("{cut:?}... ({} bytes)", payload.len())
+        }
+        Err(_) if full || payload.len() <= PREVIEW_LEN / 3 => format!("{payload:02x?}"),
+        Err(_) => format!("{:02x?}... ({} bytes)", &payload[..PREVIEW_LEN / 3], payload.len()),
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+
+/// First bytes of every capture file, the last one is the format version.
+const MAGIC: [u8; 5] = *b"UDPC\x01";
+
+/// Whether a datagram left or arrived at the recording socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Sent => write!(f, ">"),
+            Direction::Received => write!(f, "<"),
+        }
+    }
+}
+
+/// One datagram read back from a capture file.
+#[derive(Debug, Clone)]
+pub struct CaptureRecord {
+    pub time: SystemTime,
+    pub direction: Direction,
+    /// Address of the recording socket
+    pub local: SocketAddr,
+    /// Address of the other end
+    pub peer: SocketAddr,
+    pub data: Bytes,
+}
+
+/// Writes datagrams to a capture file for `udpc-dump`.
+///
+/// Clones write to the same file, so several workers can share one capture.
+///
+/// Every record is:
+/// - Seconds and nanoseconds since the UNIX epoch (u64, u32)
+/// - Direction (u8, 0 = sent, 1 = received)
+/// - Local and peer address (family u8 4/6, IP, port u16)
+/// - Datagram length (u32) and the datagram
+///
+/// All integers are big-endian.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use udp_connection::{send_handshake, Capture};
+/// let capture = Capture::create("replication.udpc").unwrap();
+/// let worker = send_handshake("127.0.0.1:8080".to_string(), |_| {})
+///     .unwrap()
+///     .with_capture(capture);
+/// ```
+#[derive(Clone)]
+pub struct Capture {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl Capture {
+    /// Creates (or truncates) the capture file at `path`.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Capture> {
+        Capture::new(BufWriter::new(File::create(path)?))
+    }
+
+    /// Starts a capture on any writer.
+    pub fn new(writer: impl Write + Send + 'static) -> io::Result<Capture> {
+        let mut writer: Box<dyn Write + Send> = Box::new(writer);
+        writer.write_all(&MAGIC)?;
+
+        Ok(Capture {
+            writer: Arc::new(Mutex::new(writer)),
+        })
+    }
+
+    /// Appends a datagram, stamped with the current time.
+    pub fn record(
+        &self,
+        direction: Direction,
+        local: SocketAddr,
+        peer: SocketAddr,
+        datagram: &[u8],
+    ) -> io::Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut record = Vec::with_capacity(64 + datagram.len());
+        record.extend_from_slice(&time.as_secs().to_be_bytes());
+        record.extend_from_slice(&time.subsec_nanos().to_be_bytes());
+        record.push(match direction {
+            Direction::Sent => 0,
+            Direction::Received => 1,
+        });
+        write_addr(&mut record, local);
+        write_addr(&mut record, peer);
+        record.extend_from_slice(&(datagram.len() as u32).to_be_bytes());
+        record.extend_from_slice(datagram);
+
+        // A writer poisoned by a panicking thread is still a valid writer
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writer.write_all(&record)
+    }
+
+    /// Pushes buffered records to the file.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writer.flush()
+    }
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Capture").finish_non_exhaustive()
+    }
+}
+
+/// Reads the records of a capture file in the order they were written.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use udp_connection::CaptureReader;
+/// for record in CaptureReader::open("replication.udpc").unwrap() {
+///     let record = record.unwrap();
+///     println!("{} {} bytes", record.direction, record.data.len());
+/// }
+/// ```
+pub struct CaptureReader<R> {
+    reader: R,
+}
+
+impl CaptureReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<CaptureReader<BufReader<File>>> {
+        CaptureReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Checks the file header.
+    pub fn new(mut reader: R) -> io::Result<CaptureReader<R>> {
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a udp-connection capture",
+            ));
+        }
+
+        Ok(CaptureReader { reader })
+    }
+
+    fn read_record(&mut self, first: u8) -> io::Result<CaptureRecord> {
+        let mut secs = [0; 8];
+        secs[0] = first;
+        self.reader.read_exact(&mut secs[1..])?;
+        let nanos = u32::from_be_bytes(self.read_array()?);
+        let time = UNIX_EPOCH + Duration::new(u64::from_be_bytes(secs), nanos);
+
+        let direction = match self.read_array::<1>()?[0] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown direction {other}"),
+                ))
+            }
+        };
+        let local = self.read_addr()?;
+        let peer = self.read_addr()?;
+
+        let len = u32::from_be_bytes(self.read_array()?) as usize;
+        let mut data = vec![0; len];
+        self.reader.read_exact(&mut data)?;
+
+        Ok(CaptureRecord {
+            time,
+            direction,
+            local,
+            peer,
+            data: data.into(),
+        })
+    }
+
+    fn read_addr(&mut self) -> io::Result<SocketAddr> {
+        let ip = match self.read_array::<1>()?[0] {
+            4 => IpAddr::V4(Ipv4Addr::from(self.read_array::<4>()?)),
+            6 => IpAddr::V6(Ipv6Addr::from(self.read_array::<16>()?)),
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown address family {other}"),
+                ))
+            }
+        };
+        let port = u16::from_be_bytes(self.read_array()?);
+
+        Ok(SocketAddr::new(ip, port))
+    }
+
+    fn read_array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<CaptureRecord>;
+
+    fn next(&mut self) -> Option<io::Result<CaptureRecord>> {
+        // A clean end of file can only fall between records
+        let mut first = [0];
+        match self.reader.read(&mut first) {
+            Ok(0) => None,
+            Ok(_) => Some(self.read_record(first[0])),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+pub(crate) fn write_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
@@ -0,0 +1,79 @@
+/// Largest payload a compressed message is allowed to expand to, and so
+/// the largest message that can be sent at all.
+pub(crate) const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024;
+
+/// Payload compression agreed on by both peers during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Compression {
+    /// Algorithms this build can offer to a peer, most preferred first.
+    pub fn supported() -> &'static [Compression] {
+        &[
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+        ]
+    }
+
+    /// Name of the algorithm as it is spelled in the handshake.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => "lz4",
+        }
+    }
+
+    /// Looks up a supported algorithm by its handshake name.
+    pub fn from_name(name: &str) -> Option<Compression> {
+        Compression::supported()
+            .iter()
+            .copied()
+            .find(|c| c.name() == name)
+    }
+
+    /// Compresses the payload.
+    ///
+    /// Returns `None` when compression is disabled or would not make the
+    /// payload smaller, in which case the message is sent as is.
+    #[cfg_attr(not(feature = "lz4"), allow(unused_variables))]
+    pub fn compress(&self, data: &[u8]) -> Option<Box<[u8]>> {
+        match self {
+            Compression::None => None,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => shrunk(lz4_flex::compress_prepend_size(data), data),
+        }
+    }
+
+    /// Restores a payload produced by [`Compression::compress`].
+    #[cfg_attr(not(feature = "lz4"), allow(unused_variables))]
+    pub fn decompress(&self, data: &[u8]) -> Result<Box<[u8]>, String> {
+        match self {
+            Compression::None => Err("Compressed payload but no compression agreed".to_string()),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                let (size, rest) = lz4_flex::block::uncompressed_size(data)
+                    .map_err(|e| format!("lz4 header {e}"))?;
+                if size > MAX_DECOMPRESSED_SIZE {
+                    return Err(format!("lz4 payload too big ({size} bytes)"));
+                }
+                lz4_flex::decompress(rest, size)
+                    .map(Vec::into_boxed_slice)
+                    .map_err(|e| format!("lz4 {e}"))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "lz4")]
+fn shrunk(compressed: Vec<u8>, data: &[u8]) -> Option<Box<[u8]>> {
+    if compressed.len() < data.len() {
+        Some(compressed.into_boxed_slice())
+    } else {
+        None
+    }
+}
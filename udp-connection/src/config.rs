@@ -0,0 +1,119 @@
+use std::time::Duration;
+
+use crate::{capture::Capture, delivery::Ttl, integrity::Integrity};
+
+/// What [`SocketWorker::send_message`](crate::SocketWorker::send_message)
+/// does when the outgoing queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Fail with [`SendError::QueueFull`](crate::SendError::QueueFull).
+    Reject,
+    /// Keep working the connection until the peer acknowledged enough, or
+    /// the connection is over.
+    Block,
+    /// Give up on the oldest queued messages, they are reported as
+    /// [`Event::Expired`](crate::Event::Expired).
+    DropOldest,
+}
+
+/// Tunables of a [`SocketWorker`](crate::SocketWorker).
+///
+/// # Examples
+///
+/// ```
+/// # use udp_connection::Config;
+/// let config = Config {
+///     max_datagram_size: 9000,
+///     ..Config::default()
+/// };
+/// assert!(config.mtu_probing);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Datagram size every path is assumed to carry, used until probing
+    /// confirms something larger.
+    pub base_datagram_size: usize,
+    /// Largest datagram the worker will ever send or accept.
+    pub max_datagram_size: usize,
+    /// Probe the path for datagram sizes between `base_datagram_size` and
+    /// `max_datagram_size`. When disabled `max_datagram_size` is used as is.
+    pub mtu_probing: bool,
+    /// Datagrams sent per call to `work()` at most.
+    pub send_window: usize,
+    /// Resend a message the peer didn't acknowledge for this long.
+    pub retransmit_timeout: Duration,
+    /// Cap on outgoing bytes per second, `None` for no cap, `Some(0)` to
+    /// pause sending.
+    pub rate_limit: Option<u64>,
+    /// Bytes that may be sent back to back while under the cap. One
+    /// datagram goes out at a time even if it is larger, or 0.
+    pub burst: usize,
+    /// Read and write several datagrams per syscall (`recvmmsg`/`sendmmsg`)
+    /// where the platform supports it.
+    pub batch_syscalls: bool,
+    /// Log the handshake and every datagram sent and received to stderr.
+    pub trace: bool,
+    /// Bytes of a stream that may be in flight or buffered at the receiver.
+    /// Both peers need the same one, the receiver fails a stream whose data
+    /// goes beyond it.
+    pub stream_window: usize,
+    /// How long a stream reader waits for data before giving up.
+    pub stream_timeout: Duration,
+    /// Incoming message IDs tracked for replay protection. Messages further
+    /// ahead of the oldest missing one are dropped until it arrives.
+    pub replay_window: usize,
+    /// Ping the peer when nothing arrived from it for this long.
+    pub keepalive_interval: Duration,
+    /// Give up on the peer when nothing arrived from it for this long, see
+    /// [`ConnectionState::TimedOut`](crate::ConnectionState::TimedOut).
+    pub idle_timeout: Duration,
+    /// Messages waiting to be acknowledged at most, `None` for no limit.
+    pub max_queued_messages: Option<usize>,
+    /// Payload bytes waiting to be acknowledged at most, `None` for no limit.
+    pub max_queued_bytes: Option<usize>,
+    /// What happens to messages that don't fit the limits.
+    pub queue_policy: QueuePolicy,
+    /// When to give up on messages sent without a [`Ttl`] of their own, by
+    /// default never.
+    pub ttl: Ttl,
+    /// Integrity checks offered to or accepted from the peer in the
+    /// handshake, most preferred first. Peers that predate the negotiation
+    /// only speak [`Integrity::Mac`].
+    pub integrity: Vec<Integrity>,
+    /// Secret shared by both peers and mixed into the session key, so
+    /// [`Integrity::Mac`] authenticates the peer. With the empty default
+    /// anybody who saw the handshake can compute the key, the MAC then
+    /// only ties messages to their session. Both peers need the same one.
+    pub secret: Vec<u8>,
+    /// Records the handshake and every datagram after it, see [`Capture`].
+    pub capture: Option<Capture>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            base_datagram_size: 1024,
+            // Ethernet MTU minus IPv4 and UDP headers
+            max_datagram_size: 1472,
+            mtu_probing: true,
+            send_window: 1,
+            retransmit_timeout: Duration::from_millis(100),
+            rate_limit: None,
+            burst: 64 * 1024,
+            batch_syscalls: true,
+            trace: false,
+            stream_window: 1024 * 1024,
+            stream_timeout: Duration::from_secs(30),
+            replay_window: 64 * 1024,
+            keepalive_interval: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(30),
+            max_queued_messages: None,
+            max_queued_bytes: None,
+            queue_policy: QueuePolicy::Reject,
+            ttl: Ttl::default(),
+            integrity: vec![Integrity::Mac],
+            secret: Vec::new(),
+            capture: None,
+        }
+    }
+}
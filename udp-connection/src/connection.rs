@@ -0,0 +1,178 @@
+use std::{
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use bytes::Bytes;
+
+use crate::{
+    delivery::{MessageId, SendError},
+    error::ConnectionError,
+    event::Event,
+    socket_worker::SocketWorker,
+    state::ConnectionState,
+};
+
+/// How long the background thread sleeps when a tick had nothing to do.
+const IDLE_SLEEP: Duration = Duration::from_millis(1);
+
+enum Command {
+    Send(Bytes, Sender<Result<MessageId, SendError>>),
+    SetRateLimit(Option<u64>, usize),
+    Close,
+    Shutdown,
+}
+
+/// A [`SocketWorker`] driven by its own thread.
+///
+/// Messages are queued through cloneable [`ConnectionSender`]s, everything
+/// `work()` reports arrives on [`Connection::receiver`]. The thread stops
+/// once the connection is closed or timed out, after reporting that as
+/// [`Event::StateChanged`].
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use udp_connection::{send_handshake, Connection, Event};
+/// let worker = send_handshake("127.0.0.1:8080".to_string(), |_| {}).unwrap();
+/// let connection = Connection::spawn(worker);
+///
+/// let sender = connection.sender();
+/// std::thread::spawn(move || sender.send(b"hi".to_vec().into_boxed_slice()));
+///
+/// if let Ok(Ok(Event::Message(msg))) = connection.receiver().recv() {
+///     println!("{:?}", msg);
+/// }
+///
+/// let worker = connection.shutdown();
+/// ```
+pub struct Connection {
+    commands: Sender<Command>,
+    events: Receiver<Result<Event, ConnectionError>>,
+    handle: Option<JoinHandle<SocketWorker>>,
+}
+
+/// Cloneable handle queuing messages on a [`Connection`].
+#[derive(Clone)]
+pub struct ConnectionSender {
+    commands: Sender<Command>,
+}
+
+impl Connection {
+    /// Moves the worker to a new thread that keeps calling `work()` on it.
+    pub fn spawn(worker: SocketWorker) -> Connection {
+        let (commands, commands_rx) = mpsc::channel();
+        let (events_tx, events) = mpsc::channel();
+
+        let handle = thread::spawn(move || run(worker, commands_rx, events_tx));
+
+        Connection {
+            commands,
+            events,
+            handle: Some(handle),
+        }
+    }
+
+    /// Returns a new handle for queuing messages from any thread.
+    pub fn sender(&self) -> ConnectionSender {
+        ConnectionSender {
+            commands: self.commands.clone(),
+        }
+    }
+
+    /// Incoming messages, delivery reports and errors, in the order `work()`
+    /// reported them.
+    pub fn receiver(&self) -> &Receiver<Result<Event, ConnectionError>> {
+        &self.events
+    }
+
+    /// Stops the background thread and hands the worker back.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the background thread panicked.
+    pub fn shutdown(mut self) -> SocketWorker {
+        self.stop().expect("Connection thread panicked!")
+    }
+
+    fn stop(&mut self) -> Option<SocketWorker> {
+        let handle = self.handle.take()?;
+        _ = self.commands.send(Command::Shutdown);
+        handle.join().ok()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl ConnectionSender {
+    /// Queues a message for reliable delivery.
+    ///
+    /// # Returns
+    ///
+    /// The ID assigned to the message, or an error if the connection is
+    /// closing or the connection thread has stopped.
+    pub fn send(&self, msg: impl Into<Bytes>) -> Result<MessageId, SendError> {
+        let closed = SendError::NotConnected(ConnectionState::Closed);
+        let (tx, rx) = mpsc::channel();
+        self.commands
+            .send(Command::Send(msg.into(), tx))
+            .map_err(|_| closed)?;
+        rx.recv().map_err(|_| closed)?
+    }
+
+    /// Closes the connection once everything queued was delivered, see
+    /// [`SocketWorker::close`].
+    pub fn close(&self) -> Result<(), ConnectionError> {
+        self.commands
+            .send(Command::Close)
+            .map_err(|_| ConnectionError::NotConnected(ConnectionState::Closed))
+    }
+
+    /// Changes the outgoing bandwidth cap, see [`SocketWorker::set_rate_limit`].
+    pub fn set_rate_limit(&self, rate: Option<u64>, burst: usize) -> Result<(), ConnectionError> {
+        self.commands
+            .send(Command::SetRateLimit(rate, burst))
+            .map_err(|_| ConnectionError::NotConnected(ConnectionState::Closed))
+    }
+}
+
+fn run(
+    mut worker: SocketWorker,
+    commands: Receiver<Command>,
+    events: Sender<Result<Event, ConnectionError>>,
+) -> SocketWorker {
+    loop {
+        let mut idle = true;
+
+        loop {
+            match commands.try_recv() {
+                Ok(Command::Send(msg, reply)) => {
+                    idle = false;
+                    _ = reply.send(worker.send_message(msg));
+                }
+                Ok(Command::SetRateLimit(rate, burst)) => worker.set_rate_limit(rate, burst),
+                Ok(Command::Close) => worker.close(),
+                Ok(Command::Shutdown) | Err(TryRecvError::Disconnected) => return worker,
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        for event in worker.work() {
+            idle = false;
+            // Nobody listening is not a reason to stop sending
+            _ = events.send(event);
+        }
+        if worker.state().is_final() {
+            return worker;
+        }
+
+        if idle {
+            thread::sleep(IDLE_SLEEP);
+        }
+    }
+}
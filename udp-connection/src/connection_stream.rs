@@ -0,0 +1,269 @@
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, Read, Write},
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    error::ConnectionError, socket_worker::SocketWorker, state::ConnectionState, stream::StreamId,
+};
+
+/// How long to sleep between ticks while waiting on the peer.
+const WAIT: Duration = Duration::from_millis(1);
+
+/// Size of the buffer behind [`BufRead`].
+const READ_BUF_LEN: usize = 8 * 1024;
+
+/// A connection as a byte stream, for code that wants `Read` and `Write`
+/// rather than messages, like `io::copy` or `serde_json::from_reader`.
+///
+/// Everything written goes into one outgoing byte stream, see
+/// [`SocketWorker::send_stream`], and reads come from the first stream the
+/// peer opens, so bytes arrive complete and in order whatever happens to the
+/// datagrams. Both ends of the connection have to use a `ConnectionStream`.
+///
+/// Reads and writes work the connection while they wait; messages, other
+/// events and garbled datagrams reported meanwhile are dropped, socket
+/// errors and the connection ending are returned. Writes block
+/// once [`Config::stream_window`](crate::Config::stream_window) bytes wait
+/// for the peer. `flush` returns once the peer acknowledged everything
+/// written.
+///
+/// [`ConnectionStream::close`] ends the outgoing stream and closes the
+/// connection. The peer reads the end of the stream as `Ok(0)`, as it does a
+/// connection closed before anything was written.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use std::io::{BufRead, Write};
+/// # use udp_connection::{send_handshake, ConnectionStream};
+/// let worker = send_handshake("127.0.0.1:8080".to_string(), |_| {})?;
+/// let mut stream = ConnectionStream::new(worker)?;
+///
+/// std::io::copy(&mut std::fs::File::open("snapshot.bin")?, &mut stream)?;
+/// stream.finish()?;
+///
+/// let mut answer = String::new();
+/// stream.read_line(&mut answer)?;
+/// stream.close()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct ConnectionStream {
+    worker: SocketWorker,
+    outgoing: StreamId,
+    /// Written bytes the outgoing stream didn't take yet
+    pipe: Arc<Mutex<Pipe>>,
+    /// The peer's stream, once it showed up
+    incoming: Option<StreamId>,
+    buf: Box<[u8]>,
+    /// Unread part of `buf`
+    pos: usize,
+    filled: usize,
+}
+
+#[derive(Default)]
+struct Pipe {
+    bytes: VecDeque<u8>,
+    /// No more bytes are coming, the stream ends once `bytes` is read
+    closed: bool,
+}
+
+/// The outgoing stream's end of the pipe.
+struct PipeReader(Arc<Mutex<Pipe>>);
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = self.0.lock().expect("Pipe poisoned!");
+        if pipe.bytes.is_empty() && !pipe.closed {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        pipe.bytes.read(buf)
+    }
+}
+
+impl ConnectionStream {
+    /// Opens the outgoing stream on `worker`.
+    ///
+    /// Fails if the connection is closing or over.
+    pub fn new(mut worker: SocketWorker) -> io::Result<ConnectionStream> {
+        let pipe = Arc::new(Mutex::new(Pipe::default()));
+        let outgoing = worker
+            .send_stream(PipeReader(pipe.clone()))
+            .map_err(|e| io::Error::new(io::ErrorKind::NotConnected, e))?;
+
+        Ok(ConnectionStream {
+            worker,
+            outgoing,
+            pipe,
+            incoming: None,
+            buf: vec![0; READ_BUF_LEN].into_boxed_slice(),
+            pos: 0,
+            filled: 0,
+        })
+    }
+
+    pub fn worker(&self) -> &SocketWorker {
+        &self.worker
+    }
+
+    /// Ends the outgoing stream and waits until the peer has all of it.
+    /// Reading goes on, writing fails from now on.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.pipe().closed = true;
+        self.wait_until(|s| s.worker.send_stream_unacked(s.outgoing).is_none())
+    }
+
+    /// Finishes the outgoing stream and closes the connection, see
+    /// [`SocketWorker::close`].
+    pub fn close(mut self) -> io::Result<SocketWorker> {
+        self.finish()?;
+        self.worker.close();
+        while !self.worker.state().is_final() {
+            self.tick()?;
+        }
+        Ok(self.worker)
+    }
+
+    fn pipe(&self) -> MutexGuard<'_, Pipe> {
+        self.pipe.lock().expect("Pipe poisoned!")
+    }
+
+    /// Whether the peer stopped taking the outgoing stream.
+    fn broken(&self) -> bool {
+        self.worker.send_stream_unacked(self.outgoing).is_none()
+    }
+
+    /// Works the connection until `done` says so.
+    ///
+    /// Fails if the connection is over first.
+    fn wait_until(&mut self, mut done: impl FnMut(&Self) -> bool) -> io::Result<()> {
+        loop {
+            if done(self) {
+                return Ok(());
+            }
+            let state = self.worker.state();
+            if state.is_final() {
+                return Err(ConnectionError::NotConnected(state).into());
+            }
+            self.tick()?;
+        }
+    }
+
+    fn tick(&mut self) -> io::Result<()> {
+        for event in self.worker.work() {
+            match event {
+                Err(ConnectionError::Io(e)) => return Err(e),
+                // Anybody can send a garbled datagram, the connection goes on
+                Err(e) if self.worker.config().trace => {
+                    eprintln!("Connection to {} {e}", self.worker.address)
+                }
+                _ => {}
+            }
+        }
+        thread::sleep(WAIT);
+        Ok(())
+    }
+
+    /// Waits for the next bytes of the peer's stream.
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.incoming.is_none() {
+                self.incoming = self.worker.take_recv_stream();
+            }
+            if let Some(id) = self.incoming {
+                if let Some(result) = self.worker.try_read_stream(id, buf) {
+                    return result;
+                }
+            }
+
+            match self.worker.state() {
+                // Closed without a word
+                ConnectionState::Closed if self.incoming.is_none() => return Ok(0),
+                state if state.is_final() => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        format!("The peer's stream ended early, the connection is {state}"),
+                    ))
+                }
+                _ => self.tick()?,
+            }
+        }
+    }
+}
+
+impl Write for ConnectionStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pipe().closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "Stream finished"));
+        }
+
+        // Bytes beyond what the peer accepts would only pile up here
+        let window = self.worker.config().stream_window;
+        self.wait_until(|s| s.pipe().bytes.len() < window || s.broken())?;
+        if self.broken() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "The peer stopped reading",
+            ));
+        }
+
+        let mut pipe = self.pipe();
+        let n = buf.len().min(window - pipe.bytes.len());
+        pipe.bytes.extend(&buf[..n]);
+        Ok(n)
+    }
+
+    /// Waits until the peer acknowledged every byte written.
+    fn flush(&mut self) -> io::Result<()> {
+        self.wait_until(|s| {
+            s.worker
+                .send_stream_unacked(s.outgoing)
+                .is_none_or(|unacked| unacked == 0 && s.pipe().bytes.is_empty())
+        })?;
+        if self.broken() && !self.pipe().bytes.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "The peer stopped reading",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Read for ConnectionStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Large reads skip the buffer
+        if self.pos == self.filled && buf.len() >= self.buf.len() {
+            return self.receive(buf);
+        }
+
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for ConnectionStream {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.filled {
+            let mut buf = std::mem::take(&mut self.buf);
+            let result = self.receive(&mut buf);
+            self.buf = buf;
+            self.filled = result?;
+            self.pos = 0;
+        }
+        Ok(&self.buf[self.pos..self.filled])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.filled);
+    }
+}
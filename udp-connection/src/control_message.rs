@@ -1,6 +1,21 @@
 use std::fmt::Debug;
 
+use crate::message::Message;
+
 #[derive(Debug)]
 pub enum ControlMessage {
     Acc { id: u64 },
+    /// Several messages packed into one datagram.
+    Batch { messages: Vec<Message> },
+    /// Path MTU probe claiming to be `size` bytes, of which `received`
+    /// actually arrived.
+    Probe { size: usize, received: usize },
+    /// The peer received a probe of `size` bytes.
+    ProbeAck { size: usize },
+    /// Round trip time measurement, answered with a `Pong`.
+    Ping { token: u64 },
+    /// Answer to the `Ping` with the same `token`.
+    Pong { token: u64 },
+    /// The peer closed the connection and won't send or acknowledge anything.
+    Close,
 }
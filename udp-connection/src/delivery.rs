@@ -0,0 +1,150 @@
+use std::{collections::BTreeMap, fmt, time::Duration};
+
+use crate::state::ConnectionState;
+
+/// Identifier assigned to a message by [`SocketWorker::send_message`](crate::SocketWorker::send_message).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageId(pub u64);
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Where a sent message is on its way to the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// Waiting in the outgoing queue, not sent yet.
+    Queued,
+    /// Sent at least once, not acknowledged yet.
+    InFlight,
+    /// Acknowledged by the peer.
+    Acked,
+    /// Given up on before the peer acknowledged it.
+    Expired,
+    /// Never assigned by this worker, or expired so long ago that it was
+    /// forgotten.
+    Unknown,
+}
+
+/// When to give up on a message the peer doesn't acknowledge, see
+/// [`Config::ttl`](crate::Config::ttl) and
+/// [`SocketWorker::send_message_with_ttl`](crate::SocketWorker::send_message_with_ttl).
+///
+/// A message given up on is reported as [`Event::Expired`](crate::Event::Expired)
+/// and its status becomes [`DeliveryStatus::Expired`].
+///
+/// # Examples
+///
+/// ```
+/// # use std::time::Duration;
+/// # use udp_connection::Ttl;
+/// // Worthless after a second, or once sent three times
+/// let ttl = Ttl {
+///     deadline: Some(Duration::from_secs(1)),
+///     max_retransmits: Some(2),
+/// };
+/// assert_ne!(ttl, Ttl::default());
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ttl {
+    /// Time after queueing, `None` for no deadline.
+    pub deadline: Option<Duration>,
+    /// Times the message is sent again after the first, `None` for no limit.
+    pub max_retransmits: Option<u32>,
+}
+
+/// Messages waiting in the outgoing queue until the peer acknowledges them,
+/// see [`SocketWorker::queue_depth`](crate::SocketWorker::queue_depth).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueDepth {
+    pub messages: usize,
+    /// Payload bytes, after compression
+    pub bytes: usize,
+}
+
+/// Why a message was not queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The outgoing queue is at its limits, see
+    /// [`Config::queue_policy`](crate::Config::queue_policy).
+    QueueFull,
+    /// The connection is closing or over.
+    NotConnected(ConnectionState),
+    /// The (compressed) payload of `size` bytes doesn't fit a datagram,
+    /// which carries `max` at most, or the payload is larger than the
+    /// `max` of 64 KiB the peer expands compressed messages to.
+    TooLarge { size: usize, max: usize },
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::QueueFull => write!(f, "Outgoing queue is full"),
+            SendError::NotConnected(state) => write!(f, "Can't send, the connection is {state}"),
+            SendError::TooLarge { size, max } => {
+                write!(f, "Message of {size} bytes exceeds the payload limit of {max}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// IDs of the messages given up on, as runs of consecutive IDs.
+///
+/// Only the newest `max_runs` runs are kept, older IDs are forgotten.
+#[derive(Debug)]
+pub(crate) struct ExpiredIds {
+    /// Last ID of every run by its first
+    runs: BTreeMap<u64, u64>,
+    max_runs: usize,
+}
+
+impl ExpiredIds {
+    pub(crate) fn new(max_runs: usize) -> ExpiredIds {
+        ExpiredIds {
+            runs: BTreeMap::new(),
+            max_runs,
+        }
+    }
+
+    pub(crate) fn contains(&self, id: u64) -> bool {
+        self.runs
+            .range(..=id)
+            .next_back()
+            .is_some_and(|(_, &last)| id <= last)
+    }
+
+    /// Records `id` as expired.
+    ///
+    /// # Returns
+    ///
+    /// The ID below which runs were forgotten to make room, if any.
+    pub(crate) fn insert(&mut self, id: u64) -> Option<u64> {
+        if self.contains(id) {
+            return None;
+        }
+        let mut first = id;
+        if let Some((&start, last)) = self.runs.range_mut(..id).next_back() {
+            if *last + 1 == id {
+                *last = id;
+                first = start;
+            }
+        }
+        if first == id {
+            self.runs.insert(id, id);
+        }
+        if let Some(next) = self.runs.remove(&(id + 1)) {
+            self.runs.insert(first, next);
+        }
+
+        let mut forgotten = None;
+        while self.runs.len() > self.max_runs {
+            let (_, last) = self.runs.pop_first().expect("More runs than allowed");
+            forgotten = Some(last + 1);
+        }
+        forgotten
+    }
+}
@@ -0,0 +1,338 @@
+//! LAN peer discovery over IPv4 multicast.
+//!
+//! Every node periodically sends a beacon with its node ID, cluster name
+//! and listen address to a multicast group, and listens for the beacons of
+//! the others. A beacon is signed with HMAC-SHA256 over a secret shared by
+//! the cluster and carries the sender's clock, so the clocks of the nodes
+//! have to agree within [`DiscoveryConfig::expiry`].
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use udp_connection::discovery::{Discovery, DiscoveryConfig, DiscoveryEvent};
+//! let config = DiscoveryConfig {
+//!     secret: b"cluster secret".to_vec(),
+//!     ..DiscoveryConfig::default()
+//! };
+//! let mut discovery = Discovery::new(1, "kv", "0.0.0.0:3000".parse().unwrap(), config)?;
+//! loop {
+//!     for event in discovery.work() {
+//!         match event {
+//!             Ok(DiscoveryEvent::Discovered(peer)) => println!("{} at {}", peer.node_id, peer.address),
+//!             Ok(DiscoveryEvent::Expired(peer)) => println!("{} is gone", peer.node_id),
+//!             Err(e) => eprintln!("{e}"),
+//!         }
+//!     }
+//!     std::thread::sleep(std::time::Duration::from_millis(100));
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Buf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::capture::write_addr;
+
+const MAGIC: &[u8; 5] = b"UDPB\x01";
+
+const TAG_LEN: usize = 32;
+
+/// Largest beacon, with an IPv6 address and a 255 byte cluster name.
+const MAX_BEACON_LEN: usize = MAGIC.len() + 4 + 8 + 19 + 1 + 255 + TAG_LEN;
+
+/// Tunables of a [`Discovery`].
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    /// Multicast group and port the beacons are sent to.
+    pub group: SocketAddrV4,
+    /// Interface to send beacons from and receive them on, unspecified for
+    /// the one the OS picks.
+    pub interface: Ipv4Addr,
+    /// Time between two beacons of this node.
+    pub interval: Duration,
+    /// A peer is reported expired after this long without a beacon.
+    pub expiry: Duration,
+    /// Key beacons are signed with. Nodes only see peers with the same
+    /// secret; the empty default merely detects corrupted beacons.
+    pub secret: Vec<u8>,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> DiscoveryConfig {
+        DiscoveryConfig {
+            // Organization-local scope, stays inside the site
+            group: SocketAddrV4::new(Ipv4Addr::new(239, 255, 85, 68), 7645),
+            interface: Ipv4Addr::UNSPECIFIED,
+            interval: Duration::from_secs(1),
+            expiry: Duration::from_secs(5),
+            secret: Vec::new(),
+        }
+    }
+}
+
+/// A node announced by a beacon.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Beacon {
+    pub node_id: u32,
+    pub cluster: String,
+    /// Where the node accepts handshakes. An unspecified IP, or a loopback
+    /// one from another host, is replaced by the address the beacon came
+    /// from.
+    pub address: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiscoveryEvent {
+    /// First beacon of a node, or the node moved to a new address.
+    Discovered(Beacon),
+    /// No beacon of the node for [`DiscoveryConfig::expiry`], or it moved
+    /// away from this address.
+    Expired(Beacon),
+}
+
+struct Known {
+    beacon: Beacon,
+    last_seen: Instant,
+    /// Sender's clock in the newest beacon, older ones are replays
+    timestamp: u64,
+}
+
+/// Announces this node and tracks the other nodes of its cluster.
+///
+/// Like [`SocketWorker`](crate::SocketWorker), it never blocks; call
+/// [`work`](Discovery::work) regularly.
+pub struct Discovery {
+    socket: UdpSocket,
+    config: DiscoveryConfig,
+    beacon: Beacon,
+    peers: HashMap<u32, Known>,
+    next_beacon: Instant,
+}
+
+impl Discovery {
+    /// Joins the multicast group and starts announcing `address` as the
+    /// listen address of node `node_id`.
+    pub fn new(
+        node_id: u32,
+        cluster: &str,
+        address: SocketAddr,
+        config: DiscoveryConfig,
+    ) -> io::Result<Discovery> {
+        if cluster.len() > u8::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Cluster name of {} bytes", cluster.len()),
+            ));
+        }
+
+        // Every node on the host binds the group port
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.group.port()).into())?;
+        socket.join_multicast_v4(config.group.ip(), &config.interface)?;
+        socket.set_multicast_if_v4(&config.interface)?;
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Discovery {
+            socket: socket.into(),
+            config,
+            beacon: Beacon {
+                node_id,
+                cluster: cluster.to_string(),
+                address,
+            },
+            peers: HashMap::new(),
+            next_beacon: Instant::now(),
+        })
+    }
+
+    /// Nodes of the cluster seen within the expiry time.
+    pub fn peers(&self) -> impl Iterator<Item = &Beacon> {
+        self.peers.values().map(|known| &known.beacon)
+    }
+
+    /// Sends a beacon when one is due, reads the beacons of the others and
+    /// expires silent peers.
+    ///
+    /// Beacons of other clusters are ignored, beacons that fail the
+    /// signature or freshness check are reported as errors.
+    pub fn work(&mut self) -> Vec<Result<DiscoveryEvent, String>> {
+        let mut events = Vec::new();
+        let now = Instant::now();
+
+        if now >= self.next_beacon {
+            self.next_beacon = now + self.config.interval;
+            let beacon = self.encode(unix_millis());
+            if let Err(e) = self.socket.send_to(&beacon, self.config.group) {
+                events.push(Err(format!("Error sending beacon {e}")));
+            }
+        }
+
+        let mut buf = [0; MAX_BEACON_LEN];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    events.push(Err(format!("Error receiving beacon {e}")));
+                    break;
+                }
+            };
+
+            match self.decode(&buf[..len], from) {
+                Ok(Some((beacon, timestamp))) => self.on_beacon(beacon, timestamp, &mut events),
+                Ok(None) => {}
+                Err(e) => events.push(Err(format!("Beacon from {from}: {e}"))),
+            }
+        }
+
+        let expiry = self.config.expiry;
+        self.peers.retain(|_, known| {
+            let alive = now.duration_since(known.last_seen) < expiry;
+            if !alive {
+                events.push(Ok(DiscoveryEvent::Expired(known.beacon.clone())));
+            }
+            alive
+        });
+
+        events
+    }
+
+    fn on_beacon(
+        &mut self,
+        beacon: Beacon,
+        timestamp: u64,
+        events: &mut Vec<Result<DiscoveryEvent, String>>,
+    ) {
+        if let Some(known) = self.peers.get_mut(&beacon.node_id) {
+            if timestamp <= known.timestamp {
+                return;
+            }
+            known.timestamp = timestamp;
+            known.last_seen = Instant::now();
+
+            if known.beacon.address != beacon.address {
+                let old = std::mem::replace(&mut known.beacon, beacon.clone());
+                events.push(Ok(DiscoveryEvent::Expired(old)));
+                events.push(Ok(DiscoveryEvent::Discovered(beacon)));
+            }
+            return;
+        }
+
+        self.peers.insert(
+            beacon.node_id,
+            Known {
+                beacon: beacon.clone(),
+                last_seen: Instant::now(),
+                timestamp,
+            },
+        );
+        events.push(Ok(DiscoveryEvent::Discovered(beacon)));
+    }
+
+    /// Beacon layout:
+    ///
+    /// - Magic `UDPB` and version 1
+    /// - Node ID u32
+    /// - Sender's clock, milliseconds since the UNIX epoch u64
+    /// - Listen address (family u8 4/6, IP, port u16)
+    /// - Cluster name length u8 and UTF-8 name
+    /// - HMAC-SHA256 of everything before
+    fn encode(&self, timestamp: u64) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(MAX_BEACON_LEN);
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&self.beacon.node_id.to_be_bytes());
+        buf.extend_from_slice(&timestamp.to_be_bytes());
+        write_addr(&mut buf, self.beacon.address);
+        buf.push(self.beacon.cluster.len() as u8);
+        buf.extend_from_slice(self.beacon.cluster.as_bytes());
+
+        let tag = self.mac(&buf).finalize().into_bytes();
+        buf.extend_from_slice(&tag);
+        buf
+    }
+
+    /// `None` for our own beacons and those of other clusters.
+    fn decode(&self, data: &[u8], from: SocketAddr) -> Result<Option<(Beacon, u64)>, String> {
+        let Some(mut buf) = data.strip_prefix(MAGIC) else {
+            return Err("Not a beacon".to_string());
+        };
+        if data.len() < MAGIC.len() + TAG_LEN {
+            return Err(format!("Beacon of {} bytes", data.len()));
+        }
+        let (signed, tag) = data.split_at(data.len() - TAG_LEN);
+        buf = &buf[..buf.len() - TAG_LEN];
+
+        let truncated = || "Truncated beacon".to_string();
+        let mut take = |n: usize| -> Result<&[u8], String> {
+            let (head, tail) = buf.split_at_checked(n).ok_or_else(truncated)?;
+            buf = tail;
+            Ok(head)
+        };
+
+        let node_id = take(4)?.get_u32();
+        let timestamp = take(8)?.get_u64();
+        let ip = match take(1)?[0] {
+            4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(take(4)?).unwrap())),
+            6 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(take(16)?).unwrap())),
+            other => return Err(format!("Unknown address family {other}")),
+        };
+        let port = take(2)?.get_u16();
+        let cluster_len = take(1)?[0] as usize;
+        let cluster = take(cluster_len)?;
+        if !buf.is_empty() {
+            return Err(format!("{} bytes after the cluster name", buf.len()));
+        }
+
+        if cluster != self.beacon.cluster.as_bytes() || node_id == self.beacon.node_id {
+            return Ok(None);
+        }
+        self.mac(signed)
+            .verify_slice(tag)
+            .map_err(|_| "Bad signature".to_string())?;
+
+        let age = unix_millis().abs_diff(timestamp);
+        if age > self.config.expiry.as_millis() as u64 {
+            return Err(format!("Beacon clock {age} ms off"));
+        }
+
+        // Loopback is only good for nodes on the same host
+        let ip = if ip.is_unspecified() || (ip.is_loopback() && !from.ip().is_loopback()) {
+            from.ip()
+        } else {
+            ip
+        };
+        Ok(Some((
+            Beacon {
+                node_id,
+                cluster: self.beacon.cluster.clone(),
+                address: SocketAddr::new(ip, port),
+            },
+            timestamp,
+        )))
+    }
+
+    fn mac(&self, data: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.config.secret)
+            .expect("HMAC takes any key size");
+        mac.update(data);
+        mac
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -0,0 +1,117 @@
+use std::{fmt, io};
+
+use crate::{delivery::SendError, state::ConnectionState, stream::StreamId};
+
+/// What went wrong on a connection, returned by the handshake functions and
+/// reported by [`SocketWorker::work`](crate::SocketWorker::work).
+///
+/// Errors from `work()` don't end the connection by themselves, that is
+/// reported as [`Event::StateChanged`](crate::Event::StateChanged).
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use udp_connection::{send_handshake, ConnectionError};
+/// match send_handshake("127.0.0.1:8080".to_string(), |_| {}) {
+///     Ok(worker) => println!("Connected to {}", worker.address),
+///     Err(ConnectionError::Timeout) => println!("Nobody there"),
+///     Err(e) => println!("Handshake failed: {e}"),
+/// }
+/// ```
+#[derive(Debug)]
+pub enum ConnectionError {
+    /// The server turned the handshake down, with its reason.
+    HandshakeRejected(String),
+    /// The peer didn't answer in time.
+    Timeout,
+    /// A handshake message, message or stream frame that makes no sense.
+    Malformed(String),
+    /// The peer closed the connection in the middle of something, like a
+    /// stream we were reading.
+    PeerClosed,
+    /// The peer cancelled a stream we were sending.
+    StreamCancelled(StreamId),
+    /// The outgoing queue is at its limits, see
+    /// [`Config::queue_policy`](crate::Config::queue_policy).
+    QueueFull,
+    /// The connection is closing or over.
+    NotConnected(ConnectionState),
+    /// A message too large to send, see
+    /// [`SocketWorker::max_payload`](crate::SocketWorker::max_payload).
+    TooLarge { size: usize, max: usize },
+    /// The peers found no integrity check they both accept.
+    AuthFailure(String),
+    /// A value a [`Codec`](crate::Codec) couldn't encode, or a message it
+    /// couldn't decode. Needs the `serde` feature to happen.
+    Codec(String),
+    /// The socket, a stream being sent or the capture failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::HandshakeRejected(reason) => write!(f, "Handshake rejected: {reason}"),
+            ConnectionError::Timeout => write!(f, "The peer didn't answer in time"),
+            ConnectionError::Malformed(e) => write!(f, "Malformed: {e}"),
+            ConnectionError::PeerClosed => write!(f, "Closed by the peer"),
+            ConnectionError::StreamCancelled(id) => write!(f, "{id} cancelled by the receiver"),
+            ConnectionError::QueueFull => write!(f, "{}", SendError::QueueFull),
+            ConnectionError::NotConnected(state) => {
+                write!(f, "{}", SendError::NotConnected(*state))
+            }
+            ConnectionError::TooLarge { size, max } => {
+                write!(f, "{}", SendError::TooLarge { size: *size, max: *max })
+            }
+            ConnectionError::AuthFailure(e) => write!(f, "Authentication failed: {e}"),
+            ConnectionError::Codec(e) => write!(f, "Codec failed: {e}"),
+            ConnectionError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConnectionError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ConnectionError {
+    fn from(e: io::Error) -> ConnectionError {
+        ConnectionError::Io(e)
+    }
+}
+
+impl From<SendError> for ConnectionError {
+    fn from(e: SendError) -> ConnectionError {
+        match e {
+            SendError::QueueFull => ConnectionError::QueueFull,
+            SendError::NotConnected(state) => ConnectionError::NotConnected(state),
+            SendError::TooLarge { size, max } => ConnectionError::TooLarge { size, max },
+        }
+    }
+}
+
+/// For code that only deals in `io::Error`, the cause stays reachable
+/// through [`io::Error::get_ref`].
+impl From<ConnectionError> for io::Error {
+    fn from(e: ConnectionError) -> io::Error {
+        let kind = match e {
+            ConnectionError::Io(e) => return e,
+            ConnectionError::HandshakeRejected(_) => io::ErrorKind::ConnectionRefused,
+            ConnectionError::Timeout => io::ErrorKind::TimedOut,
+            ConnectionError::Malformed(_) => io::ErrorKind::InvalidData,
+            ConnectionError::PeerClosed => io::ErrorKind::ConnectionReset,
+            ConnectionError::StreamCancelled(_) => io::ErrorKind::BrokenPipe,
+            ConnectionError::QueueFull => io::ErrorKind::WouldBlock,
+            ConnectionError::NotConnected(_) => io::ErrorKind::NotConnected,
+            ConnectionError::TooLarge { .. } => io::ErrorKind::InvalidInput,
+            ConnectionError::AuthFailure(_) => io::ErrorKind::PermissionDenied,
+            ConnectionError::Codec(_) => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
+    }
+}
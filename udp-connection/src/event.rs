@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use crate::{
+    delivery::MessageId, reconnect::ReconnectStatus, state::ConnectionState, stream::StreamId,
+};
+
+/// Something that happened on a connection during [`SocketWorker::work`](crate::SocketWorker::work).
+#[derive(Debug)]
+pub enum Event {
+    /// A message from the peer.
+    Message(Bytes),
+    /// The peer acknowledged a message we sent.
+    Delivered(MessageId),
+    /// We gave up on a message we sent before the peer acknowledged it, see
+    /// [`Ttl`](crate::Ttl) and [`QueuePolicy::DropOldest`](crate::QueuePolicy::DropOldest),
+    /// or the connection ended with it queued.
+    Expired(MessageId),
+    /// The peer answered a [`SocketWorker::ping`](crate::SocketWorker::ping)
+    /// after this round trip time.
+    Pong(Duration),
+    /// The peer started a byte stream, read it with
+    /// [`SocketWorker::recv_stream`](crate::SocketWorker::recv_stream).
+    StreamOpened(StreamId),
+    /// The peer received every byte of a stream we sent.
+    StreamSent(StreamId),
+    /// The connection moved to a new state.
+    StateChanged(ConnectionState),
+    /// A [`ReconnectingConnection`](crate::ReconnectingConnection) lost the
+    /// session or got it back.
+    Reconnect(ReconnectStatus),
+}
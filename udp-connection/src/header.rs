@@ -0,0 +1,302 @@
+//! Layout of the message header.
+//!
+//! Version 1:
+//!
+//! - Version u8, `1`
+//! - Packet type u8, see [`PacketType`]
+//! - Flags u8: fragment, compressed, unreliable, stream, connection ID
+//!   present, and the tag length in bits 5 and 6
+//! - Connection ID u32, if flagged
+//! - Message ID, LEB128, 0 for control packets
+//! - Tag, 0, 4, 8 or 32 bytes
+//!
+//! The legacy header is a big-endian u64 ID whose top bits are the
+//! compressed and stream flags and the tag length, followed by the tag.
+//! Control packets have ID 0 and their type in the byte after the tag. IDs
+//! stay far below 2^56, so the low four bits of a legacy header's first
+//! byte are 0, while no version is.
+
+use bytes::BufMut;
+
+use crate::integrity::MAX_TAG_LEN;
+
+/// Version of the header [`Message::serialize`](crate::Message::serialize)
+/// writes.
+pub const VERSION: u8 = 1;
+
+/// Largest LEB128 encoded u64.
+pub(crate) const MAX_VARINT_LEN: usize = 10;
+
+/// Longest version 1 header before the tag.
+pub(crate) const MAX_PREFIX_LEN: usize = 3 + 4 + MAX_VARINT_LEN;
+
+const FRAGMENT_FLAG: u8 = 1 << 0;
+const COMPRESSED_FLAG: u8 = 1 << 1;
+const UNRELIABLE_FLAG: u8 = 1 << 2;
+const STREAM_FLAG: u8 = 1 << 3;
+const CONNECTION_ID_FLAG: u8 = 1 << 4;
+const TAG_LEN_SHIFT: u32 = 5;
+const TAG_LEN_BITS: u8 = 0b11 << TAG_LEN_SHIFT;
+const KNOWN_FLAGS: u8 = FRAGMENT_FLAG
+    | COMPRESSED_FLAG
+    | UNRELIABLE_FLAG
+    | STREAM_FLAG
+    | CONNECTION_ID_FLAG
+    | TAG_LEN_BITS;
+
+/// Bit of the legacy ID marking a compressed payload.
+const LEGACY_COMPRESSED_FLAG: u64 = 1 << 63;
+/// Bit of the legacy ID marking a frame of a byte stream.
+const LEGACY_STREAM_FLAG: u64 = 1 << 62;
+const LEGACY_TAG_LEN_SHIFT: u32 = 60;
+const LEGACY_FLAG_BITS: u64 = LEGACY_COMPRESSED_FLAG | LEGACY_STREAM_FLAG | 0b11 << 60;
+
+/// What a packet carries. Everything but `Data` is a control packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum PacketType {
+    Data = 0,
+    Ack = 1,
+    Batch = 2,
+    Probe = 3,
+    ProbeAck = 4,
+    Ping = 5,
+    Pong = 6,
+    Close = 7,
+}
+
+impl PacketType {
+    pub fn from_u8(value: u8) -> Option<PacketType> {
+        Some(match value {
+            0 => PacketType::Data,
+            1 => PacketType::Ack,
+            2 => PacketType::Batch,
+            3 => PacketType::Probe,
+            4 => PacketType::ProbeAck,
+            5 => PacketType::Ping,
+            6 => PacketType::Pong,
+            7 => PacketType::Close,
+            _ => return None,
+        })
+    }
+}
+
+/// Header layout of a message on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WireFormat {
+    /// Before the header had a version. Has no room for the fragment and
+    /// unreliable flags or a connection ID; they are dropped.
+    Legacy,
+    /// [`VERSION`] 1.
+    V1,
+}
+
+/// Everything a header says.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Header {
+    pub(crate) format: WireFormat,
+    pub(crate) kind: PacketType,
+    pub(crate) id: u64,
+    pub(crate) connection_id: Option<u32>,
+    pub(crate) fragment: bool,
+    pub(crate) compressed: bool,
+    pub(crate) unreliable: bool,
+    pub(crate) stream: bool,
+    pub(crate) tag_len: usize,
+}
+
+impl Header {
+    /// Bytes of the header, tag included; the payload starts here.
+    pub(crate) fn len(&self) -> usize {
+        match self.format {
+            WireFormat::Legacy => 8 + self.tag_len + usize::from(self.kind != PacketType::Data),
+            WireFormat::V1 => {
+                3 + 4 * usize::from(self.connection_id.is_some())
+                    + varint_len(self.id)
+                    + self.tag_len
+            }
+        }
+    }
+
+    /// Where the tag starts.
+    pub(crate) fn tag_at(&self) -> usize {
+        match self.format {
+            WireFormat::Legacy => 8,
+            WireFormat::V1 => self.len() - self.tag_len,
+        }
+    }
+
+    /// Writes the header with `tag`, or without it to get the bytes the tag
+    /// is made of, which come before the payload.
+    ///
+    /// # Panics
+    ///
+    /// Panics on a tag length no [`Integrity`](crate::Integrity) check
+    /// produces.
+    pub(crate) fn put(&self, buf: &mut impl BufMut, tag: Option<&[u8]>) {
+        let code = tag_len_code(self.tag_len);
+        match self.format {
+            WireFormat::Legacy => {
+                let mut id = self.id | (code as u64) << LEGACY_TAG_LEN_SHIFT;
+                if self.compressed {
+                    id |= LEGACY_COMPRESSED_FLAG;
+                }
+                if self.stream {
+                    id |= LEGACY_STREAM_FLAG;
+                }
+                buf.put_u64(id);
+                if let Some(tag) = tag {
+                    buf.put_slice(tag);
+                }
+                if self.kind != PacketType::Data {
+                    buf.put_u8(self.kind as u8);
+                }
+            }
+            WireFormat::V1 => {
+                let mut flags = code << TAG_LEN_SHIFT;
+                for (set, flag) in [
+                    (self.fragment, FRAGMENT_FLAG),
+                    (self.compressed, COMPRESSED_FLAG),
+                    (self.unreliable, UNRELIABLE_FLAG),
+                    (self.stream, STREAM_FLAG),
+                    (self.connection_id.is_some(), CONNECTION_ID_FLAG),
+                ] {
+                    if set {
+                        flags |= flag;
+                    }
+                }
+
+                buf.put_u8(VERSION);
+                buf.put_u8(self.kind as u8);
+                buf.put_u8(flags);
+                if let Some(connection_id) = self.connection_id {
+                    buf.put_u32(connection_id);
+                }
+                put_varint(buf, self.id);
+                if let Some(tag) = tag {
+                    buf.put_slice(tag);
+                }
+            }
+        }
+    }
+}
+
+/// Reads the header at the start of `ser`, in either format.
+pub(crate) fn parse(ser: &[u8]) -> Result<Header, String> {
+    let truncated = || format!("Truncated header of {} bytes", ser.len());
+    let first = *ser.first().ok_or_else(truncated)?;
+
+    let header = if first & 0x0f == 0 {
+        let id = u64::from_be_bytes(ser.get(..8).ok_or_else(truncated)?.try_into().unwrap());
+        let tag_len = tag_len_of(((id >> LEGACY_TAG_LEN_SHIFT) & 0b11) as u8);
+        let kind = if id & !LEGACY_FLAG_BITS == 0 {
+            let kind = *ser.get(8 + tag_len).ok_or_else(truncated)?;
+            PacketType::from_u8(kind)
+                .filter(|&kind| kind != PacketType::Data)
+                .ok_or_else(|| format!("Unknown control type {kind}"))?
+        } else {
+            PacketType::Data
+        };
+
+        Header {
+            format: WireFormat::Legacy,
+            kind,
+            id: id & !LEGACY_FLAG_BITS,
+            connection_id: None,
+            fragment: false,
+            compressed: id & LEGACY_COMPRESSED_FLAG != 0,
+            unreliable: false,
+            stream: id & LEGACY_STREAM_FLAG != 0,
+            tag_len,
+        }
+    } else {
+        if first != VERSION {
+            return Err(format!("Unknown header version {first}"));
+        }
+        let &[kind, flags] = ser.get(1..3).ok_or_else(truncated)? else {
+            unreachable!()
+        };
+        let kind =
+            PacketType::from_u8(kind).ok_or_else(|| format!("Unknown packet type {kind}"))?;
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(format!("Unknown flags {flags:#04x}"));
+        }
+
+        let mut rest = &ser[3..];
+        let connection_id = if flags & CONNECTION_ID_FLAG != 0 {
+            let bytes = rest.get(..4).ok_or_else(truncated)?;
+            rest = &rest[4..];
+            Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+        } else {
+            None
+        };
+        let id = get_varint(&mut rest).ok_or_else(truncated)?;
+
+        let header = Header {
+            format: WireFormat::V1,
+            kind,
+            id,
+            connection_id,
+            fragment: flags & FRAGMENT_FLAG != 0,
+            compressed: flags & COMPRESSED_FLAG != 0,
+            unreliable: flags & UNRELIABLE_FLAG != 0,
+            stream: flags & STREAM_FLAG != 0,
+            tag_len: tag_len_of((flags & TAG_LEN_BITS) >> TAG_LEN_SHIFT),
+        };
+        // The tag covers the header as we write it
+        if header.tag_at() != ser.len() - rest.len() {
+            return Err(format!("Overlong ID {id}"));
+        }
+        header
+    };
+
+    if ser.len() < header.len() {
+        return Err(truncated());
+    }
+    Ok(header)
+}
+
+/// Code of a tag length in the flags. Full-length tags get 0, so legacy
+/// SHA-256 and MAC headers look as they always did.
+fn tag_len_code(tag_len: usize) -> u8 {
+    match tag_len {
+        MAX_TAG_LEN => 0,
+        0 => 1,
+        4 => 2,
+        8 => 3,
+        len => panic!("No tag is {len} bytes long!"),
+    }
+}
+
+fn tag_len_of(code: u8) -> usize {
+    match code {
+        0 => MAX_TAG_LEN,
+        1 => 0,
+        2 => 4,
+        _ => 8,
+    }
+}
+
+pub(crate) fn varint_len(value: u64) -> usize {
+    (64 - value.leading_zeros() as usize).max(1).div_ceil(7)
+}
+
+fn put_varint(buf: &mut impl BufMut, mut value: u64) {
+    while value >= 0x80 {
+        buf.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+fn get_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for (i, &byte) in buf.iter().enumerate().take(MAX_VARINT_LEN) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            *buf = &buf[i + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
@@ -0,0 +1,170 @@
+use std::{fmt, ops::Deref};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::Xxh3;
+
+/// Longest tag any [`Integrity`] check produces.
+pub const MAX_TAG_LEN: usize = 32;
+
+/// Integrity check of the messages of a connection, agreed on by both peers
+/// during the handshake, see [`Config::integrity`](crate::Config::integrity).
+///
+/// Only `Mac` ties a message to its session; the others merely detect
+/// corruption, so datagrams replayed from an earlier session are no longer
+/// told apart. They are meant for trusted networks, where they save most of
+/// the header and the cost of SHA-256 per datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Integrity {
+    /// No tag at all, UDP's own checksum is all there is.
+    None,
+    /// CRC-32C, 4 bytes.
+    Crc32c,
+    /// 64 bit xxHash3, 8 bytes.
+    Xxh3,
+    /// SHA-256, 32 bytes.
+    Sha256,
+    /// HMAC-SHA256 under the session key, 32 bytes. Authenticates the peer
+    /// only with a [`Config::secret`](crate::Config::secret).
+    Mac,
+}
+
+impl Integrity {
+    /// Every check, strongest first.
+    pub fn supported() -> &'static [Integrity] {
+        &[
+            Integrity::Mac,
+            Integrity::Sha256,
+            Integrity::Xxh3,
+            Integrity::Crc32c,
+            Integrity::None,
+        ]
+    }
+
+    /// Name of the check as it is spelled in the handshake.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Integrity::None => "none",
+            Integrity::Crc32c => "crc32c",
+            Integrity::Xxh3 => "xxh3",
+            Integrity::Sha256 => "sha256",
+            Integrity::Mac => "mac",
+        }
+    }
+
+    /// Looks up a check by its handshake name.
+    pub fn from_name(name: &str) -> Option<Integrity> {
+        Integrity::supported()
+            .iter()
+            .copied()
+            .find(|i| i.name() == name)
+    }
+
+    /// Bytes the tag takes in the header.
+    pub fn tag_len(&self) -> usize {
+        match self {
+            Integrity::None => 0,
+            Integrity::Crc32c => 4,
+            Integrity::Xxh3 => 8,
+            Integrity::Sha256 | Integrity::Mac => 32,
+        }
+    }
+
+    /// Whether the tag depends on the session key.
+    pub fn is_keyed(&self) -> bool {
+        *self == Integrity::Mac
+    }
+
+    /// Tag of a message whose header, without the tag, is `header`. `key`
+    /// is only used by keyed checks.
+    pub(crate) fn tag(&self, key: &[u8; 32], header: &[u8], data: &[u8]) -> Tag {
+        match self {
+            Integrity::None => Tag::new(&[]),
+            Integrity::Crc32c => {
+                Tag::new(&crc32c::crc32c_append(crc32c::crc32c(header), data).to_be_bytes())
+            }
+            Integrity::Xxh3 => {
+                let mut hasher = Xxh3::new();
+                hasher.update(header);
+                hasher.update(data);
+                Tag::new(&hasher.digest().to_be_bytes())
+            }
+            Integrity::Sha256 => {
+                let mut hasher = Sha256::new();
+                hasher.update(header);
+                hasher.update(data);
+                Tag::new(&hasher.finalize())
+            }
+            Integrity::Mac => Tag::new(&hmac(key, header, data).finalize().into_bytes()),
+        }
+    }
+
+    /// Whether `tag` is what this check makes of the message.
+    pub(crate) fn verify(&self, key: &[u8; 32], header: &[u8], data: &[u8], tag: &[u8]) -> bool {
+        if tag.len() != self.tag_len() {
+            return false;
+        }
+        match self {
+            // Compared in constant time
+            Integrity::Mac => hmac(key, header, data).verify_slice(tag).is_ok(),
+            _ => *self.tag(key, header, data) == *tag,
+        }
+    }
+}
+
+impl fmt::Display for Integrity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+fn hmac(key: &[u8; 32], header: &[u8], data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes any key size");
+    mac.update(header);
+    mac.update(data);
+    mac
+}
+
+/// The integrity tag of a [`Message`](crate::Message), as many bytes as
+/// its [`Integrity`] check makes.
+///
+/// # Examples
+///
+/// ```
+/// # use udp_connection::Message;
+/// let message = Message::new(1, &b"data"[..]);
+/// assert_eq!(message.hash.len(), 32);
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Tag {
+    len: u8,
+    bytes: [u8; MAX_TAG_LEN],
+}
+
+impl Tag {
+    /// # Panics
+    ///
+    /// Panics if `bytes` is longer than [`MAX_TAG_LEN`].
+    pub fn new(bytes: &[u8]) -> Tag {
+        let mut tag = Tag {
+            len: bytes.len() as u8,
+            bytes: [0; MAX_TAG_LEN],
+        };
+        tag.bytes[..bytes.len()].copy_from_slice(bytes);
+        tag
+    }
+}
+
+impl Deref for Tag {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+impl fmt::Debug for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
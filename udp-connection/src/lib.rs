@@ -3,10 +3,66 @@
 pub mod socket_worker;
 mod message;
 pub mod socket_worker_handshake;
+mod connection;
+mod connection_stream;
 mod control_message;
+mod compression;
+mod config;
+mod mtu;
+mod pacer;
+mod batch_io;
+pub mod capture;
+mod delivery;
+mod event;
+mod stream;
+mod session;
+mod replay;
+pub mod discovery;
+pub mod rpc;
+mod state;
+mod integrity;
+mod reconnect;
+mod protocol;
+mod readiness;
+pub mod sim;
+mod header;
+mod error;
+#[cfg(feature = "serde")]
+mod typed;
+
+#[cfg(test)]
+mod tests;
 
 // Re-export commonly used types
 pub use socket_worker::SocketWorker;
-pub use message::Message;
-pub use socket_worker_handshake::{receive_handshake, send_handshake};
+pub use protocol::{Protocol, Transmit};
+pub use readiness::wait_readable;
+pub use message::{Message, HEADER_LEN};
+pub use header::{PacketType, WireFormat, VERSION};
+pub use socket_worker_handshake::{
+    receive_handshake, receive_handshake_with_config, send_handshake, send_handshake_timeout,
+    send_handshake_with_config,
+};
 pub use control_message::ControlMessage;
+pub use compression::Compression;
+pub use integrity::{Integrity, Tag};
+pub use config::{Config, QueuePolicy};
+pub use delivery::{DeliveryStatus, MessageId, QueueDepth, SendError, Ttl};
+pub use event::Event;
+pub use error::ConnectionError;
+#[cfg(feature = "serde")]
+pub use typed::{Codec, TypedConnection, TypedEvent};
+#[cfg(feature = "json")]
+pub use typed::Json;
+#[cfg(feature = "bincode")]
+pub use typed::Bincode;
+#[cfg(feature = "postcard")]
+pub use typed::Postcard;
+pub use state::ConnectionState;
+pub use reconnect::{ReconnectPolicy, ReconnectStatus, ReconnectingConnection};
+pub use stream::{StreamId, StreamReader};
+pub use connection::{Connection, ConnectionSender};
+pub use connection_stream::ConnectionStream;
+pub use capture::{Capture, CaptureReader, CaptureRecord, Direction};
+pub use discovery::{Beacon, Discovery, DiscoveryConfig, DiscoveryEvent};
+pub use rpc::{CallId, Rpc, RpcError, RpcEvent};
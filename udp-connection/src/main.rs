@@ -1,78 +1,391 @@
-use std::time::Duration;
-use std::{io, thread};
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    process,
+    sync::mpsc::{self, RecvTimeoutError, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
 
-use crate::message::Message;
-use crate::socket_worker_handshake::{receive_handshake, send_handshake};
+use udp_connection::{
+    receive_handshake_with_config, send_handshake_with_config, Capture, Config, Connection,
+    ConnectionState, Event, Integrity, SocketWorker,
+};
 
-mod socket_worker;
-mod message;
-mod socket_worker_handshake;
-mod control_message;
+const USAGE: &str = "\
+Usage: udp-connection <command> [options]
 
-#[cfg(test)]
-mod tests;
+Commands:
+    listen <addr>               Accept one peer, pipe stdin/stdout over the connection
+    connect <addr>              Connect to a peer, pipe stdin/stdout over the connection
+    send-file <addr> <path>     Connect to a peer and send it a file
+    recv-file <addr> <path>     Accept one peer and store the file it sends
+    ping <addr>                 Measure the round trip time to a listening peer
+
+Options:
+    --datagram-size <bytes>     Largest datagram to send or accept
+    --base-datagram-size <bytes>
+                                Datagram size assumed to reach every peer
+    --no-mtu-probing            Use --datagram-size without probing the path
+    --window <datagrams>        Datagrams sent per tick at most
+    --rate <bytes/s>            Cap on outgoing bandwidth
+    --burst <bytes>             Bytes sent back to back while under the cap
+    --no-batch-syscalls         One syscall per datagram
+    --integrity <checks>        Integrity checks to offer or accept, most preferred
+                                first: mac (default), sha256, xxh3, crc32c, none
+    --capture <path>            Record every datagram for udpc-dump
+    --trace                     Log every datagram to stderr
+    --count <pings>             Pings to send (default 4)";
+
+/// Bytes in front of every `send-file` chunk, the offset of the chunk.
+const OFFSET_LEN: usize = 8;
+
+/// `send-file` chunks queued but not acknowledged at most.
+const CHUNKS_IN_FLIGHT: usize = 256;
+
+/// How long to wait for events before checking stdin again.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long the file receiver keeps acknowledging retransmissions after the
+/// transfer completed, in case its last ACKs were lost.
+const LINGER: Duration = Duration::from_secs(2);
+
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Options {
+    config: Config,
+    capture: Option<String>,
+    count: u32,
+}
 
 fn main() {
-    //_message_test();
+    let mut args = std::env::args().skip(1);
+    let command = args.next().unwrap_or_default();
+
+    let result = parse_args(args).and_then(|(positional, options)| {
+        match (command.as_str(), positional.as_slice()) {
+            ("listen", [addr]) => listen(addr, &options).and_then(pipe),
+            ("connect", [addr]) => connect(addr, &options).and_then(pipe),
+            ("send-file", [addr, path]) => send_file(connect(addr, &options)?, path),
+            ("recv-file", [addr, path]) => recv_file(listen(addr, &options)?, path),
+            ("ping", [addr]) => ping(connect(addr, &options)?, options.count),
+            _ => Err(USAGE.to_string()),
+        }
+    });
+
+    if let Err(e) = result {
+        eprintln!("{e}");
+        process::exit(1);
+    }
+}
 
-    /*let socket1 = UdpSocket::bind("127.0.0.1:0").unwrap();
-    let socket2 = UdpSocket::bind("127.0.0.1:0").unwrap();
+fn parse_args(args: impl Iterator<Item = String>) -> Result<(Vec<String>, Options), String> {
+    let mut positional = Vec::new();
+    let mut options = Options {
+        config: Config::default(),
+        capture: None,
+        count: 4,
+    };
 
-    print!("{:?} | {:?}", socket1.local_addr(), socket2.local_addr());*/
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| format!("{name} needs a value\n\n{USAGE}"))
+        };
 
-    _socket_test();
+        match arg.as_str() {
+            "--datagram-size" => options.config.max_datagram_size = number(&value(&arg)?)?,
+            "--base-datagram-size" => options.config.base_datagram_size = number(&value(&arg)?)?,
+            "--no-mtu-probing" => options.config.mtu_probing = false,
+            "--window" => options.config.send_window = number(&value(&arg)?)?,
+            "--rate" => options.config.rate_limit = Some(number(&value(&arg)?)?),
+            "--burst" => options.config.burst = number(&value(&arg)?)?,
+            "--no-batch-syscalls" => options.config.batch_syscalls = false,
+            "--integrity" => options.config.integrity = integrity(&value(&arg)?)?,
+            "--capture" => options.capture = Some(value(&arg)?),
+            "--trace" => options.config.trace = true,
+            "--count" => options.count = number(&value(&arg)?)?,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ if arg.starts_with("--") => {
+                return Err(format!("Unknown option '{arg}'\n\n{USAGE}"))
+            }
+            _ => positional.push(arg),
+        }
+    }
 
-    println!("End!");
+    Ok((positional, options))
 }
 
-fn _socket_test() {
-    println!("Server or client?");
-    
-    let mut cmd = String::new();
-    
-    io::stdin().read_line(&mut cmd).expect("Error read_line");
-    
-    match cmd.to_lowercase().trim() {
-        "server" => run_server(),
-        "client" => run_client(),
-        _ => { println!("Wrong command!") }
+fn number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("'{value}' is not a number"))
+}
+
+fn integrity(value: &str) -> Result<Vec<Integrity>, String> {
+    value
+        .split(',')
+        .map(|name| {
+            Integrity::from_name(name).ok_or_else(|| format!("Unknown integrity check '{name}'"))
+        })
+        .collect()
+}
+
+fn listen(addr: &str, options: &Options) -> Result<SocketWorker, String> {
+    eprintln!("Waiting for a peer on {addr}");
+    let socket = std::net::UdpSocket::bind(addr).map_err(|e| format!("Can't bind {addr}: {e}"))?;
+    let worker = receive_handshake_with_config(&socket, |_| {}, config(options)?)
+        .map_err(|e| format!("Handshake on {addr} failed: {e}"))?;
+
+    setup(worker)
+}
+
+fn connect(addr: &str, options: &Options) -> Result<SocketWorker, String> {
+    let worker = send_handshake_with_config(addr.to_string(), |_| {}, config(options)?)
+        .map_err(|e| format!("Handshake with {addr} failed: {e}"))?;
+
+    setup(worker)
+}
+
+/// The config of the options, capturing from the handshake on.
+fn config(options: &Options) -> Result<Config, String> {
+    let mut config = options.config.clone();
+    if let Some(path) = &options.capture {
+        let capture = Capture::create(path).map_err(|e| format!("Can't create {path}: {e}"))?;
+        config.capture = Some(capture);
     }
+
+    Ok(config)
+}
+
+fn setup(worker: SocketWorker) -> Result<SocketWorker, String> {
+    eprintln!(
+        "Connected to {} (compression: {}, integrity: {})",
+        worker.address,
+        worker.compression().name(),
+        worker.integrity()
+    );
+
+    Ok(worker)
 }
 
-fn run_server() {
-    let mut worker = receive_handshake(
-        "127.0.0.1:8080".to_string(),
-        |msg| println!("{:?}", msg)).unwrap();
+/// Sends stdin to the peer and writes what it sends to stdout, like netcat.
+///
+/// Closes the connection once stdin is closed and everything read from it
+/// was delivered, returns once either side closed it.
+fn pipe(worker: SocketWorker) -> Result<(), String> {
+    // The path MTU only grows, what fits now always fits
+    let chunk = worker.max_payload();
+    let connection = Connection::spawn(worker);
 
-    println!("Made worker {:?}", worker);
+    let sender = connection.sender();
+    let (input_tx, input_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        let mut buf = vec![0; chunk];
+        while let Ok(n @ 1..) = stdin.read(&mut buf) {
+            if sender.send(buf[..n].to_vec()).is_err() {
+                break;
+            }
+            _ = input_tx.send(());
+        }
+        // Dropping `input_tx` tells the main thread stdin is done
+    });
 
+    let mut stdout = io::stdout().lock();
+    let mut sent = 0;
+    let mut delivered = 0;
+    let mut eof = false;
+    let mut closing = false;
     loop {
-        //println!("work");
-        worker.work();
+        loop {
+            match input_rx.try_recv() {
+                Ok(()) => sent += 1,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    eof = true;
+                    break;
+                }
+            }
+        }
+        if eof && delivered == sent && !closing {
+            // Fails only if the peer closed first, reported below
+            _ = connection.sender().close();
+            closing = true;
+        }
 
-        thread::sleep(Duration::from_millis(100));
+        match connection.receiver().recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(Event::Message(data))) => {
+                stdout
+                    .write_all(&data)
+                    .and_then(|_| stdout.flush())
+                    .map_err(|e| format!("Error writing stdout {e}"))?;
+            }
+            Ok(Ok(Event::Delivered(_))) => delivered += 1,
+            Ok(Ok(Event::StateChanged(ConnectionState::Closed))) => return Ok(()),
+            Ok(Ok(Event::StateChanged(ConnectionState::TimedOut))) => {
+                return Err("Connection timed out".to_string())
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => eprintln!("{e}"),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Err("Connection closed".to_string()),
+        }
     }
 }
 
-fn run_client() {
-    let mut worker = send_handshake(
-        "127.0.0.1:8080".to_string(),
-        |msg| println!("{:?}", msg)).unwrap();
+/// Sends the file as chunks prefixed with their offset, followed by an
+/// empty chunk at the file length. Chunks may arrive in any order.
+///
+/// Reads ahead of the peer by [`CHUNKS_IN_FLIGHT`] chunks at most, so the
+/// file doesn't have to fit into memory.
+fn send_file(mut worker: SocketWorker, path: &str) -> Result<(), String> {
+    let mut file = File::open(path).map_err(|e| format!("Can't open {path}: {e}"))?;
+    let start = Instant::now();
 
-    println!("Made worker {:?}", worker);
-    
-    worker.send_message("Aaa it worked!".as_bytes().to_vec().into_boxed_slice());
+    let mut buf = vec![0; worker.max_payload() - OFFSET_LEN];
+    let mut offset = 0u64;
+    let mut queued = 0;
+    let mut delivered = 0;
+    let mut eof = false;
+    while !eof || delivered < queued {
+        while !eof && queued - delivered < CHUNKS_IN_FLIGHT {
+            let n = file
+                .read(&mut buf)
+                .map_err(|e| format!("Error reading {path}: {e}"))?;
+            let mut chunk = Vec::with_capacity(OFFSET_LEN + n);
+            chunk.extend_from_slice(&offset.to_be_bytes());
+            chunk.extend_from_slice(&buf[..n]);
+            worker.send_message(chunk).map_err(|e| e.to_string())?;
+            queued += 1;
 
-    loop {
-        //println!("work");
-        worker.work();
+            offset += n as u64;
+            eof = n == 0;
+        }
 
-        thread::sleep(Duration::from_millis(100));
+        let events = worker.work();
+        if events.is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        for event in events {
+            match event {
+                Ok(Event::Delivered(_)) => delivered += 1,
+                Ok(Event::StateChanged(state)) if state.is_final() => {
+                    return Err(format!("Connection {state} after {offset} bytes"))
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("{e}"),
+            }
+        }
     }
+
+    report("Sent", offset, start.elapsed());
+    Ok(())
 }
 
-fn _message_test() {
-    let m1 = Message::new(1, format!("hi!!!").as_bytes().to_vec().into_boxed_slice());
+fn recv_file(mut worker: SocketWorker, path: &str) -> Result<(), String> {
+    let mut file = File::create(path).map_err(|e| format!("Can't create {path}: {e}"))?;
+    let start = Instant::now();
+
+    let mut received = 0u64;
+    let mut total = None;
+    while total != Some(received) {
+        let events = worker.work();
+        if events.is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        for event in events {
+            let data = match event {
+                Ok(Event::Message(data)) if data.len() >= OFFSET_LEN => data,
+                Ok(Event::Message(_)) => return Err("Malformed file chunk".to_string()),
+                Ok(_) => continue,
+                Err(e) => {
+                    eprintln!("{e}");
+                    continue;
+                }
+            };
+
+            let offset = u64::from_be_bytes(data[..OFFSET_LEN].try_into().unwrap());
+            let chunk = &data[OFFSET_LEN..];
+            if chunk.is_empty() {
+                total = Some(offset);
+                continue;
+            }
+
+            file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.write_all(chunk))
+                .map_err(|e| format!("Error writing {path}: {e}"))?;
+            received += chunk.len() as u64;
+        }
+    }
+
+    report("Received", received, start.elapsed());
+
+    // Keep acknowledging retransmissions whose ACK got lost
+    let linger = Instant::now();
+    while linger.elapsed() < LINGER {
+        worker.work();
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    Ok(())
+}
+
+fn report(what: &str, bytes: u64, elapsed: Duration) {
+    eprintln!(
+        "{what} {bytes} bytes in {:.2}s ({:.1} KiB/s)",
+        elapsed.as_secs_f64(),
+        bytes as f64 / 1024.0 / elapsed.as_secs_f64().max(f64::EPSILON)
+    );
+}
+
+fn ping(mut worker: SocketWorker, count: u32) -> Result<(), String> {
+    let mut rtts = Vec::new();
+
+    for seq in 0..count {
+        worker.ping();
+        let deadline = Instant::now() + PING_INTERVAL;
+        let mut answered = false;
+
+        while Instant::now() < deadline {
+            for event in worker.work() {
+                match event {
+                    Ok(Event::Pong(rtt)) => {
+                        println!(
+                            "pong from {}: seq={seq} time={:.3} ms",
+                            worker.address,
+                            rtt.as_secs_f64() * 1000.0
+                        );
+                        rtts.push(rtt);
+                        answered = true;
+                    }
+                    Ok(_) => {}
+                    Err(e) => eprintln!("{e}"),
+                }
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        if !answered {
+            println!("seq={seq} timed out");
+        }
+    }
+
+    println!(
+        "{count} pings sent, {} answered, {:.0}% lost",
+        rtts.len(),
+        100.0 * (count as usize - rtts.len()) as f64 / count.max(1) as f64
+    );
+    if let (Some(min), Some(max)) = (rtts.iter().min(), rtts.iter().max()) {
+        let avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+        println!(
+            "rtt min/avg/max = {:.3}/{:.3}/{:.3} ms",
+            min.as_secs_f64() * 1000.0,
+            avg.as_secs_f64() * 1000.0,
+            max.as_secs_f64() * 1000.0
+        );
+    }
 
-    println!("{}", m1);
+    Ok(())
 }
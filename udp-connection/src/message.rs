@@ -1,17 +1,56 @@
-use sha2::{Digest, Sha256};
-use std::fmt;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{fmt, ops::RangeInclusive};
 
-use crate::control_message::ControlMessage;
+use crate::{
+    control_message::ControlMessage,
+    error::ConnectionError,
+    header::{self, Header, PacketType, WireFormat, MAX_PREFIX_LEN, MAX_VARINT_LEN},
+    integrity::{Integrity, Tag, MAX_TAG_LEN},
+};
 
-/// A message struct that contains an ID, SHA-256 hash, and data payload.
-/// The hash is computed from the ID and data to ensure message integrity.
+/// Size of the longest serialized header: a version 1 header with a
+/// connection ID, the longest ID and a 32 byte tag.
+pub const HEADER_LEN: usize = MAX_PREFIX_LEN + MAX_TAG_LEN;
+
+/// Largest datagram UDP can carry over IPv4.
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// Largest payload that fits into a single datagram.
+pub const MAX_PAYLOAD_SIZE: usize = MAX_DATAGRAM_SIZE - HEADER_LEN;
+
+/// Key for checks that take none.
+const NO_KEY: [u8; 32] = [0; 32];
+
+/// A message struct that contains an ID, integrity tag, and data payload.
+/// The tag is computed from the header and data to ensure message
+/// integrity, with SHA-256 unless the message is sealed with another
+/// [`Integrity`] check.
+///
+/// The payload is a [`Bytes`] handle, so deserialized messages share the
+/// buffer of the datagram they arrived in instead of copying it.
+#[derive(Debug, Clone)]
 pub struct Message {
-    /// Unique identifier for the message
+    /// Unique identifier for the message, 0 for control packets
     pub id: u64,
-    /// SHA-256 hash of the ID and data combined
-    pub hash: Box<[u8]>,
+    /// What the packet carries
+    pub kind: PacketType,
+    /// Tag of the header and data combined, as long as its check makes it
+    pub hash: Tag,
     /// Message payload data
-    pub data: Box<[u8]>,
+    pub data: Bytes,
+    /// Whether `data` holds a compressed payload
+    pub compressed: bool,
+    /// Whether `data` is a frame of a byte stream rather than a message for
+    /// the application
+    pub stream: bool,
+    /// Whether `data` is part of a larger message
+    pub fragment: bool,
+    /// Whether the sender won't retransmit the message
+    pub unreliable: bool,
+    /// Connection the message belongs to, if the sender says
+    pub connection_id: Option<u32>,
+    /// Header layout [`Message::serialize`] writes
+    pub format: WireFormat,
 }
 
 impl Message {
@@ -22,11 +61,11 @@ impl Message {
     /// # Arguments
     ///
     /// * `id` - Unique identifier for the message
-    /// * `data` - Message payload data (must be ≤ 500 bytes)
+    /// * `data` - Message payload data (must be ≤ [`MAX_PAYLOAD_SIZE`] bytes)
     ///
     /// # Panics
     ///
-    /// Panics if the data length exceeds 500 bytes.
+    /// Panics if the data length exceeds [`MAX_PAYLOAD_SIZE`] bytes.
     ///
     /// # Examples
     ///
@@ -36,19 +75,120 @@ impl Message {
     /// let message = Message::new(1, data);
     /// assert_eq!(message.id, 1);
     /// ```
-    pub fn new(id: u64, data: Box<[u8]>) -> Message {
-        if data.len() > 500 {
+    pub fn new(id: u64, data: impl Into<Bytes>) -> Message {
+        Message::build(id, data.into(), false, false)
+    }
+
+    /// Creates a new message whose payload has already been compressed.
+    ///
+    /// The compressed flag is carried in the header and is covered by the
+    /// hash.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the data length exceeds [`MAX_PAYLOAD_SIZE`] bytes.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use udp_connection::Message;
+    /// let data = b"compressed bytes".to_vec().into_boxed_slice();
+    /// let message = Message::new_compressed(1, data);
+    /// assert!(message.compressed);
+    /// assert!(message.check_hash());
+    /// ```
+    pub fn new_compressed(id: u64, data: impl Into<Bytes>) -> Message {
+        Message::build(id, data.into(), true, false)
+    }
+
+    /// Creates a message carrying a frame of a byte stream, see
+    /// [`SocketWorker::send_stream`](crate::SocketWorker::send_stream).
+    ///
+    /// Like the compressed flag, the stream flag is carried in the header
+    /// and covered by the hash.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use udp_connection::Message;
+    /// let message = Message::new_stream(1, &b"frame"[..]);
+    /// let deserialized = Message::deserialize(message.serialize());
+    /// assert!(deserialized.stream);
+    /// assert!(deserialized.check_hash());
+    /// ```
+    pub fn new_stream(id: u64, data: impl Into<Bytes>) -> Message {
+        Message::build(id, data.into(), false, true)
+    }
+
+    /// Creates the stand-in for a message the sender gave up on: an empty
+    /// stream frame. The receiver acknowledges it like any message, so the
+    /// ID doesn't stay missing, and otherwise ignores it.
+    pub fn new_placeholder(id: u64) -> Message {
+        Message::new_stream(id, Bytes::new())
+    }
+
+    /// Stands in for all messages from `first` to `last`, like
+    /// [`Message::new_placeholder`] does for one. It takes the ID `last`
+    /// and carries `first`, too short for a stream frame.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use udp_connection::Message;
+    /// let skip = Message::new_skip(3, 9);
+    /// assert!(skip.is_placeholder());
+    /// assert_eq!(skip.id, 9);
+    /// assert_eq!(skip.skipped(), 3..=9);
+    /// ```
+    pub fn new_skip(first: u64, last: u64) -> Message {
+        if first == last {
+            return Message::new_placeholder(last);
+        }
+        Message::new_stream(last, Bytes::copy_from_slice(&first.to_be_bytes()))
+    }
+
+    /// Whether this is a [`Message::new_placeholder`] or a
+    /// [`Message::new_skip`].
+    pub fn is_placeholder(&self) -> bool {
+        self.stream && (self.data.is_empty() || self.data.len() == 8)
+    }
+
+    /// IDs a placeholder stands in for.
+    pub fn skipped(&self) -> RangeInclusive<u64> {
+        match self.data.get(..8) {
+            Some(first) => u64::from_be_bytes(first.try_into().expect("8 bytes"))..=self.id,
+            None => self.id..=self.id,
+        }
+    }
+
+    fn build(id: u64, data: Bytes, compressed: bool, stream: bool) -> Message {
+        let mut msg = Message::unsealed(id, data, compressed, stream);
+        msg.seal_with(Integrity::Sha256, &NO_KEY);
+        msg
+    }
+
+    /// A message without a tag yet, for callers that seal it right away
+    /// and shouldn't pay for SHA-256 first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the data length exceeds [`MAX_PAYLOAD_SIZE`] bytes.
+    pub(crate) fn unsealed(id: u64, data: Bytes, compressed: bool, stream: bool) -> Message {
+        if data.len() > MAX_PAYLOAD_SIZE {
             panic!("To big packet!")
         }
-        let mut hasher = Sha256::new();
-        hasher.update(&id.to_be_bytes());
-        hasher.update(&data);
-        let hash: Box<[u8]> = hasher.finalize().to_vec().into_boxed_slice();
 
         Message {
             id,
-            hash: hash,
+            kind: PacketType::Data,
+            hash: Tag::new(&[]),
             data,
+            compressed,
+            stream,
+            fragment: false,
+            unreliable: false,
+            connection_id: None,
+            format: WireFormat::V1,
         }
     }
 
@@ -61,73 +201,367 @@ impl Message {
     /// # Returns
     ///
     /// A new Message with:
-    /// - ID set to 0 (indicating a control message)
+    /// - ID set to 0 and type [`PacketType::Ack`]
     /// - Empty hash (32 zero bytes)
-    /// - Data containing the message type (1 for ACK) and the acknowledged message ID
+    /// - Data containing the acknowledged message ID
     ///
     /// # Examples
     ///
     /// ```
-    /// # use udp_connection::Message;
+    /// # use udp_connection::{Message, PacketType};
     /// let ack = Message::new_acc(42);
     /// assert_eq!(ack.id, 0); // Control messages always have ID 0
-    /// assert_eq!(ack.data.len(), 9); // 1 byte type + 8 bytes message ID
+    /// assert_eq!(ack.kind, PacketType::Ack);
+    /// assert_eq!(ack.data.len(), 8); // 8 bytes message ID
     /// ```
     pub fn new_acc(id: u64) -> Message {
-        let hash = [0u8; 32].to_vec().into_boxed_slice();
-        let data = 1u8
-            .to_be_bytes()
-            .iter()
-            .chain(id.to_be_bytes().iter())
-            .copied()
-            .collect::<Vec<u8>>()
-            .into_boxed_slice();
-        let id = 0u64;
+        let mut data = BytesMut::with_capacity(8);
+        data.put_u64(id);
 
-        Message { id, hash, data }
+        Message::control(PacketType::Ack, data.freeze())
     }
 
-    /// Deserializes a byte buffer into a Message.
+    /// Creates a batch control message that packs several serialized messages
+    /// into a single datagram.
     ///
-    /// The expected format is:
-    /// - Bytes 0-8: Message ID (big-endian u64)
-    /// - Bytes 8-40: SHA-256 hash (32 bytes)
-    /// - Bytes 40+: Message data
+    /// Every inner message keeps its own ID and hash, so the receiver handles
+    /// them exactly as if they had arrived in separate datagrams.
     ///
     /// # Arguments
     ///
-    /// * `ser` - Serialized message buffer (must be ≥ 40 bytes)
+    /// * `messages` - Serialized messages (see [`Message::serialize`])
+    ///
+    /// # Returns
+    ///
+    /// A new Message with:
+    /// - ID set to 0 and type [`PacketType::Batch`]
+    /// - No hash
+    /// - Data containing every inner message prefixed with its length as a
+    ///   big-endian u16
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use udp_connection::Message;
+    /// let a = Message::new(1, &b"a"[..]).serialize();
+    /// let b = Message::new_acc(7).serialize();
+    /// let batch = Message::new_batch(&[a, b]);
+    /// assert_eq!(batch.id, 0);
+    /// assert_eq!(batch.data.len(), (2 + 37) + (2 + 44));
+    /// ```
+    pub fn new_batch(messages: &[Bytes]) -> Message {
+        let len = messages.iter().map(|msg| 2 + msg.len()).sum::<usize>();
+        let mut data = BytesMut::with_capacity(len);
+        for msg in messages {
+            let len = u16::try_from(msg.len()).expect("Batched message too big!");
+            data.put_u16(len);
+            data.put_slice(msg);
+        }
+
+        let mut batch = Message::batch_container(WireFormat::V1, MAX_TAG_LEN);
+        batch.data = data.freeze();
+        batch
+    }
+
+    /// An empty batch. Version 1 batches have no tag, legacy ones a zeroed
+    /// tag of the connection's length.
+    fn batch_container(format: WireFormat, tag_len: usize) -> Message {
+        let tag_len = match format {
+            WireFormat::Legacy => tag_len,
+            WireFormat::V1 => 0,
+        };
+        let mut batch = Message::control(PacketType::Batch, Bytes::new());
+        batch.hash = Tag::new(&[0; MAX_TAG_LEN][..tag_len]);
+        batch.format = format;
+        batch
+    }
+
+    /// Writes the header of a batch control message into `buf`.
+    ///
+    /// Follow it with [`Message::serialize_batched_into`] for every inner
+    /// message to build the same datagram as [`Message::new_batch`] without
+    /// serializing the inner messages separately first.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use bytes::BytesMut;
+    /// # use udp_connection::Message;
+    /// let a = Message::new(1, &b"a"[..]);
+    /// let b = Message::new_acc(7);
+    ///
+    /// let mut buf = BytesMut::new();
+    /// Message::begin_batch_into(&mut buf);
+    /// a.serialize_batched_into(&mut buf);
+    /// b.serialize_batched_into(&mut buf);
+    ///
+    /// let batch = Message::new_batch(&[a.serialize(), b.serialize()]);
+    /// assert_eq!(buf, batch.serialize());
+    /// ```
+    pub fn begin_batch_into(buf: &mut BytesMut) {
+        Message::begin_batch_for(buf, WireFormat::V1, MAX_TAG_LEN);
+    }
+
+    /// Like [`Message::begin_batch_into`], for a connection with the given
+    /// header format whose tags are `tag_len` bytes long.
+    pub(crate) fn begin_batch_for(buf: &mut BytesMut, format: WireFormat, tag_len: usize) {
+        Message::batch_container(format, tag_len).serialize_into(buf);
+    }
+
+    /// Bytes [`Message::begin_batch_for`] writes.
+    pub(crate) fn batch_header_len(format: WireFormat, tag_len: usize) -> usize {
+        Message::batch_container(format, tag_len).header_len()
+    }
+
+    /// Appends this message to a batch started with
+    /// [`Message::begin_batch_into`].
+    pub fn serialize_batched_into(&self, buf: &mut BytesMut) {
+        let len = u16::try_from(self.serialized_len()).expect("Batched message too big!");
+        buf.put_u16(len);
+        self.serialize_into(buf);
+    }
+
+    /// Creates a path MTU probe control message.
+    ///
+    /// The message is padded so that it serializes to exactly `size` bytes.
+    /// A peer that receives it answers with [`Message::new_probe_ack`].
     ///
     /// # Panics
     ///
-    /// Panics if the buffer is less than 40 bytes.
+    /// Panics if `size` is smaller than the probe header or larger than
+    /// [`MAX_DATAGRAM_SIZE`].
     ///
     /// # Examples
     ///
     /// ```
     /// # use udp_connection::Message;
+    /// let probe = Message::new_probe(1200);
+    /// assert_eq!(probe.serialize().len(), 1200);
+    /// ```
+    pub fn new_probe(size: usize) -> Message {
+        Message::new_probe_for(size, WireFormat::V1, MAX_TAG_LEN)
+    }
+
+    /// Like [`Message::new_probe`], padded for the given header format and
+    /// a tag of `tag_len` bytes.
+    pub(crate) fn new_probe_for(size: usize, format: WireFormat, tag_len: usize) -> Message {
+        let mut probe = Message::control(PacketType::Probe, Bytes::new());
+        probe.hash = Tag::new(&[0; MAX_TAG_LEN][..tag_len]);
+        probe.format = format;
+
+        let header_len = probe.header_len();
+        if !(header_len + 2..=MAX_DATAGRAM_SIZE).contains(&size) {
+            panic!("Wrong probe size {}!", size)
+        }
+        let mut data = BytesMut::zeroed(size - header_len);
+        data[..2].copy_from_slice(&(size as u16).to_be_bytes());
+        probe.data = data.freeze();
+        probe
+    }
+
+    /// Creates the acknowledgment of a path MTU probe of `size` bytes.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use udp_connection::{ControlMessage, Message};
+    /// let ack = Message::new_probe_ack(1200);
+    /// match ack.get_control() {
+    ///     ControlMessage::ProbeAck { size } => assert_eq!(size, 1200),
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
+    pub fn new_probe_ack(size: usize) -> Message {
+        let mut data = BytesMut::with_capacity(2);
+        data.put_u16(size as u16);
+
+        Message::control(PacketType::ProbeAck, data.freeze())
+    }
+
+    /// Creates a ping control message. The peer answers with
+    /// [`Message::new_pong`] carrying the same `token`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use udp_connection::{ControlMessage, Message};
+    /// let ping = Message::new_ping(7);
+    /// match ping.get_control() {
+    ///     ControlMessage::Ping { token } => assert_eq!(token, 7),
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
+    pub fn new_ping(token: u64) -> Message {
+        let mut data = BytesMut::with_capacity(8);
+        data.put_u64(token);
+
+        Message::control(PacketType::Ping, data.freeze())
+    }
+
+    /// Creates the answer to a ping carrying `token`.
+    pub fn new_pong(token: u64) -> Message {
+        let mut data = BytesMut::with_capacity(8);
+        data.put_u64(token);
+
+        Message::control(PacketType::Pong, data.freeze())
+    }
+
+    /// Creates the message telling the peer we are done with the connection.
+    pub fn new_close() -> Message {
+        Message::control(PacketType::Close, Bytes::new())
+    }
+
+    fn control(kind: PacketType, data: Bytes) -> Message {
+        Message {
+            id: 0,
+            kind,
+            hash: Tag::new(&[0; MAX_TAG_LEN]),
+            data,
+            compressed: false,
+            stream: false,
+            fragment: false,
+            unreliable: false,
+            connection_id: None,
+            format: WireFormat::V1,
+        }
+    }
+
+    /// Whether this is a control packet rather than data.
+    pub fn is_control(&self) -> bool {
+        self.kind != PacketType::Data
+    }
+
+    /// Deserializes a byte buffer into a Message.
+    ///
+    /// The payload of the returned message points into `ser`, nothing is
+    /// copied.
+    ///
+    /// Both header formats are understood, see [`WireFormat`]. Version 1
+    /// is:
+    /// - Version u8, packet type u8 and flags u8
+    /// - Connection ID u32, if flagged
+    /// - Message ID, LEB128
+    /// - 0, 4, 8 or 32 bytes: Tag, its length given by the flags
+    /// - Rest: Message data
+    ///
+    /// # Arguments
+    ///
+    /// * `ser` - Serialized message buffer, see [`Message::header_len_of`]
+    ///
+    /// # Panics
+    ///
+    /// Panics if the buffer doesn't start with a valid header, see
+    /// [`Message::try_deserialize`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use udp_connection::{Message, WireFormat};
     /// let mut buffer = Vec::new();
     /// buffer.extend_from_slice(&100u64.to_be_bytes());
     /// buffer.extend_from_slice(&[0u8; 32]); // hash
     /// buffer.extend_from_slice(b"test");
-    /// let message = Message::deserialize(&buffer);
+    /// let message = Message::deserialize(buffer);
     /// assert_eq!(message.id, 100);
+    /// assert_eq!(message.format, WireFormat::Legacy);
     /// ```
-    pub fn deserialize(ser: &[u8]) -> Message {
-        if ser.len() < 40 {
-            panic!("Buffer length < 40")
-        }
+    pub fn deserialize(ser: impl Into<Bytes>) -> Message {
+        Message::try_deserialize(ser).unwrap_or_else(|e| panic!("{e}"))
+    }
 
-        let id = u64::from_be_bytes(ser[0..8].try_into().expect("Error casting id!"));
+    /// Like [`Message::deserialize`], failing on a truncated header, an
+    /// unknown version or packet type, or flags this version doesn't know.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use udp_connection::Message;
+    /// assert!(Message::try_deserialize(&[1u8, 0, 0x80][..]).is_err());
+    /// assert!(Message::try_deserialize(&[2u8, 0, 0, 0][..]).is_err());
+    /// ```
+    pub fn try_deserialize(ser: impl Into<Bytes>) -> Result<Message, String> {
+        let ser: Bytes = ser.into();
+        let header = header::parse(&ser)?;
+        let hash = Tag::new(&ser[header.tag_at()..header.tag_at() + header.tag_len]);
+
+        // Moving past the header keeps the single handle to the buffer,
+        // cheaper than slicing out a second one
+        let mut data = ser;
+        data.advance(header.len());
 
-        let hash = ser[8..40].to_vec().into_boxed_slice();
+        Ok(Message {
+            id: header.id,
+            kind: header.kind,
+            hash,
+            data,
+            compressed: header.compressed,
+            stream: header.stream,
+            fragment: header.fragment,
+            unreliable: header.unreliable,
+            connection_id: header.connection_id,
+            format: header.format,
+        })
+    }
 
-        let data = ser[40..].to_vec().into_boxed_slice();
+    /// Length of the header of a serialized message.
+    ///
+    /// # Returns
+    ///
+    /// `None` if `ser` doesn't start with a valid header.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use udp_connection::{Integrity, Message};
+    /// let mut message = Message::new(7, &b"data"[..]);
+    /// assert_eq!(Message::header_len_of(&message.serialize()), Some(36));
+    ///
+    /// message.seal_with(Integrity::Crc32c, &[0; 32]);
+    /// assert_eq!(Message::header_len_of(&message.serialize()), Some(8));
+    /// assert_eq!(Message::header_len_of(&[1; 3]), None);
+    /// ```
+    pub fn header_len_of(ser: &[u8]) -> Option<usize> {
+        header::parse(ser).ok().map(|header| header.len())
+    }
 
-        Message { id, hash, data }
+    /// Length of the serialized header, the tag included.
+    pub fn header_len(&self) -> usize {
+        self.header().len()
     }
 
-    /// Converts this message into a ControlMessage if it is a control message (id = 0).
+    /// Longest header of a data message in `format` with a tag of
+    /// `tag_len` bytes, when the sender sets no connection ID.
+    pub(crate) fn max_data_header_len(format: WireFormat, tag_len: usize) -> usize {
+        match format {
+            WireFormat::Legacy => 8 + tag_len,
+            WireFormat::V1 => 3 + MAX_VARINT_LEN + tag_len,
+        }
+    }
+
+    fn header(&self) -> Header {
+        Header {
+            format: self.format,
+            kind: self.kind,
+            id: self.id,
+            connection_id: self.connection_id,
+            fragment: self.fragment,
+            compressed: self.compressed,
+            unreliable: self.unreliable,
+            stream: self.stream,
+            tag_len: self.hash.len(),
+        }
+    }
+
+    /// Calls `f` with the header bytes the tag covers.
+    fn with_signed_header<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
+        let mut buf = [0; MAX_PREFIX_LEN];
+        let mut rest = &mut buf[..];
+        self.header().put(&mut rest, None);
+        let len = MAX_PREFIX_LEN - rest.len();
+        f(&buf[..len])
+    }
+
+    /// Converts this message into a ControlMessage if it is a control message.
     ///
     /// # Returns
     ///
@@ -135,9 +569,8 @@ impl Message {
     ///
     /// # Panics
     ///
-    /// * If the message ID is not 0 (not a control message)
-    /// * If the control message type is unknown
-    /// * If the message data is malformed
+    /// * If it is not a control message
+    /// * If the message data is malformed, see [`Message::try_get_control`]
     ///
     /// # Examples
     ///
@@ -151,26 +584,107 @@ impl Message {
     /// let control = ack.get_control();
     /// match control {
     ///     ControlMessage::Acc { id } => assert_eq!(id, 123),
+    ///     _ => unreachable!(),
     /// }
     /// ```
     pub fn get_control(self) -> ControlMessage {
-        if self.id != 0 {
-            panic!("It is not control message!")
-        }
+        self.try_get_control().unwrap_or_else(|e| panic!("{e}"))
+    }
 
-        match u8::from_be_bytes((&self.data[..1]).try_into().expect("wtf?")) {
-            1 => {
-                let msg_id = u64::from_be_bytes((&self.data[1..9]).try_into().unwrap());
-                ControlMessage::Acc { id: msg_id }
+    /// Like [`Message::get_control`], failing with
+    /// [`ConnectionError::Malformed`] on a data message, a payload too
+    /// short for its packet type or a batch that doesn't split up. Anyone
+    /// who can reach the socket can send such a packet.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use udp_connection::{ConnectionError, Message};
+    /// assert!(Message::new_ping(7).try_get_control().is_ok());
+    ///
+    /// // A ping without its token
+    /// let mut ping = Message::new_ping(7).serialize().to_vec();
+    /// ping.truncate(ping.len() - 4);
+    /// let ping = Message::try_deserialize(ping).unwrap();
+    /// assert!(matches!(ping.try_get_control(), Err(ConnectionError::Malformed(_))));
+    /// ```
+    pub fn try_get_control(self) -> Result<ControlMessage, ConnectionError> {
+        let control = match self.kind {
+            PacketType::Data => {
+                return Err(ConnectionError::Malformed(format!(
+                    "#{} is not a control message",
+                    self.id
+                )))
             }
-            type_id => panic!("Unknown type ({})!", type_id),
-        }
+            PacketType::Ack => ControlMessage::Acc {
+                id: self.payload_u64()?,
+            },
+            PacketType::Batch => {
+                let mut messages = Vec::new();
+                let mut rest = self.data.clone();
+                while !rest.is_empty() {
+                    let len = match rest.get(..2) {
+                        Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize,
+                        None => return Err(self.too_short()),
+                    };
+                    if len > rest.len() - 2 {
+                        return Err(ConnectionError::Malformed(format!(
+                            "batched message of {len} bytes with {} left",
+                            rest.len() - 2
+                        )));
+                    }
+                    let msg = Message::try_deserialize(rest.slice(2..2 + len))
+                        .map_err(ConnectionError::Malformed)?;
+                    if msg.kind == PacketType::Batch {
+                        return Err(ConnectionError::Malformed("nested batch".to_string()));
+                    }
+                    messages.push(msg);
+                    rest = rest.slice(2 + len..);
+                }
+                ControlMessage::Batch { messages }
+            }
+            PacketType::Probe => ControlMessage::Probe {
+                size: self.payload_u16()? as usize,
+                received: self.serialized_len(),
+            },
+            PacketType::ProbeAck => ControlMessage::ProbeAck {
+                size: self.payload_u16()? as usize,
+            },
+            PacketType::Ping => ControlMessage::Ping {
+                token: self.payload_u64()?,
+            },
+            PacketType::Pong => ControlMessage::Pong {
+                token: self.payload_u64()?,
+            },
+            PacketType::Close => ControlMessage::Close,
+        };
+        Ok(control)
+    }
+
+    /// The number a control message of a fixed size starts with.
+    fn payload_u64(&self) -> Result<u64, ConnectionError> {
+        let bytes = self.data.get(..8).ok_or_else(|| self.too_short())?;
+        Ok(u64::from_be_bytes(bytes.try_into().expect("8 bytes")))
+    }
+
+    fn payload_u16(&self) -> Result<u16, ConnectionError> {
+        let bytes = self.data.get(..2).ok_or_else(|| self.too_short())?;
+        Ok(u16::from_be_bytes(bytes.try_into().expect("2 bytes")))
+    }
+
+    fn too_short(&self) -> ConnectionError {
+        ConnectionError::Malformed(format!(
+            "{:?} with {} payload bytes",
+            self.kind,
+            self.data.len()
+        ))
     }
 
     /// Verifies the integrity of the message by checking its hash.
     ///
-    /// Recomputes the SHA-256 hash from the current ID and data,
-    /// then compares it with the stored hash.
+    /// Recomputes the SHA-256 hash from the current header and data,
+    /// then compares it with the stored hash. See
+    /// [`Message::check_seal_with`] for the other checks.
     ///
     /// # Returns
     ///
@@ -185,23 +699,70 @@ impl Message {
     /// assert!(message.check_hash());
     /// ```
     pub fn check_hash(&self) -> bool {
-        let mut hasher = Sha256::new();
-        hasher.update(&self.id.to_be_bytes());
-        hasher.update(&self.data);
+        self.check_seal_with(Integrity::Sha256, &NO_KEY)
+    }
+
+    /// Replaces the hash with an HMAC-SHA256 of the header and data under a
+    /// session key, binding the message to that session.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use udp_connection::Message;
+    /// let mut message = Message::new(42, &b"test data"[..]);
+    /// message.seal(&[7; 32]);
+    /// assert!(message.check_seal(&[7; 32]));
+    /// assert!(!message.check_seal(&[8; 32]));
+    /// assert!(!message.check_hash());
+    /// ```
+    pub fn seal(&mut self, key: &[u8; 32]) {
+        self.seal_with(Integrity::Mac, key);
+    }
+
+    /// Verifies a hash made by [`Message::seal`] with the same key.
+    pub fn check_seal(&self, key: &[u8; 32]) -> bool {
+        self.check_seal_with(Integrity::Mac, key)
+    }
+
+    /// Replaces the tag with one made by `integrity`, which also sets the
+    /// tag length carried in the header. `key` is ignored by checks that
+    /// take none.
+    ///
+    /// The tag covers the header as [`Message::format`] writes it, so set
+    /// the format and flags first.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use udp_connection::{Integrity, Message};
+    /// let mut message = Message::new(42, &b"test data"[..]);
+    /// message.seal_with(Integrity::Xxh3, &[0; 32]);
+    /// assert_eq!(message.serialize().len(), 3 + 1 + 8 + 9);
+    ///
+    /// let received = Message::deserialize(message.serialize());
+    /// assert!(received.check_seal_with(Integrity::Xxh3, &[0; 32]));
+    /// assert!(!received.check_seal_with(Integrity::Crc32c, &[0; 32]));
+    /// ```
+    pub fn seal_with(&mut self, integrity: Integrity, key: &[u8; 32]) {
+        // The header carries the length of the tag it is signed with
+        self.hash = Tag::new(&[0; MAX_TAG_LEN][..integrity.tag_len()]);
+        self.hash = self.with_signed_header(|header| integrity.tag(key, header, &self.data));
+    }
 
-        self.hash[..] == hasher.finalize()[..]
+    /// Verifies a tag made by [`Message::seal_with`] with the same check
+    /// and key.
+    pub fn check_seal_with(&self, integrity: Integrity, key: &[u8; 32]) -> bool {
+        self.with_signed_header(|header| integrity.verify(key, header, &self.data, &self.hash))
     }
 
     /// Serializes the message into a byte buffer.
     ///
-    /// The serialized format is:
-    /// - Bytes 0-8: Message ID (big-endian u64)
-    /// - Bytes 8-40: SHA-256 hash (32 bytes)
-    /// - Bytes 40+: Message data
+    /// The serialized format is the header of [`Message::format`], see
+    /// [`Message::deserialize`], followed by the message data.
     ///
     /// # Returns
     ///
-    /// A boxed slice containing all message data concatenated together.
+    /// A buffer containing all message data concatenated together.
     ///
     /// # Examples
     ///
@@ -210,17 +771,38 @@ impl Message {
     /// let data = b"test".to_vec().into_boxed_slice();
     /// let message = Message::new(123, data);
     /// let serialized = message.serialize();
-    /// assert_eq!(serialized.len(), 8 + 32 + 4); // id + hash + data
-    /// ```
-    pub fn serialize(&self) -> Box<[u8]> {
-        self.id
-            .to_be_bytes()
-            .iter()
-            .chain(self.hash.iter())
-            .chain(self.data.iter())
-            .copied()
-            .collect::<Vec<u8>>()
-            .into_boxed_slice()
+    /// assert_eq!(serialized.len(), 3 + 1 + 32 + 4); // prefix + id + hash + data
+    /// ```
+    pub fn serialize(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(self.serialized_len());
+        self.serialize_into(&mut buf);
+        buf.freeze()
+    }
+
+    /// Appends the serialized message to `buf`.
+    ///
+    /// Reusing the same buffer for every datagram avoids allocating on each
+    /// (re)send.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use bytes::BytesMut;
+    /// # use udp_connection::Message;
+    /// let message = Message::new(123, &b"test"[..]);
+    /// let mut buf = BytesMut::with_capacity(1024);
+    /// message.serialize_into(&mut buf);
+    /// assert_eq!(buf, message.serialize());
+    /// ```
+    pub fn serialize_into(&self, buf: &mut BytesMut) {
+        buf.reserve(self.serialized_len());
+        self.header().put(buf, Some(&self.hash));
+        buf.put_slice(&self.data);
+    }
+
+    /// Number of bytes [`Message::serialize`] produces.
+    pub fn serialized_len(&self) -> usize {
+        self.header_len() + self.data.len()
     }
 }
 
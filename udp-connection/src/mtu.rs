@@ -0,0 +1,160 @@
+use std::time::{Duration, Instant};
+
+/// How long to wait for a probe to be acknowledged.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+/// Unacknowledged probes of one size before that size is considered too big.
+const MAX_PROBES: u32 = 3;
+/// Stop searching once the bounds are this close.
+const SEARCH_GRANULARITY: usize = 16;
+/// Pause before searching for a larger size again after a search ends.
+const RAISE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Datagram packetization layer path MTU discovery (RFC 8899 style).
+///
+/// Padded probe datagrams of growing size are sent to the peer; sizes the
+/// peer acknowledges become the new path MTU. The path MTU never drops
+/// below the configured base size.
+#[derive(Debug)]
+pub(crate) struct MtuDiscovery {
+    /// Largest datagram size confirmed to reach the peer.
+    plpmtu: usize,
+    /// Smallest size known (or assumed) not to reach the peer.
+    ceiling: usize,
+    max: usize,
+    enabled: bool,
+    probe: Option<Probe>,
+    next_search: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Probe {
+    size: usize,
+    sent_at: Instant,
+    attempts: u32,
+}
+
+impl MtuDiscovery {
+    pub(crate) fn new(base: usize, max: usize, enabled: bool) -> MtuDiscovery {
+        let max = max.max(base);
+        MtuDiscovery {
+            plpmtu: if enabled { base } else { max },
+            ceiling: max + 1,
+            max,
+            enabled,
+            probe: None,
+            next_search: None,
+        }
+    }
+
+    /// Current path MTU (largest datagram size to send).
+    pub(crate) fn mtu(&self) -> usize {
+        self.plpmtu
+    }
+
+    /// Returns the size of the probe to send now, if any.
+    pub(crate) fn poll(&mut self, now: Instant) -> Option<usize> {
+        if !self.enabled {
+            return None;
+        }
+
+        if let Some(next_search) = self.next_search {
+            if now < next_search {
+                return None;
+            }
+            self.next_search = None;
+            self.ceiling = self.max + 1;
+        }
+
+        match &mut self.probe {
+            Some(probe) if now.duration_since(probe.sent_at) < PROBE_TIMEOUT => None,
+            Some(probe) if probe.attempts < MAX_PROBES => {
+                probe.attempts += 1;
+                probe.sent_at = now;
+                Some(probe.size)
+            }
+            probe => {
+                if let Some(lost) = probe.take() {
+                    self.ceiling = lost.size;
+                }
+
+                if self.ceiling - self.plpmtu <= SEARCH_GRANULARITY {
+                    self.next_search = Some(now + RAISE_INTERVAL);
+                    return None;
+                }
+
+                // Optimistically try the largest size first, then bisect
+                let size = if self.ceiling > self.max {
+                    self.max
+                } else {
+                    (self.plpmtu + self.ceiling) / 2
+                };
+                self.probe = Some(Probe {
+                    size,
+                    sent_at: now,
+                    attempts: 1,
+                });
+                Some(size)
+            }
+        }
+    }
+
+    /// When [`MtuDiscovery::poll`] has something to do next, `None` if
+    /// probing is disabled.
+    pub(crate) fn next_poll(&self, now: Instant) -> Option<Instant> {
+        if !self.enabled {
+            return None;
+        }
+        Some(match (&self.next_search, &self.probe) {
+            (Some(next_search), _) => *next_search,
+            (None, Some(probe)) => probe.sent_at + PROBE_TIMEOUT,
+            (None, None) => now,
+        })
+    }
+
+    /// Handles the peer acknowledging a probe of `size` bytes.
+    pub(crate) fn on_probe_ack(&mut self, size: usize) {
+        if size > self.plpmtu && size <= self.max {
+            self.plpmtu = size;
+        }
+
+        if self.probe.as_ref().is_some_and(|probe| probe.size <= size) {
+            self.probe = None;
+        }
+    }
+}
+
+/// Sets the don't-fragment bit on outgoing datagrams so oversized probes are
+/// dropped instead of being fragmented by IP.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_dont_fragment(socket: &std::net::UdpSocket) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let (level, name, value) = if socket.local_addr()?.is_ipv4() {
+        (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_PROBE)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_PROBE)
+    };
+
+    // SAFETY: the fd is valid for the lifetime of `socket` and `value` is a
+    // c_int as the option expects.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn set_dont_fragment(_socket: &std::net::UdpSocket) -> std::io::Result<()> {
+    Ok(())
+}
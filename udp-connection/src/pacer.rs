@@ -0,0 +1,76 @@
+use std::time::{Duration, Instant};
+
+/// Token bucket spacing outgoing datagrams to a bytes per second cap.
+///
+/// Tokens are bytes. A datagram may be sent while the bucket is not empty,
+/// which can leave it in debt by up to one datagram; the debt is paid back
+/// before anything else goes out. A rate of 0 pauses sending.
+#[derive(Debug)]
+pub(crate) struct Pacer {
+    rate: Option<u64>,
+    burst: usize,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Pacer {
+    pub(crate) fn new(rate: Option<u64>, burst: usize, now: Instant) -> Pacer {
+        let burst = burst.max(1);
+        Pacer {
+            rate,
+            burst,
+            tokens: burst as f64,
+            last_refill: now,
+        }
+    }
+
+    /// Changes the cap, keeping the tokens collected so far.
+    pub(crate) fn set_rate(&mut self, rate: Option<u64>, burst: usize, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+        self.burst = burst.max(1);
+        self.tokens = self.tokens.min(self.burst as f64);
+    }
+
+    /// Whether the next datagram may be sent now.
+    pub(crate) fn can_send(&mut self, now: Instant) -> bool {
+        match self.rate {
+            None => return true,
+            Some(0) => return false,
+            Some(_) => {}
+        }
+
+        self.refill(now);
+        self.tokens > 0.0
+    }
+
+    /// When the next datagram may be sent, in the past if right away.
+    /// `None` while paused.
+    pub(crate) fn ready_at(&self) -> Option<Instant> {
+        match self.rate {
+            Some(0) => None,
+            Some(rate) if self.tokens <= 0.0 => {
+                // Rounded up, `can_send` wants the bucket above 0
+                let wait = Duration::from_secs_f64(-self.tokens / rate as f64);
+                Some(self.last_refill + wait + Duration::from_micros(1))
+            }
+            _ => Some(self.last_refill),
+        }
+    }
+
+    /// Takes the tokens for a datagram of `bytes` that was just sent.
+    pub(crate) fn on_sent(&mut self, bytes: usize) {
+        if self.rate.is_some() {
+            self.tokens -= bytes as f64;
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.saturating_duration_since(self.last_refill);
+            self.tokens =
+                (self.tokens + elapsed.as_secs_f64() * rate as f64).min(self.burst as f64);
+        }
+        self.last_refill = now;
+    }
+}
@@ -0,0 +1,1296 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Debug,
+    io::{self, Read, Seek, SeekFrom},
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+
+use crate::{
+    batch_io,
+    compression::{Compression, MAX_DECOMPRESSED_SIZE},
+    config::{Config, QueuePolicy},
+    control_message::ControlMessage,
+    delivery::{DeliveryStatus, ExpiredIds, MessageId, QueueDepth, SendError, Ttl},
+    error::ConnectionError,
+    event::Event,
+    header::{PacketType, WireFormat},
+    integrity::Integrity,
+    message::{Message, MAX_DATAGRAM_SIZE},
+    mtu::MtuDiscovery,
+    pacer::Pacer,
+    replay::{ReplayWindow, Verdict},
+    session::Session,
+    state::ConnectionState,
+    stream::{self, Frame, RecvStream, SendStream, StreamId, FRAME_HEADER_LEN},
+};
+
+/// Unanswered pings older than this are forgotten.
+const PING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Runs of expired message IDs kept for [`Protocol::delivery_status`].
+const MAX_EXPIRED_RUNS: usize = 1024;
+
+/// The protocol of a connection without the socket: datagrams and the time
+/// go in, datagrams, timers and events come out.
+///
+/// [`SocketWorker`](crate::SocketWorker) drives one over a UDP socket, the
+/// [`sim`](crate::sim) module over a simulated link in virtual time. Nothing
+/// here reads the clock, every call that depends on time is told `now`.
+///
+/// A driver loops over:
+///
+/// - [`Protocol::handle_datagram`] for every datagram from the peer,
+/// - [`Protocol::handle_timeout`] once [`Protocol::poll_timeout`] is due,
+/// - [`Protocol::poll_transmit`], sending what it returns to the peer,
+/// - [`Protocol::take_events`].
+///
+/// # Examples
+///
+/// ```
+/// # use std::time::Instant;
+/// # use udp_connection::Protocol;
+/// let now = Instant::now();
+/// let mut protocol = Protocol::new("peer".to_string(), |_| {}, now);
+/// protocol.send_message(&b"hello"[..]).unwrap();
+///
+/// let transmit = protocol.poll_transmit(now);
+/// assert_eq!(transmit.len(), 1);
+/// for datagram in transmit.iter() {
+///     // socket.send_to(datagram, peer)
+///     # let _ = datagram;
+/// }
+/// assert!(protocol.poll_timeout().is_some());
+/// ```
+pub struct Protocol {
+    /// Who the peer is, for trace output
+    name: String,
+    outgoing: VecDeque<Outgoing>,
+    /// What of `outgoing` counts against the queue limits
+    queued: QueueDepth,
+    replay: ReplayWindow,
+    expired: ExpiredIds,
+    events: Vec<Result<Event, ConnectionError>>,
+    notify: fn(&[u8]),
+    message_id: u64,
+    /// Lowest ID [`Protocol::delivery_status`] knows about: the first
+    /// message we sent, unless older expired IDs were forgotten since
+    oldest_known_id: u64,
+    /// Key agreed on in the handshake, see [`Message::seal`]
+    session_key: Option<[u8; 32]>,
+    integrity: Integrity,
+    /// Header layout of the datagrams we send
+    format: WireFormat,
+    compression: Compression,
+    config: Config,
+    mtu: MtuDiscovery,
+    pacer: Pacer,
+    /// Datagrams that bypass the queue and the pacer, like probes
+    direct: Vec<Bytes>,
+    send_buf: BytesMut,
+    /// End offsets of the datagrams packed into `send_buf`
+    send_ends: Vec<usize>,
+    /// Unanswered pings by token, with the time they were queued
+    pings: HashMap<u64, Instant>,
+    next_ping: u64,
+    send_streams: Vec<SendStream>,
+    recv_streams: BTreeMap<u64, RecvStream>,
+    next_stream: u64,
+    state: ConnectionState,
+    /// When the last authentic datagram arrived from the peer
+    last_received: Instant,
+    last_keepalive: Instant,
+    /// The latest time we were told
+    now: Instant,
+}
+
+/// Datagrams to send, returned by [`Protocol::poll_transmit`].
+pub struct Transmit<'a> {
+    pub(crate) buf: &'a [u8],
+    /// End offsets of the datagrams in `buf`
+    pub(crate) ends: &'a [usize],
+}
+
+impl<'a> Transmit<'a> {
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+
+    /// The datagrams, in the order they are to be sent.
+    pub fn iter(&self) -> impl Iterator<Item = &'a [u8]> + '_ {
+        let buf = self.buf;
+        self.ends.iter().scan(0, move |start, &end| {
+            let datagram = &buf[*start..end];
+            *start = end;
+            Some(datagram)
+        })
+    }
+}
+
+impl Protocol {
+    /// A connection with the default [`Config`], established and without a
+    /// session, see [`Protocol::set_config`].
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The peer, as trace output calls it
+    /// * `notify` - Called with every message from the peer
+    /// * `now` - The current time
+    pub fn new(name: String, notify: fn(&[u8]), now: Instant) -> Protocol {
+        let config = Config::default();
+
+        Protocol {
+            name,
+            outgoing: VecDeque::with_capacity(1000),
+            queued: QueueDepth::default(),
+            replay: ReplayWindow::new(1, config.replay_window),
+            expired: ExpiredIds::new(MAX_EXPIRED_RUNS),
+            events: Vec::new(),
+            notify,
+            message_id: 1u64,
+            oldest_known_id: 1u64,
+            session_key: None,
+            integrity: Integrity::Sha256,
+            format: WireFormat::V1,
+            compression: Compression::None,
+            mtu: new_mtu_discovery(&config),
+            pacer: Pacer::new(config.rate_limit, config.burst, now),
+            config,
+            direct: Vec::new(),
+            send_buf: BytesMut::with_capacity(MAX_DATAGRAM_SIZE),
+            send_ends: Vec::with_capacity(batch_io::BATCH_SIZE),
+            pings: HashMap::new(),
+            next_ping: 0,
+            send_streams: Vec::new(),
+            recv_streams: BTreeMap::new(),
+            next_stream: 1,
+            state: ConnectionState::Established,
+            last_received: now,
+            last_keepalive: now,
+            now,
+        }
+    }
+
+    /// Replaces the configuration, restarting path MTU discovery and rate
+    /// pacing.
+    pub fn set_config(&mut self, config: Config, now: Instant) {
+        self.now = now;
+        self.mtu = new_mtu_discovery(&config);
+        self.pacer = Pacer::new(config.rate_limit, config.burst, now);
+        self.replay = ReplayWindow::new(self.replay.base(), config.replay_window);
+        self.config = config;
+    }
+
+    /// Changes the outgoing bandwidth cap, see
+    /// [`SocketWorker::set_rate_limit`](crate::SocketWorker::set_rate_limit).
+    pub fn set_rate_limit(&mut self, rate: Option<u64>, burst: usize, now: Instant) {
+        self.now = now;
+        self.pacer.set_rate(rate, burst, now);
+        self.config.rate_limit = rate;
+        self.config.burst = burst;
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Largest datagram currently known to reach the peer.
+    pub fn mtu(&self) -> usize {
+        self.mtu.mtu()
+    }
+
+    /// Largest payload `send_message` accepts with the current path MTU.
+    pub fn max_payload(&self) -> usize {
+        self.mtu() - Message::max_data_header_len(self.format, self.integrity.tag_len())
+    }
+
+    /// Integrity check agreed on with the peer during the handshake,
+    /// SHA-256 without one.
+    pub fn integrity(&self) -> Integrity {
+        self.integrity
+    }
+
+    /// Header format agreed on with the peer during the handshake,
+    /// version 1 without one.
+    pub fn wire_format(&self) -> WireFormat {
+        self.format
+    }
+
+    /// Compression agreed on with the peer during the handshake.
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub(crate) fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Switches to the IDs, key, integrity check and header format agreed
+    /// on in the handshake.
+    pub(crate) fn set_session(&mut self, session: Session) {
+        self.message_id = session.local_isn;
+        self.oldest_known_id = session.local_isn;
+        self.replay = ReplayWindow::new(session.peer_isn, self.config.replay_window);
+        self.session_key = Some(session.key);
+        self.integrity = session.integrity;
+        self.format = session.format;
+    }
+
+    /// Waits for the peer to show up before counting the connection as
+    /// established.
+    pub(crate) fn await_peer(&mut self) {
+        self.state = ConnectionState::Handshaking;
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Stops accepting new messages and closes the connection once the peer
+    /// acknowledged everything queued so far, see
+    /// [`SocketWorker::close`](crate::SocketWorker::close).
+    pub fn close(&mut self) {
+        self.set_state(ConnectionState::Closing);
+    }
+
+    /// Pings a timed out peer on the old session. The connection is
+    /// established again once the peer answers; messages queued before the
+    /// timeout stay expired.
+    pub(crate) fn resume(&mut self, now: Instant) {
+        self.now = now;
+        if self.state != ConnectionState::TimedOut {
+            return;
+        }
+        self.set_state(ConnectionState::Handshaking);
+        self.last_received = now;
+        self.last_keepalive = now;
+
+        let token = self.next_ping;
+        self.next_ping += 1;
+        let msg = self.seal(Message::new_ping(token));
+        self.outgoing.push_front(Outgoing::new(msg));
+    }
+
+    /// Moves to `next` if that is a valid transition, reporting it as an
+    /// event. Messages still queued in a final state are expired.
+    fn set_state(&mut self, next: ConnectionState) {
+        if !self.state.can_become(next) {
+            return;
+        }
+        if self.config.trace {
+            eprintln!("Connection to {} {next}", self.name);
+        }
+        self.state = next;
+        self.events.push(Ok(Event::StateChanged(next)));
+
+        if next.is_final() {
+            for pending in std::mem::take(&mut self.outgoing) {
+                let msg = pending.msg;
+                if msg.is_control() {
+                    continue;
+                }
+                self.record_expired(msg.id);
+                // Stream frames and placeholders stand for no message of the user
+                if !msg.stream {
+                    self.events.push(Ok(Event::Expired(MessageId(msg.id))));
+                }
+            }
+            self.queued = QueueDepth::default();
+            self.send_streams.clear();
+        }
+    }
+
+    /// An authentic datagram arrived from the peer.
+    fn on_peer_alive(&mut self, now: Instant) {
+        self.last_received = now;
+        if self.state == ConnectionState::Handshaking {
+            self.set_state(ConnectionState::Established);
+        }
+    }
+
+    /// Tags an outgoing message with the agreed integrity check.
+    fn seal(&self, mut msg: Message) -> Message {
+        msg.format = self.format;
+        msg.seal_with(self.integrity, &self.session_key.unwrap_or_default());
+        msg
+    }
+
+    fn check_hash(&self, msg: &Message) -> bool {
+        msg.check_seal_with(self.integrity, &self.session_key.unwrap_or_default())
+    }
+
+    /// Everything reported since the last call.
+    pub fn take_events(&mut self) -> Vec<Result<Event, ConnectionError>> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Handles a datagram from the peer that arrived at `now`.
+    ///
+    /// Datagrams that fail to parse or fail the integrity check are
+    /// dropped, as is everything once the connection is over.
+    pub fn handle_datagram(&mut self, now: Instant, datagram: Bytes) {
+        self.now = now;
+        let number_of_bytes = datagram.len();
+        let Ok(msg) = Message::try_deserialize(datagram) else {
+            return;
+        };
+        if self.config.trace {
+            eprintln!(
+                "Received {} bytes from {}: C({}) '{}'",
+                number_of_bytes,
+                self.name,
+                self.check_hash(&msg),
+                msg
+            );
+        }
+
+        let result = self.handle_message(now, msg);
+        collect(result, &mut self.events);
+    }
+
+    /// Pings a silent peer, gives up on it after the idle timeout and
+    /// probes the path MTU.
+    ///
+    /// Meant to be called once [`Protocol::poll_timeout`] passed, calling it
+    /// early does no harm.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        self.now = now;
+        self.keep_alive(now);
+        if !self.state.is_final() {
+            self.probe_mtu(now);
+            self.expire(now);
+        }
+    }
+
+    /// When [`Protocol::handle_timeout`] or [`Protocol::poll_transmit`]
+    /// have something to do next, `None` once the connection is over.
+    ///
+    /// The instant is in the past if that is right away.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        if self.state.is_final() {
+            return None;
+        }
+
+        let interval = self.config.keepalive_interval;
+        let mut next = [
+            self.last_received + self.config.idle_timeout,
+            self.last_received.max(self.last_keepalive) + interval,
+        ]
+        .into_iter()
+        .chain(self.mtu.next_poll(self.now))
+        .min()
+        .expect("Never empty");
+
+        if !self.direct.is_empty() || self.closing_done() {
+            next = next.min(self.now);
+        }
+
+        let rto = self.config.retransmit_timeout;
+        let earliest_retransmit = self
+            .outgoing
+            .iter()
+            .map(|pending| match pending.last_sent {
+                Some(sent) => sent + rto,
+                None => self.now,
+            })
+            .min();
+        if let (Some(retransmit), Some(ready)) = (earliest_retransmit, self.pacer.ready_at()) {
+            next = next.min(retransmit.max(ready));
+        }
+        if let Some(expiry) = self.outgoing.iter().filter_map(|p| p.expires_at(rto)).min() {
+            next = next.min(expiry);
+        }
+
+        Some(next)
+    }
+
+    /// Packs the datagrams due at `now`.
+    ///
+    /// Up to `send_window` datagrams, as far as the rate cap allows, plus
+    /// probes and the goodbye of a closed connection, which bypass both.
+    /// Every queued message goes out at most once per call; sent messages
+    /// go out again after [`Config::retransmit_timeout`] until the peer
+    /// acknowledges them.
+    pub fn poll_transmit(&mut self, now: Instant) -> Transmit<'_> {
+        self.now = now;
+        self.send_buf.clear();
+        self.send_ends.clear();
+
+        for datagram in std::mem::take(&mut self.direct) {
+            self.send_buf.extend_from_slice(&datagram);
+            self.send_ends.push(self.send_buf.len());
+        }
+
+        if !self.state.is_final() {
+            self.fill_streams();
+            let mut unsent = self.outgoing.len();
+            for _ in 0..self.config.send_window {
+                if unsent == 0 || !self.pacer.can_send(now) {
+                    break;
+                }
+
+                let start = self.send_buf.len();
+                if !self.next_datagram(now, &mut unsent) {
+                    break;
+                }
+
+                self.pacer.on_sent(self.send_buf.len() - start);
+                self.send_ends.push(self.send_buf.len());
+            }
+            self.finish_closing();
+        }
+
+        Transmit {
+            buf: &self.send_buf,
+            ends: &self.send_ends,
+        }
+    }
+
+    /// Pings a silent peer and gives up on it after the idle timeout.
+    fn keep_alive(&mut self, now: Instant) {
+        let silence = now.saturating_duration_since(self.last_received);
+        if silence >= self.config.idle_timeout {
+            self.set_state(ConnectionState::TimedOut);
+            return;
+        }
+
+        let interval = self.config.keepalive_interval;
+        if silence >= interval && now.saturating_duration_since(self.last_keepalive) >= interval {
+            self.last_keepalive = now;
+            // Not in `pings`, so the answer is no `Event::Pong`
+            let token = self.next_ping;
+            self.next_ping += 1;
+            let msg = self.seal(Message::new_ping(token));
+            self.outgoing.push_front(Outgoing::new(msg));
+        }
+    }
+
+    /// Whether a closing connection has nothing left to send.
+    fn closing_done(&self) -> bool {
+        self.state == ConnectionState::Closing
+            && self.outgoing.is_empty()
+            && self.send_streams.is_empty()
+    }
+
+    /// Says goodbye once a closing connection has nothing left to send.
+    fn finish_closing(&mut self) {
+        if !self.closing_done() {
+            return;
+        }
+
+        let close = self.seal(Message::new_close()).serialize();
+        self.send_buf.extend_from_slice(&close);
+        self.send_ends.push(self.send_buf.len());
+        self.set_state(ConnectionState::Closed);
+    }
+
+    /// Queues a message for reliable delivery.
+    ///
+    /// Returns the ID assigned to the message, see
+    /// [`Protocol::delivery_status`] and [`Event::Delivered`]. Fails if the
+    /// connection is closing or over, if the (compressed) payload exceeds
+    /// [`Protocol::max_payload`], the payload 64 KiB, or if the queue is
+    /// full;
+    /// [`QueuePolicy::Block`] fails like [`QueuePolicy::Reject`] here,
+    /// waiting is up to the driver.
+    ///
+    /// The message expires as [`Config::ttl`] says, counting from the latest
+    /// time the protocol was told.
+    pub fn send_message(&mut self, msg: impl Into<Bytes>) -> Result<MessageId, SendError> {
+        self.send_message_with_ttl(self.now, msg, self.config.ttl)
+    }
+
+    /// Like [`Protocol::send_message`], but expires as `ttl` says, counting
+    /// from `now`.
+    pub fn send_message_with_ttl(
+        &mut self,
+        now: Instant,
+        msg: impl Into<Bytes>,
+        ttl: Ttl,
+    ) -> Result<MessageId, SendError> {
+        self.now = now;
+        self.check_can_send()?;
+        let msg = msg.into();
+        // The peer refuses to decompress anything larger
+        if msg.len() > MAX_DECOMPRESSED_SIZE {
+            return Err(SendError::TooLarge {
+                size: msg.len(),
+                max: MAX_DECOMPRESSED_SIZE,
+            });
+        }
+        let (data, compressed) = match self.compression.compress(&msg) {
+            Some(data) => (Bytes::from(data), true),
+            None => (msg, false),
+        };
+        if data.len() > self.max_payload() {
+            return Err(SendError::TooLarge {
+                size: data.len(),
+                max: self.max_payload(),
+            });
+        }
+        self.make_room(data.len())?;
+
+        let msg = Message::unsealed(self.message_id, data, compressed, false);
+        self.message_id += 1;
+        let id = MessageId(msg.id);
+        let msg = self.seal(msg);
+        self.enqueue(msg);
+        self.outgoing
+            .back_mut()
+            .expect("Just queued")
+            .set_ttl(ttl, now);
+
+        Ok(id)
+    }
+
+    /// Messages and bytes waiting to be acknowledged by the peer.
+    pub fn queue_depth(&self) -> QueueDepth {
+        self.queued
+    }
+
+    /// Stops sending a message returned by `send_message` and reports it
+    /// expired, as if its [`Ttl`] ran out. The peer may have it already.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the message is acknowledged or given up on already.
+    pub fn expire_message(&mut self, id: MessageId) -> bool {
+        let index = self.outgoing.iter().position(|pending| {
+            pending.msg.id == id.0 && !pending.msg.is_control() && !pending.msg.stream
+        });
+        match index {
+            Some(index) if !self.expired.contains(id.0) => {
+                self.give_up(index);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn check_can_send(&self) -> Result<(), SendError> {
+        if self.state.can_send() {
+            Ok(())
+        } else {
+            Err(SendError::NotConnected(self.state))
+        }
+    }
+
+    /// Whether a message with `len` payload bytes fits the queue limits.
+    fn has_room(&self, len: usize) -> bool {
+        self.config
+            .max_queued_messages
+            .is_none_or(|max| self.queued.messages < max)
+            && self
+                .config
+                .max_queued_bytes
+                .is_none_or(|max| self.queued.bytes + len <= max)
+    }
+
+    /// Makes room for a message with `len` payload bytes as
+    /// [`Config::queue_policy`] says.
+    fn make_room(&mut self, len: usize) -> Result<(), SendError> {
+        if self.has_room(len) {
+            return Ok(());
+        }
+        if self.config.max_queued_bytes.is_some_and(|max| len > max) {
+            return Err(SendError::QueueFull);
+        }
+
+        match self.config.queue_policy {
+            QueuePolicy::Reject | QueuePolicy::Block => Err(SendError::QueueFull),
+            QueuePolicy::DropOldest => {
+                while !self.has_room(len) {
+                    if !self.drop_oldest() {
+                        return Err(SendError::QueueFull);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Replaces the oldest queued message with a placeholder, see
+    /// [`Message::new_placeholder`]. Stream frames are never dropped.
+    ///
+    /// # Returns
+    ///
+    /// `false` if there was nothing to drop.
+    fn drop_oldest(&mut self) -> bool {
+        let oldest = self
+            .outgoing
+            .iter()
+            .enumerate()
+            .filter(|(_, pending)| !pending.msg.is_control() && !pending.msg.stream)
+            .min_by_key(|(_, pending)| pending.msg.id)
+            .map(|(index, _)| index);
+        let Some(index) = oldest else {
+            return false;
+        };
+
+        self.give_up(index);
+        true
+    }
+
+    /// Gives up on the messages whose [`Ttl`] ran out at `now`.
+    fn expire(&mut self, now: Instant) {
+        let rto = self.config.retransmit_timeout;
+        let mut index = 0;
+        while index < self.outgoing.len() {
+            if self.outgoing[index]
+                .expires_at(rto)
+                .is_some_and(|expiry| now >= expiry)
+            {
+                if let Some(merged) = self.give_up(index) {
+                    if merged < index {
+                        index -= 1;
+                    }
+                }
+            }
+            index += 1;
+        }
+    }
+
+    /// Replaces a queued message with a placeholder, see
+    /// [`Message::new_skip`], and reports it expired. The placeholder is
+    /// retransmitted until acknowledged, so the peer doesn't wait for the
+    /// message forever.
+    ///
+    /// A placeholder for the message right before is merged into the new
+    /// one, so a run of dropped messages takes a single slot in the queue.
+    ///
+    /// # Returns
+    ///
+    /// The index of the placeholder merged and removed, if any.
+    fn give_up(&mut self, index: usize) -> Option<usize> {
+        let id = self.outgoing[index].msg.id;
+        let is_run_before =
+            |pending: &Outgoing| pending.msg.is_placeholder() && pending.msg.id + 1 == id;
+        // Dropped in order, the run is usually right in front
+        let run = match index.checked_sub(1) {
+            Some(before) if is_run_before(&self.outgoing[before]) => Some(before),
+            _ if self.expired.contains(id - 1) => self.outgoing.iter().position(is_run_before),
+            _ => None,
+        };
+        let first = run.map_or(id, |run| *self.outgoing[run].msg.skipped().start());
+
+        let placeholder = self.seal(Message::new_skip(first, id));
+        let pending = &mut self.outgoing[index];
+        pending.set_ttl(Ttl::default(), self.now);
+        let dropped = std::mem::replace(&mut pending.msg, placeholder);
+        self.dequeued(&dropped);
+        if let Some(run) = run {
+            self.outgoing.remove(run);
+        }
+        self.record_expired(id);
+        self.events.push(Ok(Event::Expired(MessageId(id))));
+        run
+    }
+
+    fn record_expired(&mut self, id: u64) {
+        if let Some(forgotten) = self.expired.insert(id) {
+            self.oldest_known_id = self.oldest_known_id.max(forgotten);
+        }
+    }
+
+    /// Appends a message to the outgoing queue.
+    fn enqueue(&mut self, msg: Message) {
+        if counts_against_limits(&msg) {
+            self.queued.messages += 1;
+            self.queued.bytes += msg.data.len();
+        }
+        self.outgoing.push_back(Outgoing::new(msg));
+    }
+
+    /// Books a message out of the outgoing queue.
+    fn dequeued(&mut self, msg: &Message) {
+        if counts_against_limits(msg) {
+            self.queued.messages -= 1;
+            self.queued.bytes -= msg.data.len();
+        }
+    }
+
+    /// Reports how far a message returned by `send_message` got.
+    pub fn delivery_status(&self, id: MessageId) -> DeliveryStatus {
+        // A dropped message may still be queued as a placeholder
+        if self.expired.contains(id.0) {
+            return DeliveryStatus::Expired;
+        }
+
+        if let Some(pending) = self
+            .outgoing
+            .iter()
+            .find(|i| i.msg.id == id.0 && !i.msg.is_placeholder())
+        {
+            return if pending.sends == 0 {
+                DeliveryStatus::Queued
+            } else {
+                DeliveryStatus::InFlight
+            };
+        }
+
+        if (self.oldest_known_id..self.message_id).contains(&id.0) {
+            // Only acknowledged messages leave the queue otherwise
+            DeliveryStatus::Acked
+        } else {
+            DeliveryStatus::Unknown
+        }
+    }
+
+    /// Starts sending everything `reader` yields as a byte stream, see
+    /// [`SocketWorker::send_stream`](crate::SocketWorker::send_stream).
+    ///
+    /// Fails if the connection is closing or over.
+    pub fn send_stream(
+        &mut self,
+        reader: impl Read + Send + 'static,
+    ) -> Result<StreamId, SendError> {
+        self.check_can_send()?;
+        let id = self.next_stream;
+        self.next_stream += 1;
+        self.send_streams.push(SendStream::new(
+            id,
+            Box::new(reader),
+            0,
+            Default::default(),
+            self.config.stream_window,
+        ));
+
+        Ok(StreamId(id))
+    }
+
+    /// Continues a stream from `offset`, see
+    /// [`SocketWorker::resume_send_stream`](crate::SocketWorker::resume_send_stream).
+    pub fn resume_send_stream(
+        &mut self,
+        id: StreamId,
+        mut reader: impl Read + Seek + Send + 'static,
+        offset: u64,
+    ) -> io::Result<()> {
+        self.check_can_send()
+            .map_err(|e| io::Error::new(io::ErrorKind::NotConnected, e))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let (hasher, len) = stream::hash_prefix((&mut reader).take(offset))?;
+        if len < offset {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Stream is only {len} bytes long"),
+            ));
+        }
+
+        self.next_stream = self.next_stream.max(id.0 + 1);
+        self.send_streams.retain(|s| s.id != id.0);
+        self.send_streams.push(SendStream::new(
+            id.0,
+            Box::new(reader),
+            offset,
+            hasher,
+            self.config.stream_window,
+        ));
+
+        Ok(())
+    }
+
+    /// Claims the oldest incoming stream nobody reads yet.
+    pub(crate) fn take_recv_stream(&mut self) -> Option<StreamId> {
+        let (&id, stream) = self.recv_streams.iter_mut().find(|(_, s)| !s.taken)?;
+        stream.taken = true;
+        Some(StreamId(id))
+    }
+
+    /// Continues receiving a stream of which `prefix` arrived before, see
+    /// [`SocketWorker::resume_recv_stream`](crate::SocketWorker::resume_recv_stream).
+    pub(crate) fn resume_recv_stream(&mut self, id: StreamId, prefix: impl Read) -> io::Result<()> {
+        let (hasher, offset) = stream::hash_prefix(prefix)?;
+        let mut stream = RecvStream::new(offset, hasher, self.config.stream_window);
+        if let Some(early) = self.recv_streams.remove(&id.0) {
+            stream.adopt(early);
+        }
+        stream.taken = true;
+        self.recv_streams.insert(id.0, stream);
+
+        Ok(())
+    }
+
+    pub(crate) fn stream_consumed(&self, id: StreamId) -> u64 {
+        self.recv_streams.get(&id.0).map_or(0, |s| s.consumed)
+    }
+
+    /// Bytes of an incoming stream received so far, in order.
+    pub(crate) fn stream_offset(&self, id: StreamId) -> Option<u64> {
+        self.recv_streams.get(&id.0).map(RecvStream::offset)
+    }
+
+    /// Copies what arrived of an incoming stream into `buf`, handing the
+    /// sender new credit as the reader frees the window.
+    ///
+    /// Returns `None` if the reader has to wait for more data.
+    pub(crate) fn try_read_stream(
+        &mut self,
+        id: StreamId,
+        buf: &mut [u8],
+    ) -> Option<io::Result<usize>> {
+        let Some(stream) = self.recv_streams.get_mut(&id.0) else {
+            return Some(Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No {id}"),
+            )));
+        };
+        let result = stream.read(buf)?;
+        if let Some(limit) = stream.credit(self.config.stream_window) {
+            self.queue_frame(&Frame::Credit { stream: id.0, limit });
+        }
+        Some(result)
+    }
+
+    /// Frames of an outgoing stream the peer didn't acknowledge yet, `None`
+    /// once the stream was sent completely, aborted or cancelled.
+    pub(crate) fn send_stream_unacked(&self, id: StreamId) -> Option<usize> {
+        self.send_streams
+            .iter()
+            .find(|s| s.id == id.0)
+            .map(|s| s.unacked)
+    }
+
+    /// Forgets an incoming stream, cancelling it if it wasn't complete.
+    pub(crate) fn close_stream(&mut self, id: StreamId) {
+        if let Some(stream) = self.recv_streams.remove(&id.0) {
+            if stream.is_open() {
+                self.queue_frame(&Frame::Cancel { stream: id.0 });
+            }
+        }
+    }
+
+    /// Measures the round trip time to the peer, see
+    /// [`SocketWorker::ping`](crate::SocketWorker::ping).
+    pub fn ping(&mut self, now: Instant) {
+        self.now = now;
+        self.pings
+            .retain(|_, sent| now.saturating_duration_since(*sent) < PING_TIMEOUT);
+
+        let token = self.next_ping;
+        self.next_ping += 1;
+        self.pings.insert(token, now);
+        let msg = self.seal(Message::new_ping(token));
+        self.outgoing.push_front(Outgoing::new(msg));
+    }
+
+    fn send_acc_message(&mut self, id: u64) {
+        let msg = self.seal(Message::new_acc(id));
+        self.outgoing.push_front(Outgoing::new(msg));
+    }
+
+    /// Control messages are only checked once the handshake agreed on a
+    /// key, data messages always.
+    fn is_authentic(&self, msg: &Message) -> bool {
+        (msg.is_control() && self.session_key.is_none()) || self.check_hash(msg)
+    }
+
+    fn handle_message(&mut self, now: Instant, msg: Message) -> ReceiveResult {
+        if self.state.is_final() {
+            return ReceiveResult::Skip;
+        }
+
+        if msg.is_control() {
+            // Batches carry no hash of their own, every message inside does
+            if msg.kind != PacketType::Batch {
+                if !self.is_authentic(&msg) {
+                    return ReceiveResult::Bad;
+                }
+                self.on_peer_alive(now);
+            }
+            return self.handle_ctrl(now, msg);
+        }
+
+        if !self.is_authentic(&msg) {
+            return ReceiveResult::Bad;
+        }
+        self.on_peer_alive(now);
+
+        if msg.is_placeholder() {
+            // Acknowledged once the peer can't be waiting for any of them
+            if self.replay.insert_range(msg.skipped()) {
+                self.send_acc_message(msg.id);
+            }
+            return ReceiveResult::Skip;
+        }
+
+        match self.replay.check(msg.id) {
+            // Not acknowledged, so the peer sends it again once we caught up
+            Verdict::Ahead => return ReceiveResult::Skip,
+            Verdict::Seen => {
+                // Our ACK may have been lost
+                self.send_acc_message(msg.id);
+                return ReceiveResult::Skip;
+            }
+            Verdict::New => {}
+        }
+
+        if msg.stream {
+            self.send_acc_message(msg.id);
+            self.replay.insert(msg.id);
+            return self.handle_frame(msg.data);
+        }
+
+        // Not acknowledged unless it can be read, so the sender doesn't
+        // report it delivered
+        let data = if msg.compressed {
+            match self.compression.decompress(&msg.data) {
+                Ok(data) => Bytes::from(data),
+                Err(e) => {
+                    return ReceiveResult::Error(ConnectionError::Malformed(format!(
+                        "Error decompressing #{}: {e}",
+                        msg.id
+                    )))
+                }
+            }
+        } else {
+            msg.data
+        };
+
+        self.send_acc_message(msg.id);
+        self.replay.insert(msg.id);
+        (self.notify)(&data);
+
+        ReceiveResult::SomeRR(data)
+    }
+
+    /// Packs as many due messages from the front of the queue as fit into a
+    /// single datagram, appended to `send_buf`.
+    ///
+    /// ACKs are sent once, data messages are moved to the back of the queue
+    /// until the peer acknowledges them, as are messages whose
+    /// retransmission isn't due yet.
+    ///
+    /// # Returns
+    ///
+    /// `false` if there was nothing to send.
+    fn next_datagram(&mut self, now: Instant, unsent: &mut usize) -> bool {
+        let rto = self.config.retransmit_timeout;
+        while *unsent > 0 && !self.outgoing[0].is_due(now, rto) {
+            let pending = self.outgoing.pop_front().expect("rotate wtf?");
+            self.outgoing.push_back(pending);
+            *unsent -= 1;
+        }
+
+        let mtu = self.mtu.mtu();
+        let mut count = 0;
+        let mut size = Message::batch_header_len(self.format, self.integrity.tag_len());
+
+        for pending in self.outgoing.iter().take(*unsent) {
+            let len = 2 + pending.msg.serialized_len();
+            if !pending.is_due(now, rto) || count > 0 && size + len > mtu {
+                break;
+            }
+            size += len;
+            count += 1;
+        }
+
+        match count {
+            0 => return false,
+            1 => self.outgoing[0].msg.serialize_into(&mut self.send_buf),
+            _ => {
+                Message::begin_batch_for(&mut self.send_buf, self.format, self.integrity.tag_len());
+                for pending in self.outgoing.iter().take(count) {
+                    pending.msg.serialize_batched_into(&mut self.send_buf);
+                }
+            }
+        }
+
+        for _ in 0..count {
+            let mut pending = self.outgoing.pop_front().expect("send wtf?");
+            if self.config.trace {
+                eprintln!("Sending '{}'", pending.msg);
+            }
+            if !pending.msg.is_control() {
+                pending.sends += 1;
+                pending.last_sent = Some(now);
+                self.outgoing.push_back(pending);
+            }
+        }
+        *unsent -= count;
+
+        true
+    }
+
+    /// Reads as much of the outgoing streams as the receivers have credit
+    /// for and the queue limits allow.
+    fn fill_streams(&mut self) {
+        let max_chunk = self.max_payload() - FRAME_HEADER_LEN;
+        for i in 0..self.send_streams.len() {
+            while self.has_room(max_chunk + FRAME_HEADER_LEN) {
+                let Some(frame) = self.send_streams[i].next_frame(max_chunk) else {
+                    break;
+                };
+                let frame = frame.unwrap_or_else(|e| {
+                    let id = self.send_streams[i].id;
+                    self.events.push(Err(ConnectionError::Io(io::Error::new(
+                        e.kind(),
+                        format!("Error reading stream {id}: {e}"),
+                    ))));
+                    Frame::Abort { stream: id }
+                });
+                self.send_streams[i].unacked += 1;
+                self.queue_frame(&frame);
+            }
+        }
+    }
+
+    fn queue_frame(&mut self, frame: &Frame) {
+        let msg = Message::unsealed(self.message_id, frame.encode(), false, true);
+        let msg = self.seal(msg);
+        self.message_id += 1;
+        self.enqueue(msg);
+    }
+
+    fn handle_frame(&mut self, data: Bytes) -> ReceiveResult {
+        let frame = match Frame::decode(data) {
+            Ok(frame) => frame,
+            Err(e) => return ReceiveResult::Error(ConnectionError::Malformed(e)),
+        };
+
+        match frame {
+            Frame::Data { stream, .. } | Frame::End { stream, .. } | Frame::Abort { stream } => {
+                let window = self.config.stream_window;
+                let mut result = ReceiveResult::Ctrl;
+                let receiver = self.recv_streams.entry(stream).or_insert_with(|| {
+                    result = ReceiveResult::StreamOpened(stream);
+                    let first = match frame {
+                        Frame::Data { offset, .. } => offset,
+                        _ => 0,
+                    };
+                    RecvStream::opened_at(first, window)
+                });
+                match frame {
+                    Frame::Data { offset, data, .. } => receiver.on_data(offset, data),
+                    Frame::End { total, hash, .. } => receiver.on_end(total, hash),
+                    _ => receiver.on_abort(),
+                }
+
+                result
+            }
+            Frame::Credit { stream, limit } => {
+                if let Some(sender) = self.send_streams.iter_mut().find(|s| s.id == stream) {
+                    sender.on_credit(limit);
+                }
+
+                ReceiveResult::Ctrl
+            }
+            Frame::Cancel { stream } => {
+                let before = self.send_streams.len();
+                self.send_streams.retain(|s| s.id != stream);
+                if self.send_streams.len() == before {
+                    return ReceiveResult::Ctrl;
+                }
+
+                ReceiveResult::Error(ConnectionError::StreamCancelled(StreamId(stream)))
+            }
+        }
+    }
+
+    /// Counts down the frames of an outgoing stream still on their way.
+    fn on_frame_acked(&mut self, data: Bytes) -> ReceiveResult {
+        let stream = match Frame::decode(data) {
+            Ok(Frame::Data { stream, .. } | Frame::End { stream, .. } | Frame::Abort { stream }) => {
+                stream
+            }
+            _ => return ReceiveResult::Ctrl,
+        };
+        let Some(index) = self.send_streams.iter().position(|s| s.id == stream) else {
+            return ReceiveResult::Ctrl;
+        };
+
+        let sender = &mut self.send_streams[index];
+        sender.unacked -= 1;
+        if !sender.ended || sender.unacked > 0 {
+            return ReceiveResult::Ctrl;
+        }
+
+        let sender = self.send_streams.remove(index);
+        if sender.aborted {
+            ReceiveResult::Ctrl
+        } else {
+            ReceiveResult::StreamSent(stream)
+        }
+    }
+
+    fn probe_mtu(&mut self, now: Instant) {
+        if let Some(size) = self.mtu.poll(now) {
+            let probe = Message::new_probe_for(size, self.format, self.integrity.tag_len());
+            let probe = self.seal(probe).serialize();
+            self.direct.push(probe);
+        }
+    }
+
+    fn handle_ctrl(&mut self, now: Instant, msg: Message) -> ReceiveResult {
+        let is_batch = msg.kind == PacketType::Batch;
+        let control = match msg.try_get_control() {
+            Ok(control) => control,
+            // Unsigned, so anybody could have sent it
+            Err(_) if is_batch => return ReceiveResult::Bad,
+            Err(e) => return ReceiveResult::Error(e),
+        };
+        match control {
+            ControlMessage::Acc { id } => {
+                if let Some(rem_ind) = self.outgoing.iter().position(|i| i.msg.id == id) {
+                    let pending = self.outgoing.remove(rem_ind).expect("acc wtf?");
+                    self.dequeued(&pending.msg);
+                    if pending.msg.is_placeholder() {
+                        // The dropped message was reported expired already
+                        return ReceiveResult::Skip;
+                    }
+                    if pending.msg.stream {
+                        return self.on_frame_acked(pending.msg.data);
+                    }
+                    return ReceiveResult::Acked(id);
+                }
+
+                ReceiveResult::Ctrl
+            }
+            ControlMessage::Probe { size, received } => {
+                if size == received {
+                    let msg = self.seal(Message::new_probe_ack(size));
+                    self.outgoing.push_front(Outgoing::new(msg));
+                }
+
+                ReceiveResult::Ctrl
+            }
+            ControlMessage::ProbeAck { size } => {
+                self.mtu.on_probe_ack(size);
+
+                ReceiveResult::Ctrl
+            }
+            ControlMessage::Ping { token } => {
+                let msg = self.seal(Message::new_pong(token));
+                self.outgoing.push_front(Outgoing::new(msg));
+
+                ReceiveResult::Ctrl
+            }
+            ControlMessage::Close => {
+                self.set_state(ConnectionState::Closed);
+
+                ReceiveResult::Ctrl
+            }
+            ControlMessage::Pong { token } => match self.pings.remove(&token) {
+                Some(sent) => ReceiveResult::Pong(now.saturating_duration_since(sent)),
+                None => ReceiveResult::Ctrl,
+            },
+            ControlMessage::Batch { messages } => {
+                // The batch itself is unsigned, one forged message spoils it
+                if !messages.iter().all(|msg| self.is_authentic(msg)) {
+                    return ReceiveResult::Bad;
+                }
+                ReceiveResult::Batch(
+                    messages
+                        .into_iter()
+                        .map(|msg| self.handle_message(now, msg))
+                        .collect(),
+                )
+            }
+        }
+    }
+}
+
+impl Debug for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Protocol")
+            .field("state", &self.state)
+            .field("outgoing", &self.outgoing.len())
+            .field("replay_base", &self.replay.base())
+            .field("notify", &self.notify)
+            .field("message_id", &self.message_id)
+            .field("compression", &self.compression)
+            .field("mtu", &self.mtu.mtu())
+            .finish()
+    }
+}
+
+/// A message waiting in the outgoing queue.
+struct Outgoing {
+    msg: Message,
+    /// How many times the message has been sent so far
+    sends: u32,
+    last_sent: Option<Instant>,
+    /// Given up on at this time, see [`Ttl::deadline`]
+    deadline: Option<Instant>,
+    /// Given up on when due again after this many sends
+    max_sends: Option<u32>,
+}
+
+impl Outgoing {
+    fn new(msg: Message) -> Outgoing {
+        Outgoing {
+            msg,
+            sends: 0,
+            last_sent: None,
+            deadline: None,
+            max_sends: None,
+        }
+    }
+
+    fn set_ttl(&mut self, ttl: Ttl, now: Instant) {
+        self.deadline = ttl.deadline.map(|deadline| now + deadline);
+        self.max_sends = ttl.max_retransmits.map(|max| max.saturating_add(1));
+    }
+
+    /// When the message is to be given up on, `None` for never.
+    fn expires_at(&self, rto: Duration) -> Option<Instant> {
+        let out_of_sends = match (self.max_sends, self.last_sent) {
+            (Some(max), Some(sent)) if self.sends >= max => Some(sent + rto),
+            _ => None,
+        };
+        self.deadline.into_iter().chain(out_of_sends).min()
+    }
+
+    /// Whether the message goes out at `now`, the first time or again.
+    fn is_due(&self, now: Instant, rto: Duration) -> bool {
+        self.last_sent.is_none_or(|sent| now >= sent + rto)
+    }
+}
+
+enum ReceiveResult {
+    SomeRR(Bytes),
+    Acked(u64),
+    Pong(Duration),
+    StreamOpened(u64),
+    StreamSent(u64),
+    Batch(Vec<ReceiveResult>),
+    Ctrl,
+    Bad,
+    Skip,
+    Error(ConnectionError),
+}
+
+fn new_mtu_discovery(config: &Config) -> MtuDiscovery {
+    MtuDiscovery::new(
+        config.base_datagram_size,
+        config.max_datagram_size.min(MAX_DATAGRAM_SIZE),
+        config.mtu_probing,
+    )
+}
+
+/// Data messages count, control messages and placeholders don't.
+fn counts_against_limits(msg: &Message) -> bool {
+    !msg.is_control() && !msg.is_placeholder()
+}
+
+fn collect(result: ReceiveResult, msgs: &mut Vec<Result<Event, ConnectionError>>) {
+    match result {
+        ReceiveResult::SomeRR(msg) => msgs.push(Ok(Event::Message(msg))),
+        ReceiveResult::Acked(id) => msgs.push(Ok(Event::Delivered(MessageId(id)))),
+        ReceiveResult::Pong(rtt) => msgs.push(Ok(Event::Pong(rtt))),
+        ReceiveResult::StreamOpened(id) => msgs.push(Ok(Event::StreamOpened(StreamId(id)))),
+        ReceiveResult::StreamSent(id) => msgs.push(Ok(Event::StreamSent(StreamId(id)))),
+        ReceiveResult::Batch(results) => {
+            for result in results {
+                collect(result, msgs);
+            }
+        }
+        ReceiveResult::Error(e) => msgs.push(Err(e)),
+        _ => {}
+    }
+}
@@ -0,0 +1,78 @@
+use std::{io, net::UdpSocket, time::Duration};
+
+/// Sleeps until one of `sockets` has a datagram waiting or `timeout`
+/// passes, `None` waiting for as long as it takes.
+///
+/// Meant for event loops over several [`SocketWorker`](crate::SocketWorker)s,
+/// see [`SocketWorker::socket`](crate::SocketWorker::socket) and
+/// [`SocketWorker::next_timeout`](crate::SocketWorker::next_timeout). Uses
+/// `poll` on Linux; elsewhere it sleeps a millisecond at most, so it may
+/// return before anything is readable.
+///
+/// # Returns
+///
+/// `false` if the timeout passed first.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use std::time::Duration;
+/// # use udp_connection::{send_handshake, wait_readable};
+/// let mut worker = send_handshake("127.0.0.1:8080".to_string(), |_| {})?;
+/// while !worker.state().is_final() {
+///     for event in worker.work() {
+///         println!("{event:?}");
+///     }
+///     wait_readable(&[worker.socket()], worker.next_timeout())?;
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn wait_readable(sockets: &[&UdpSocket], timeout: Option<Duration>) -> io::Result<bool> {
+    #[cfg(target_os = "linux")]
+    {
+        linux::poll_readable(sockets, timeout)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = sockets;
+        std::thread::sleep(timeout.unwrap_or(Duration::MAX).min(Duration::from_millis(1)));
+        Ok(true)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{io, net::UdpSocket, os::fd::AsRawFd, time::Duration};
+
+    pub(super) fn poll_readable(
+        sockets: &[&UdpSocket],
+        timeout: Option<Duration>,
+    ) -> io::Result<bool> {
+        let mut fds: Vec<libc::pollfd> = sockets
+            .iter()
+            .map(|socket| libc::pollfd {
+                fd: socket.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        // Rounded up, waking early would only mean polling again
+        let timeout = timeout.map_or(-1, |t| {
+            t.as_nanos().div_ceil(1_000_000).min(libc::c_int::MAX as u128) as libc::c_int
+        });
+
+        loop {
+            // SAFETY: `fds` holds `fds.len()` initialized pollfds whose file
+            // descriptors stay open for the lifetime of `sockets`.
+            let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+            if ret >= 0 {
+                return Ok(ret > 0);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+}
@@ -0,0 +1,402 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    config::Config,
+    delivery::{MessageId, SendError},
+    error::ConnectionError,
+    event::Event,
+    socket_worker::SocketWorker,
+    socket_worker_handshake::{handshake, send_handshake_timeout},
+    state::ConnectionState,
+};
+
+/// When and how often a [`ReconnectingConnection`] tries again.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// How long the old session gets to answer before a new handshake, see
+    /// [`ReconnectingConnection`] on when it still can.
+    pub resume_timeout: Duration,
+    /// Delay before the first handshake attempt, doubled after every failure.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Fraction of each delay that is random, so peers that lost the same
+    /// link don't retry in lockstep. `0.0` to `1.0`.
+    pub jitter: f64,
+    /// How long a handshake attempt waits for the server. `work()` blocks
+    /// for up to this long while an attempt runs.
+    pub handshake_timeout: Duration,
+    /// Failed handshakes in a row before giving up, `None` to retry forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            resume_timeout: Duration::from_secs(2),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            jitter: 0.5,
+            handshake_timeout: Duration::from_secs(1),
+            max_attempts: None,
+        }
+    }
+}
+
+/// Progress of a [`ReconnectingConnection`], reported as [`Event::Reconnect`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReconnectStatus {
+    /// The connection timed out; pinging the peer on the old session.
+    Resuming,
+    /// Handshake attempt `attempt` starts after `delay`.
+    Scheduled { attempt: u32, delay: Duration },
+    /// Handshake attempt `attempt` failed.
+    Failed { attempt: u32, error: String },
+    /// The peer answered on the old session, `requeued` unacknowledged
+    /// messages were sent again.
+    Resumed { requeued: usize },
+    /// Handshake attempt `attempt` succeeded, `requeued` unacknowledged
+    /// messages were sent again on the new session.
+    Reconnected { attempt: u32, requeued: usize },
+    /// [`ReconnectPolicy::max_attempts`] handshakes failed, the connection
+    /// is given up.
+    GaveUp,
+}
+
+enum Link {
+    Up(SocketWorker),
+    Resuming(SocketWorker, Instant),
+    /// Waiting for handshake attempt `attempt`
+    Waiting {
+        attempt: u32,
+        at: Instant,
+    },
+    /// Closed, or given up
+    Down,
+}
+
+/// A client connection that handshakes again when the peer stops
+/// answering.
+///
+/// When the connection times out, it first tries to resume the session,
+/// which works while the peer still holds it. Otherwise it re-runs the
+/// handshake with exponential backoff and jitter. The server gives the
+/// session up after its own [`Config::idle_timeout`], so resuming needs
+/// the server's to be longer than the client's plus
+/// [`ReconnectPolicy::resume_timeout`]; with the same timeouts on both
+/// sides the server usually dropped the session already, and resuming
+/// only costs the resume timeout before the handshake. Either way,
+/// messages the peer has not acknowledged are sent again, so a message may
+/// arrive twice if only its ACK was lost.
+///
+/// Message IDs are assigned by the wrapper and stay the same across
+/// sessions; [`Event::Delivered`] and [`Event::Expired`] report them. The
+/// [`Config::ttl`] of a message starts over with every session.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use udp_connection::{Event, ReconnectPolicy, ReconnectingConnection};
+/// let mut connection = ReconnectingConnection::connect(
+///     "127.0.0.1:8080".to_string(),
+///     |_| {},
+///     ReconnectPolicy::default(),
+/// )?;
+/// connection.send_message(b"survives outages".to_vec()).unwrap();
+///
+/// loop {
+///     for event in connection.work() {
+///         match event {
+///             Ok(Event::Reconnect(status)) => eprintln!("{status:?}"),
+///             Ok(Event::Delivered(id)) => println!("{id} delivered"),
+///             Ok(_) => {}
+///             Err(e) => eprintln!("{e}"),
+///         }
+///     }
+///     std::thread::sleep(std::time::Duration::from_millis(1));
+/// }
+/// # Ok::<(), udp_connection::ConnectionError>(())
+/// ```
+pub struct ReconnectingConnection {
+    address: String,
+    notify: fn(&[u8]),
+    policy: ReconnectPolicy,
+    config: Option<Config>,
+    link: Link,
+    /// Payloads not acknowledged yet, by wrapper ID
+    unacked: BTreeMap<u64, Bytes>,
+    /// Wrapper IDs by the ID the current worker assigned
+    in_flight: HashMap<u64, u64>,
+    next_id: u64,
+    closed: bool,
+}
+
+impl ReconnectingConnection {
+    /// Connects to the server at `address`; the first handshake is not
+    /// retried.
+    pub fn connect(
+        address: String,
+        notify: fn(&[u8]),
+        policy: ReconnectPolicy,
+    ) -> Result<ReconnectingConnection, ConnectionError> {
+        let worker = send_handshake_timeout(address.clone(), notify, policy.handshake_timeout)?;
+
+        Ok(ReconnectingConnection {
+            address,
+            notify,
+            policy,
+            config: None,
+            link: Link::Up(worker),
+            unacked: BTreeMap::new(),
+            in_flight: HashMap::new(),
+            next_id: 1,
+            closed: false,
+        })
+    }
+
+    /// Configures the current worker and every one after a reconnect. The
+    /// [`Config::integrity`] checks are offered from the next handshake on.
+    pub fn with_config(mut self, config: Config) -> ReconnectingConnection {
+        self.link = match self.link {
+            Link::Up(worker) => Link::Up(worker.with_config(config.clone())),
+            Link::Resuming(worker, since) => {
+                Link::Resuming(worker.with_config(config.clone()), since)
+            }
+            link => link,
+        };
+        self.config = Some(config);
+        self
+    }
+
+    /// The worker of the current session, if there is one.
+    pub fn worker(&mut self) -> Option<&mut SocketWorker> {
+        match &mut self.link {
+            Link::Up(worker) | Link::Resuming(worker, _) => Some(worker),
+            _ => None,
+        }
+    }
+
+    /// Whether the current session is established.
+    pub fn is_connected(&self) -> bool {
+        matches!(&self.link, Link::Up(worker) if worker.state() == ConnectionState::Established)
+    }
+
+    /// Messages sent but not acknowledged yet, including those waiting for
+    /// a new session.
+    pub fn unacked(&self) -> usize {
+        self.unacked.len()
+    }
+
+    /// Queues a message for reliable delivery, across reconnects.
+    ///
+    /// Fails once the connection was closed or given up, or if the payload
+    /// exceeds [`SocketWorker::max_payload`]. While there is no session the
+    /// queue limits of the [`Config`] are enforced here, always rejecting
+    /// what doesn't fit, and a message too large for the next session is
+    /// given up once it starts.
+    pub fn send_message(&mut self, msg: impl Into<Bytes>) -> Result<MessageId, SendError> {
+        if self.closed || matches!(self.link, Link::Down) {
+            return Err(SendError::NotConnected(ConnectionState::Closed));
+        }
+
+        let id = self.next_id;
+        let msg = msg.into();
+        if let Link::Up(worker) = &mut self.link {
+            let worker_id = worker.send_message(msg.clone())?;
+            self.in_flight.insert(worker_id.0, id);
+        } else if !self.has_room(msg.len()) {
+            return Err(SendError::QueueFull);
+        }
+        self.next_id += 1;
+        self.unacked.insert(id, msg);
+
+        Ok(MessageId(id))
+    }
+
+    /// Whether a message waiting for the session fits the queue limits.
+    fn has_room(&self, len: usize) -> bool {
+        let Some(config) = &self.config else {
+            return true;
+        };
+        let bytes = || self.unacked.values().map(Bytes::len).sum::<usize>();
+
+        config
+            .max_queued_messages
+            .is_none_or(|max| self.unacked.len() < max)
+            && config
+                .max_queued_bytes
+                .is_none_or(|max| bytes() + len <= max)
+    }
+
+    /// Closes the connection once everything queued was delivered, see
+    /// [`SocketWorker::close`]. A closed connection is not reconnected.
+    pub fn close(&mut self) {
+        self.closed = true;
+        match &mut self.link {
+            Link::Up(worker) => worker.close(),
+            _ => self.link = Link::Down,
+        }
+    }
+
+    /// Works the current session, or the reconnect when it is due.
+    pub fn work(&mut self) -> Vec<Result<Event, ConnectionError>> {
+        let mut events = Vec::new();
+
+        match std::mem::replace(&mut self.link, Link::Down) {
+            Link::Up(mut worker) => {
+                self.work_worker(&mut worker, &mut events);
+                if worker.state() == ConnectionState::TimedOut && !self.closed {
+                    worker.resume();
+                    self.in_flight.clear();
+                    events.push(Ok(Event::Reconnect(ReconnectStatus::Resuming)));
+                    self.link = Link::Resuming(worker, Instant::now());
+                } else {
+                    self.link = Link::Up(worker);
+                }
+            }
+            Link::Resuming(mut worker, since) => {
+                self.work_worker(&mut worker, &mut events);
+                if worker.state() == ConnectionState::Established {
+                    let requeued = self.requeue(&mut worker, &mut events);
+                    events.push(Ok(Event::Reconnect(ReconnectStatus::Resumed { requeued })));
+                    self.link = Link::Up(worker);
+                } else if worker.state().is_final() || since.elapsed() >= self.policy.resume_timeout
+                {
+                    self.schedule(1, &mut events);
+                } else {
+                    self.link = Link::Resuming(worker, since);
+                }
+            }
+            Link::Waiting { attempt, at } => {
+                if Instant::now() < at {
+                    self.link = Link::Waiting { attempt, at };
+                } else {
+                    self.attempt(attempt, &mut events);
+                }
+            }
+            Link::Down => {}
+        }
+
+        events
+    }
+
+    /// Works `worker`, translating its message IDs to ours.
+    fn work_worker(
+        &mut self,
+        worker: &mut SocketWorker,
+        events: &mut Vec<Result<Event, ConnectionError>>,
+    ) {
+        let mut session_lost = false;
+        for event in worker.work() {
+            if let Ok(Event::StateChanged(ConnectionState::TimedOut)) = event {
+                session_lost = !self.closed;
+            }
+            events.push(match event {
+                Ok(Event::Delivered(worker_id)) => match self.in_flight.remove(&worker_id.0) {
+                    Some(id) => {
+                        self.unacked.remove(&id);
+                        Ok(Event::Delivered(MessageId(id)))
+                    }
+                    None => continue,
+                },
+                Ok(Event::Expired(worker_id)) => match self.in_flight.remove(&worker_id.0) {
+                    // Dropped with the session, requeued on the next one
+                    Some(_) if session_lost => continue,
+                    Some(id) => {
+                        self.unacked.remove(&id);
+                        Ok(Event::Expired(MessageId(id)))
+                    }
+                    None => continue,
+                },
+                event => event,
+            });
+        }
+    }
+
+    fn attempt(&mut self, attempt: u32, events: &mut Vec<Result<Event, ConnectionError>>) {
+        let result = handshake(
+            self.address.clone(),
+            self.notify,
+            Some(self.policy.handshake_timeout),
+            self.config.clone().unwrap_or_default(),
+        );
+
+        match result {
+            Ok(mut worker) => {
+                let requeued = self.requeue(&mut worker, events);
+                events.push(Ok(Event::Reconnect(ReconnectStatus::Reconnected {
+                    attempt,
+                    requeued,
+                })));
+                self.link = Link::Up(worker);
+            }
+            Err(e) => {
+                events.push(Ok(Event::Reconnect(ReconnectStatus::Failed {
+                    attempt,
+                    error: e.to_string(),
+                })));
+                if self.policy.max_attempts.is_some_and(|max| attempt >= max) {
+                    events.push(Ok(Event::Reconnect(ReconnectStatus::GaveUp)));
+                    self.link = Link::Down;
+                } else {
+                    self.schedule(attempt + 1, events);
+                }
+            }
+        }
+    }
+
+    fn schedule(&mut self, attempt: u32, events: &mut Vec<Result<Event, ConnectionError>>) {
+        let delay = self.backoff(attempt);
+        events.push(Ok(Event::Reconnect(ReconnectStatus::Scheduled {
+            attempt,
+            delay,
+        })));
+        self.link = Link::Waiting {
+            attempt,
+            at: Instant::now() + delay,
+        };
+    }
+
+    /// Exponential backoff, shortened by a random part of up to `jitter`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .policy
+            .initial_backoff
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.policy.max_backoff);
+
+        let mut random = [0; 4];
+        if getrandom::getrandom(&mut random).is_err() {
+            return delay;
+        }
+        let random = u32::from_be_bytes(random) as f64 / u32::MAX as f64;
+        delay.mul_f64(1.0 - self.policy.jitter.clamp(0.0, 1.0) * random)
+    }
+
+    /// Sends every unacknowledged message on `worker`, oldest first. Those
+    /// the new worker refuses are given up and reported expired.
+    fn requeue(
+        &mut self,
+        worker: &mut SocketWorker,
+        events: &mut Vec<Result<Event, ConnectionError>>,
+    ) -> usize {
+        self.in_flight.clear();
+        self.unacked
+            .retain(|&id, msg| match worker.send_message(msg.clone()) {
+                Ok(worker_id) => {
+                    self.in_flight.insert(worker_id.0, id);
+                    true
+                }
+                Err(e) => {
+                    events.push(Err(e.into()));
+                    events.push(Ok(Event::Expired(MessageId(id))));
+                    false
+                }
+            });
+        self.in_flight.len()
+    }
+}
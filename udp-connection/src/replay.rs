@@ -0,0 +1,95 @@
+use std::ops::RangeInclusive;
+
+/// Anti-replay window over the IDs of incoming messages.
+///
+/// Every ID below `base` has been received. The bitmap tracks the next
+/// `size` IDs; an ID is accepted once, IDs below the window are replays or
+/// retransmissions, IDs beyond it are dropped until the window catches up.
+#[derive(Debug)]
+pub(crate) struct ReplayWindow {
+    base: u64,
+    /// Ring of `size` bits, ID `id` lives at bit `id % size`
+    bits: Box<[u64]>,
+}
+
+/// What the window says about an incoming ID.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    /// Not received before
+    New,
+    /// Received before, or too old to tell
+    Seen,
+    /// Too far ahead of the oldest missing ID to track
+    Ahead,
+}
+
+impl ReplayWindow {
+    /// A window expecting `first` as the next ID, tracking at least `size`
+    /// IDs.
+    pub(crate) fn new(first: u64, size: usize) -> ReplayWindow {
+        ReplayWindow {
+            base: first,
+            bits: vec![0; size.div_ceil(64).max(1)].into_boxed_slice(),
+        }
+    }
+
+    /// Lowest ID not received yet.
+    pub(crate) fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// IDs tracked beyond `base`.
+    pub(crate) fn size(&self) -> u64 {
+        self.bits.len() as u64 * 64
+    }
+
+    pub(crate) fn check(&self, id: u64) -> Verdict {
+        if id < self.base {
+            Verdict::Seen
+        } else if id - self.base >= self.size() {
+            Verdict::Ahead
+        } else if self.is_set(id) {
+            Verdict::Seen
+        } else {
+            Verdict::New
+        }
+    }
+
+    /// Marks an ID [`Verdict::New`] as received.
+    pub(crate) fn insert(&mut self, id: u64) {
+        debug_assert_eq!(self.check(id), Verdict::New);
+        self.flip(id);
+
+        while self.is_set(self.base) {
+            self.flip(self.base);
+            self.base += 1;
+        }
+    }
+
+    /// Marks the IDs in `ids` not received yet as received, as far as the
+    /// window reaches.
+    ///
+    /// # Returns
+    ///
+    /// `true` once every ID in `ids` was received.
+    pub(crate) fn insert_range(&mut self, ids: RangeInclusive<u64>) -> bool {
+        for id in (*ids.start()).max(self.base)..=*ids.end() {
+            match self.check(id) {
+                Verdict::New => self.insert(id),
+                Verdict::Seen => {}
+                Verdict::Ahead => return false,
+            }
+        }
+        true
+    }
+
+    fn is_set(&self, id: u64) -> bool {
+        let bit = id % self.size();
+        self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+    }
+
+    fn flip(&mut self, id: u64) {
+        let bit = id % self.size();
+        self.bits[(bit / 64) as usize] ^= 1 << (bit % 64);
+    }
+}
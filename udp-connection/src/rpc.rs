@@ -0,0 +1,489 @@
+//! Request/response calls over a [`SocketWorker`].
+//!
+//! Every call carries a correlation ID that the response repeats, so calls
+//! can overlap. The peer answers with the handler registered for the
+//! method name, or with an error if there is none. Responses are reported
+//! by [`Rpc::work`], handed to a callback, or waited for with
+//! [`Rpc::call_wait`]. A call nobody answers within its timeout fails, and
+//! its request is no longer retransmitted, see [`Ttl`].
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! # use std::time::Duration;
+//! # use bytes::Bytes;
+//! # use udp_connection::{receive_handshake, send_handshake, Rpc};
+//! // One node
+//! let mut server = Rpc::new(receive_handshake("127.0.0.1:8080".to_string(), |_| {})?);
+//! server.register("echo", |request| Ok(request))?;
+//!
+//! // Another node
+//! let mut client = Rpc::new(send_handshake("127.0.0.1:8080".to_string(), |_| {})?);
+//! let answer = client.call_wait("echo", &b"hello"[..], Duration::from_secs(1))?;
+//! assert_eq!(answer, Bytes::from_static(b"hello"));
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::{
+    collections::HashMap,
+    fmt, thread,
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::{
+    delivery::{MessageId, SendError, Ttl},
+    error::ConnectionError,
+    event::Event,
+    socket_worker::SocketWorker,
+};
+
+/// Timeout of calls made without one.
+pub const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Kind and correlation ID of an RPC frame
+const RPC_HEADER_LEN: usize = 9;
+
+/// Longest method name, its length is sent in a byte.
+pub const MAX_METHOD_LEN: usize = u8::MAX as usize;
+
+/// Identifier assigned to a call by [`Rpc::call`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CallId(pub u64);
+
+impl fmt::Display for CallId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "call {}", self.0)
+    }
+}
+
+/// Why a call got no answer.
+#[derive(Debug)]
+pub enum RpcError {
+    /// No response within the timeout of the call.
+    Timeout,
+    /// Given up on with [`Rpc::cancel`].
+    Cancelled,
+    /// The peer has no handler for the method.
+    UnknownMethod(String),
+    /// The method name is longer than [`MAX_METHOD_LEN`] bytes.
+    MethodTooLong(String),
+    /// The handler of the peer failed, with its reason.
+    Remote(String),
+    /// The request couldn't be sent, or the connection broke while
+    /// waiting for the response.
+    Connection(ConnectionError),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Timeout => write!(f, "No response in time"),
+            RpcError::Cancelled => write!(f, "Call cancelled"),
+            RpcError::UnknownMethod(method) => write!(f, "Unknown method '{method}'"),
+            RpcError::MethodTooLong(method) => {
+                write!(f, "Method name of {} bytes, at most {MAX_METHOD_LEN}", method.len())
+            }
+            RpcError::Remote(e) => write!(f, "Remote error: {e}"),
+            RpcError::Connection(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RpcError::Connection(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ConnectionError> for RpcError {
+    fn from(e: ConnectionError) -> RpcError {
+        RpcError::Connection(e)
+    }
+}
+
+impl From<SendError> for RpcError {
+    fn from(e: SendError) -> RpcError {
+        RpcError::Connection(e.into())
+    }
+}
+
+/// Something that happened during [`Rpc::work`].
+#[derive(Debug)]
+pub enum RpcEvent {
+    /// The outcome of a call made without a callback.
+    Response(CallId, Result<Bytes, RpcError>),
+    /// Anything but RPC traffic, as [`SocketWorker::work`] reported it.
+    Other(Event),
+}
+
+type Handler = Box<dyn FnMut(Bytes) -> Result<Bytes, String> + Send>;
+type Callback = Box<dyn FnOnce(Result<Bytes, RpcError>) + Send>;
+
+/// A call waiting for its response.
+struct Pending {
+    /// The request, until the response comes
+    request: MessageId,
+    deadline: Instant,
+    /// `None` reports the response as [`RpcEvent::Response`]
+    callback: Option<Callback>,
+}
+
+/// RPC on both ends of a connection, see the [module docs](self).
+///
+/// Every message on the connection is taken to be RPC traffic; send plain
+/// messages on a connection of their own.
+pub struct Rpc {
+    worker: SocketWorker,
+    handlers: HashMap<String, Handler>,
+    pending: HashMap<u64, Pending>,
+    next_call: u64,
+    /// Events that came up while [`Rpc::call_wait`] waited
+    backlog: Vec<Result<RpcEvent, ConnectionError>>,
+}
+
+impl Rpc {
+    pub fn new(worker: SocketWorker) -> Rpc {
+        Rpc {
+            worker,
+            handlers: HashMap::new(),
+            pending: HashMap::new(),
+            next_call: 1,
+            backlog: Vec::new(),
+        }
+    }
+
+    pub fn worker(&self) -> &SocketWorker {
+        &self.worker
+    }
+
+    /// For everything but calls, like closing.
+    pub fn worker_mut(&mut self) -> &mut SocketWorker {
+        &mut self.worker
+    }
+
+    pub fn into_inner(self) -> SocketWorker {
+        self.worker
+    }
+
+    /// Answers requests for `method` with `handler`, replacing the handler
+    /// registered before, if any.
+    ///
+    /// The handler runs inside [`Rpc::work`]; an `Err` is reported to the
+    /// caller as [`RpcError::Remote`].
+    ///
+    /// Fails with [`RpcError::MethodTooLong`] for a name no caller could
+    /// send.
+    pub fn register(
+        &mut self,
+        method: impl Into<String>,
+        handler: impl FnMut(Bytes) -> Result<Bytes, String> + Send + 'static,
+    ) -> Result<(), RpcError> {
+        let method = check_method(method.into())?;
+        self.handlers.insert(method, Box::new(handler));
+        Ok(())
+    }
+
+    /// Stops answering requests for `method`.
+    pub fn unregister(&mut self, method: &str) -> bool {
+        self.handlers.remove(method).is_some()
+    }
+
+    /// Calls `method` on the peer with the [`DEFAULT_CALL_TIMEOUT`], the
+    /// outcome is reported by [`Rpc::work`] as [`RpcEvent::Response`].
+    ///
+    /// Fails if the request can't be queued, like one that exceeds
+    /// [`SocketWorker::max_payload`], or with [`RpcError::MethodTooLong`].
+    pub fn call(&mut self, method: &str, request: impl Into<Bytes>) -> Result<CallId, RpcError> {
+        self.start_call(method, request.into(), DEFAULT_CALL_TIMEOUT, None)
+    }
+
+    /// Like [`Rpc::call`], but fails with [`RpcError::Timeout`] after
+    /// `timeout` and hands the outcome to `callback`, inside
+    /// [`Rpc::work`].
+    pub fn call_with(
+        &mut self,
+        method: &str,
+        request: impl Into<Bytes>,
+        timeout: Duration,
+        callback: impl FnOnce(Result<Bytes, RpcError>) + Send + 'static,
+    ) -> Result<CallId, RpcError> {
+        self.start_call(method, request.into(), timeout, Some(Box::new(callback)))
+    }
+
+    /// Calls `method` and works the connection until the response arrives
+    /// or `timeout` passes.
+    ///
+    /// Anything else that happens meanwhile, requests of the peer
+    /// included, is handled as usual and returned by the next call to
+    /// `work`.
+    pub fn call_wait(
+        &mut self,
+        method: &str,
+        request: impl Into<Bytes>,
+        timeout: Duration,
+    ) -> Result<Bytes, RpcError> {
+        let id = self.start_call(method, request.into(), timeout, None)?;
+        loop {
+            let events = self.work_once();
+            let mut response = None;
+            for event in events {
+                match event {
+                    Ok(RpcEvent::Response(call, result)) if call == id => response = Some(result),
+                    event => self.backlog.push(event),
+                }
+            }
+            if let Some(result) = response {
+                return result;
+            }
+            if self.worker.state().is_final() {
+                self.pending.remove(&id.0);
+                return Err(ConnectionError::NotConnected(self.worker.state()).into());
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Gives up on a call; its callback gets [`RpcError::Cancelled`]. The
+    /// request is no longer sent, but the peer may still handle it if it
+    /// got it already, the response is dropped.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the call was over already.
+    pub fn cancel(&mut self, id: CallId) -> bool {
+        let Some(pending) = self.pending.remove(&id.0) else {
+            return false;
+        };
+        self.worker.expire_message(pending.request);
+        if let Some(callback) = pending.callback {
+            callback(Err(RpcError::Cancelled));
+        }
+        true
+    }
+
+    /// Calls that are waiting for a response.
+    pub fn pending_calls(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Works the connection, see [`SocketWorker::work`]: answers the
+    /// requests of the peer, routes responses to their callers and fails
+    /// the calls that timed out.
+    pub fn work(&mut self) -> Vec<Result<RpcEvent, ConnectionError>> {
+        let mut events = std::mem::take(&mut self.backlog);
+        events.append(&mut self.work_once());
+        events
+    }
+
+    fn work_once(&mut self) -> Vec<Result<RpcEvent, ConnectionError>> {
+        let mut events = Vec::new();
+        for event in self.worker.work() {
+            match event {
+                Ok(Event::Message(data)) => match RpcFrame::decode(data) {
+                    Ok(frame) => self.handle_frame(frame, &mut events),
+                    Err(e) => events.push(Err(ConnectionError::Malformed(e))),
+                },
+                Ok(event) => events.push(Ok(RpcEvent::Other(event))),
+                Err(e) => events.push(Err(e)),
+            }
+        }
+
+        let now = Instant::now();
+        let expired: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| now >= pending.deadline)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            self.finish(id, Err(RpcError::Timeout), &mut events);
+        }
+
+        events
+    }
+
+    fn start_call(
+        &mut self,
+        method: &str,
+        request: Bytes,
+        timeout: Duration,
+        callback: Option<Callback>,
+    ) -> Result<CallId, RpcError> {
+        let id = self.next_call;
+        let frame = RpcFrame::Request {
+            id,
+            method: check_method(method.to_string())?,
+            data: request,
+        };
+        // A request nobody waits for any more is not worth retransmitting
+        let ttl = Ttl {
+            deadline: Some(timeout),
+            ..Ttl::default()
+        };
+        let request = self.worker.send_message_with_ttl(frame.encode(), ttl)?;
+
+        self.next_call += 1;
+        self.pending.insert(
+            id,
+            Pending {
+                request,
+                deadline: Instant::now() + timeout,
+                callback,
+            },
+        );
+        Ok(CallId(id))
+    }
+
+    fn handle_frame(
+        &mut self,
+        frame: RpcFrame,
+        events: &mut Vec<Result<RpcEvent, ConnectionError>>,
+    ) {
+        match frame {
+            RpcFrame::Request { id, method, data } => {
+                let response = match self.handlers.get_mut(&method) {
+                    Some(handler) => match handler(data) {
+                        Ok(data) => RpcFrame::Response { id, data },
+                        Err(error) => RpcFrame::Failed { id, error },
+                    },
+                    None => RpcFrame::UnknownMethod { id, method },
+                };
+                if let Err(e) = self.worker.send_message(response.encode()) {
+                    // The caller would wait for the response until it times out
+                    if let SendError::TooLarge { .. } = e {
+                        let error = e.to_string();
+                        _ = self.worker.send_message(RpcFrame::Failed { id, error }.encode());
+                    }
+                    events.push(Err(e.into()));
+                }
+            }
+            RpcFrame::Response { id, data } => self.finish(id, Ok(data), events),
+            RpcFrame::Failed { id, error } => self.finish(id, Err(RpcError::Remote(error)), events),
+            RpcFrame::UnknownMethod { id, method } => {
+                self.finish(id, Err(RpcError::UnknownMethod(method)), events)
+            }
+        }
+    }
+
+    /// Ends a call with `result`, unless it is over already.
+    fn finish(
+        &mut self,
+        id: u64,
+        result: Result<Bytes, RpcError>,
+        events: &mut Vec<Result<RpcEvent, ConnectionError>>,
+    ) {
+        let Some(pending) = self.pending.remove(&id) else {
+            // Timed out or cancelled before the response came
+            return;
+        };
+        match pending.callback {
+            Some(callback) => callback(result),
+            None => events.push(Ok(RpcEvent::Response(CallId(id), result))),
+        }
+    }
+}
+
+impl fmt::Debug for Rpc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut methods: Vec<_> = self.handlers.keys().collect();
+        methods.sort();
+        f.debug_struct("Rpc")
+            .field("worker", &self.worker)
+            .field("methods", &methods)
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+/// Passes `method` if its length fits the byte it is sent with.
+fn check_method(method: String) -> Result<String, RpcError> {
+    if method.len() > MAX_METHOD_LEN {
+        return Err(RpcError::MethodTooLong(method));
+    }
+    Ok(method)
+}
+
+/// Payload of a message on an RPC connection.
+#[derive(Debug, PartialEq, Eq)]
+enum RpcFrame {
+    Request {
+        id: u64,
+        method: String,
+        data: Bytes,
+    },
+    Response {
+        id: u64,
+        data: Bytes,
+    },
+    /// The handler failed
+    Failed {
+        id: u64,
+        error: String,
+    },
+    UnknownMethod {
+        id: u64,
+        method: String,
+    },
+}
+
+impl RpcFrame {
+    fn encode(&self) -> Bytes {
+        let (kind, id, data): (u8, u64, &[u8]) = match self {
+            RpcFrame::Request { id, method, .. } => (0, *id, method.as_bytes()),
+            RpcFrame::Response { id, data } => (1, *id, data),
+            RpcFrame::Failed { id, error } => (2, *id, error.as_bytes()),
+            RpcFrame::UnknownMethod { id, method } => (3, *id, method.as_bytes()),
+        };
+
+        let mut buf = BytesMut::with_capacity(RPC_HEADER_LEN + 1 + data.len());
+        buf.put_u8(kind);
+        buf.put_u64(id);
+        if let RpcFrame::Request { data: request, .. } = self {
+            // Checked by `check_method`
+            buf.put_u8(data.len() as u8);
+            buf.put_slice(data);
+            buf.put_slice(request);
+        } else {
+            buf.put_slice(data);
+        }
+        buf.freeze()
+    }
+
+    fn decode(mut data: Bytes) -> Result<RpcFrame, String> {
+        if data.len() < RPC_HEADER_LEN {
+            return Err(format!("RPC frame of {} bytes", data.len()));
+        }
+        let kind = data.get_u8();
+        let id = data.get_u64();
+        let text = |data: &[u8]| {
+            String::from_utf8(data.to_vec()).map_err(|_| "RPC frame with bad UTF-8".to_string())
+        };
+
+        match kind {
+            0 => {
+                let len = *data.first().ok_or("RPC request without method")? as usize;
+                if data.len() < 1 + len {
+                    return Err("RPC request with cut method".to_string());
+                }
+                let method = text(&data[1..1 + len])?;
+                data.advance(1 + len);
+                Ok(RpcFrame::Request { id, method, data })
+            }
+            1 => Ok(RpcFrame::Response { id, data }),
+            2 => Ok(RpcFrame::Failed {
+                id,
+                error: text(&data)?,
+            }),
+            3 => Ok(RpcFrame::UnknownMethod {
+                id,
+                method: text(&data)?,
+            }),
+            kind => Err(format!("Unknown RPC frame kind {kind}")),
+        }
+    }
+}
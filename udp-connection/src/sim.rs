@@ -0,0 +1,311 @@
+//! Seeded simulations of a connection over a lossy link, in virtual time.
+//!
+//! Two [`Protocol`]s sharing a session exchange datagrams over a simulated
+//! [`Link`] that drops and delays them. Time only passes when the
+//! simulation jumps to the next timer or arrival, so a minute of
+//! retransmissions and keepalives runs in milliseconds, and a run with the
+//! same seed does exactly the same every time.
+//!
+//! # Examples
+//!
+//! ```
+//! # use std::time::Duration;
+//! # use udp_connection::{Config, Event};
+//! # use udp_connection::sim::{Link, Side, Simulation};
+//! let link = Link {
+//!     loss: 0.1,
+//!     ..Link::default()
+//! };
+//! let mut sim = Simulation::new(Config::default(), link, 42);
+//! sim.endpoint_mut(Side::Client).send_message(&b"hello"[..]).unwrap();
+//!
+//! let delivered = sim.run_until(Duration::from_secs(10), |sim| {
+//!     sim.events(Side::Client)
+//!         .iter()
+//!         .any(|(_, event)| matches!(event, Ok(Event::Delivered(_))))
+//! });
+//! assert!(delivered);
+//! ```
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    config::Config, error::ConnectionError, event::Event, header::WireFormat, integrity::Integrity,
+    protocol::Protocol,
+    session::Session,
+};
+
+/// How the simulated link treats every datagram.
+#[derive(Debug, Clone, Copy)]
+pub struct Link {
+    /// Chance of a datagram getting lost, between 0 and 1.
+    pub loss: f64,
+    /// Time every datagram takes.
+    pub delay: Duration,
+    /// Up to this much is added to the delay at random, which reorders
+    /// datagrams.
+    pub jitter: Duration,
+}
+
+impl Default for Link {
+    fn default() -> Link {
+        Link {
+            loss: 0.0,
+            delay: Duration::from_millis(10),
+            jitter: Duration::ZERO,
+        }
+    }
+}
+
+/// One end of a simulated connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// What the link did so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    /// Datagrams sent by either side.
+    pub sent: u64,
+    /// Datagrams of those the link dropped.
+    pub lost: u64,
+}
+
+/// Two [`Protocol`]s connected by a simulated [`Link`].
+pub struct Simulation {
+    endpoints: [Protocol; 2],
+    /// Everything reported so far, with the virtual time it happened at
+    events: [Vec<(Duration, Result<Event, ConnectionError>)>; 2],
+    link: Link,
+    rng: Rng,
+    start: Instant,
+    now: Instant,
+    in_flight: BinaryHeap<Reverse<Arrival>>,
+    /// Breaks ties between datagrams arriving at the same time
+    next_seq: u64,
+    stats: LinkStats,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Arrival {
+    at: Instant,
+    seq: u64,
+    to: usize,
+    datagram: Bytes,
+}
+
+impl Simulation {
+    /// Two established endpoints with `config`, as if they just finished
+    /// the handshake, agreeing on the first of `config.integrity`.
+    ///
+    /// `seed` decides the initial message IDs and the fate of every
+    /// datagram on the link.
+    pub fn new(config: Config, link: Link, seed: u64) -> Simulation {
+        let mut rng = Rng(seed);
+        let start = Instant::now();
+        let integrity = config.integrity.first().copied().unwrap_or(Integrity::Mac);
+        let client_isn = rng.next_isn();
+        let server_isn = rng.next_isn();
+        let session = |local_isn, peer_isn| {
+            Session::new(
+                &[1; 16],
+                &[2; 16],
+                local_isn,
+                peer_isn,
+                integrity,
+                WireFormat::V1,
+                &config.secret,
+            )
+        };
+
+        let endpoints = [
+            (Side::Server, session(client_isn, server_isn)),
+            (Side::Client, session(server_isn, client_isn)),
+        ]
+        .map(|(peer, session)| {
+            let mut protocol = Protocol::new(format!("{peer:?}"), |_| {}, start);
+            protocol.set_config(config.clone(), start);
+            protocol.set_session(session);
+            protocol
+        });
+
+        Simulation {
+            endpoints,
+            events: [Vec::new(), Vec::new()],
+            link,
+            rng,
+            start,
+            now: start,
+            in_flight: BinaryHeap::new(),
+            next_seq: 0,
+            stats: LinkStats::default(),
+        }
+    }
+
+    pub fn endpoint(&self, side: Side) -> &Protocol {
+        &self.endpoints[side.index()]
+    }
+
+    /// Calls that take the time should be given [`Simulation::now`].
+    pub fn endpoint_mut(&mut self, side: Side) -> &mut Protocol {
+        &mut self.endpoints[side.index()]
+    }
+
+    /// The virtual time.
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// Virtual time since the simulation started.
+    pub fn elapsed(&self) -> Duration {
+        self.now - self.start
+    }
+
+    /// Changes the link for datagrams sent from now on. A loss of 1 cuts
+    /// it.
+    pub fn set_link(&mut self, link: Link) {
+        self.link = link;
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    /// Everything `side` reported so far, with the [`Simulation::elapsed`]
+    /// time it happened at.
+    pub fn events(&self, side: Side) -> &[(Duration, Result<Event, ConnectionError>)] {
+        &self.events[side.index()]
+    }
+
+    /// Advances the virtual time by `duration`.
+    pub fn run_for(&mut self, duration: Duration) {
+        let end = self.now + duration;
+        while self.step(end) {}
+    }
+
+    /// Runs until `done` says so, checked after everything that happens, or
+    /// until `timeout` passed.
+    ///
+    /// # Returns
+    ///
+    /// `false` on timeout.
+    pub fn run_until(
+        &mut self,
+        timeout: Duration,
+        mut done: impl FnMut(&Simulation) -> bool,
+    ) -> bool {
+        let end = self.now + timeout;
+        loop {
+            if done(self) {
+                return true;
+            }
+            if !self.step(end) {
+                return done(self);
+            }
+        }
+    }
+
+    /// Jumps to the next timer or arrival and handles it, unless that is
+    /// after `end`.
+    ///
+    /// # Returns
+    ///
+    /// `false` if nothing happens until `end`, the clock is at `end` then.
+    fn step(&mut self, end: Instant) -> bool {
+        let next = self
+            .endpoints
+            .iter()
+            .filter_map(Protocol::poll_timeout)
+            .chain(self.in_flight.peek().map(|Reverse(arrival)| arrival.at))
+            .min();
+        let Some(next) = next.filter(|&next| next <= end) else {
+            self.now = end;
+            return false;
+        };
+        // Timers in the past fire right away
+        self.now = self.now.max(next);
+
+        while let Some(Reverse(arrival)) = self.in_flight.peek() {
+            if arrival.at > self.now {
+                break;
+            }
+            let Reverse(arrival) = self.in_flight.pop().expect("peeked");
+            self.endpoints[arrival.to].handle_datagram(self.now, arrival.datagram);
+        }
+
+        for side in 0..2 {
+            self.endpoints[side].handle_timeout(self.now);
+            let datagrams: Vec<_> = self.endpoints[side]
+                .poll_transmit(self.now)
+                .iter()
+                .map(Bytes::copy_from_slice)
+                .collect();
+            for datagram in datagrams {
+                self.transmit(1 - side, datagram);
+            }
+        }
+
+        let elapsed = self.elapsed();
+        for (endpoint, events) in self.endpoints.iter_mut().zip(&mut self.events) {
+            events.extend(endpoint.take_events().into_iter().map(|e| (elapsed, e)));
+        }
+        true
+    }
+
+    /// Puts a datagram on the link, towards endpoint `to`.
+    fn transmit(&mut self, to: usize, datagram: Bytes) {
+        self.stats.sent += 1;
+        if self.rng.next_f64() < self.link.loss {
+            self.stats.lost += 1;
+            return;
+        }
+
+        let jitter = self.link.jitter.mul_f64(self.rng.next_f64());
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.in_flight.push(Reverse(Arrival {
+            at: self.now + self.link.delay + jitter,
+            seq,
+            to,
+            datagram,
+        }));
+    }
+}
+
+/// SplitMix64, small and plenty random for picking lost datagrams.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// An initial message ID as [`random_isn`](crate::session::random_isn)
+    /// picks them.
+    fn next_isn(&mut self) -> u64 {
+        (self.next_u64() >> 33) + 1
+    }
+}
@@ -1,127 +1,552 @@
 use std::{
-    collections::{HashMap, VecDeque}, fmt::Debug, net::UdpSocket, rc::Rc
+    fmt::Debug,
+    io::{self, Read, Seek},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    thread,
+    time::{Duration, Instant},
 };
 
-use crate::message::Message;
+#[cfg(unix)]
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
 
+use bytes::Bytes;
+
+use crate::{
+    batch_io::{self, RecvBatch},
+    capture::{Capture, Direction},
+    compression::Compression,
+    config::{Config, QueuePolicy},
+    delivery::{DeliveryStatus, MessageId, QueueDepth, SendError, Ttl},
+    error::ConnectionError,
+    event::Event,
+    header::WireFormat,
+    integrity::Integrity,
+    message::MAX_DATAGRAM_SIZE,
+    mtu,
+    protocol::Protocol,
+    session::Session,
+    state::ConnectionState,
+    stream::{StreamId, StreamReader},
+};
+
+/// A [`Protocol`] driven over a UDP socket, in real time.
 pub struct SocketWorker {
     pub address: String,
     socket: UdpSocket,
-    outgoing: VecDeque<Message>,
-    incoming: HashMap<u64, Rc<Message>>,
-    notify: fn(&[u8]),
-    message_id: u64,
+    protocol: Protocol,
+    backlog: Vec<Result<Event, ConnectionError>>,
+    peer_addr: Option<SocketAddr>,
+    recv_batch: RecvBatch,
+    /// Where datagrams are recorded, with the address of our socket
+    capture: Option<(Capture, SocketAddr)>,
 }
 
 impl SocketWorker {
     pub fn new(socket: UdpSocket, address: String, f: fn(&[u8])) -> SocketWorker {
+        let protocol = Protocol::new(address.clone(), f, Instant::now());
+        if protocol.config().mtu_probing {
+            _ = mtu::set_dont_fragment(&socket);
+        }
+
         SocketWorker {
             socket,
+            peer_addr: address.to_socket_addrs().ok().and_then(|mut a| a.next()),
             address,
-            outgoing: VecDeque::with_capacity(1000),
-            incoming: HashMap::new(),
-            notify: f,
-            message_id: 1u64,
+            recv_batch: RecvBatch::new(recv_slot(protocol.config())),
+            protocol,
+            backlog: Vec::new(),
+            capture: None,
+        }
+    }
+
+    /// Replaces the worker configuration, restarting path MTU discovery and
+    /// rate pacing.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use udp_connection::{send_handshake, Config};
+    /// let worker = send_handshake("127.0.0.1:8080".to_string(), |_| {})
+    ///     .expect("Failed to connect")
+    ///     .with_config(Config {
+    ///         max_datagram_size: 9000,
+    ///         ..Config::default()
+    ///     });
+    /// ```
+    pub fn with_config(mut self, config: Config) -> SocketWorker {
+        if config.mtu_probing {
+            _ = mtu::set_dont_fragment(&self.socket);
+        }
+        self.recv_batch = RecvBatch::new(recv_slot(&config));
+        let capture = config.capture.clone();
+        self.protocol.set_config(config, Instant::now());
+        match capture {
+            Some(capture) => self.with_capture(capture),
+            None => self,
+        }
+    }
+
+    /// Records every datagram sent or received from now on.
+    ///
+    /// See [`Capture`] for an example, `udpc-dump` decodes the file. Use
+    /// [`Config::capture`] to record the handshake, too.
+    pub fn with_capture(mut self, capture: Capture) -> SocketWorker {
+        let local = self
+            .socket
+            .local_addr()
+            .unwrap_or(SocketAddr::from(([0, 0, 0, 0], 0)));
+        self.capture = Some((capture, local));
+        self
+    }
+
+    /// The socket the worker sends and receives on, non-blocking.
+    ///
+    /// For waiting until it is readable, like [`wait_readable`] does; reading
+    /// from it directly loses datagrams.
+    ///
+    /// [`wait_readable`]: crate::wait_readable
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// How long `work()` may wait for the socket to become readable before
+    /// something else is due, like a retransmission or keepalive. `None`
+    /// once the connection is over.
+    ///
+    /// Event loops sleep until the socket is readable or this passes, see
+    /// [`wait_readable`](crate::wait_readable). Anything called meanwhile,
+    /// like `send_message`, may make the timeout shorter.
+    pub fn next_timeout(&self) -> Option<Duration> {
+        if !self.backlog.is_empty() {
+            return Some(Duration::ZERO);
+        }
+        let deadline = self.protocol.poll_timeout()?;
+        Some(deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// The protocol state machine this worker drives.
+    pub fn protocol(&self) -> &Protocol {
+        &self.protocol
+    }
+
+    pub fn protocol_mut(&mut self) -> &mut Protocol {
+        &mut self.protocol
+    }
+
+    /// Changes the outgoing bandwidth cap of a running worker.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate` - Bytes per second, `None` to lift the cap, `Some(0)` to
+    ///   pause sending
+    /// * `burst` - Bytes that may be sent back to back while under the cap
+    pub fn set_rate_limit(&mut self, rate: Option<u64>, burst: usize) {
+        self.protocol.set_rate_limit(rate, burst, Instant::now());
+    }
+
+    pub fn config(&self) -> &Config {
+        self.protocol.config()
+    }
+
+    /// Largest datagram currently known to reach the peer.
+    pub fn mtu(&self) -> usize {
+        self.protocol.mtu()
+    }
+
+    /// Largest payload `send_message` accepts with the current path MTU.
+    pub fn max_payload(&self) -> usize {
+        self.protocol.max_payload()
+    }
+
+    /// Integrity check agreed on with the peer during the handshake,
+    /// SHA-256 without one.
+    pub fn integrity(&self) -> Integrity {
+        self.protocol.integrity()
+    }
+
+    /// Header format agreed on with the peer during the handshake,
+    /// version 1 without one.
+    pub fn wire_format(&self) -> WireFormat {
+        self.protocol.wire_format()
+    }
+
+    /// Compression agreed on with the peer during the handshake.
+    pub fn compression(&self) -> Compression {
+        self.protocol.compression()
+    }
+
+    pub(crate) fn set_compression(&mut self, compression: Compression) {
+        self.protocol.set_compression(compression);
+    }
+
+    /// Switches to the IDs, key, integrity check and header format agreed
+    /// on in the handshake.
+    pub(crate) fn set_session(&mut self, session: Session) {
+        self.protocol.set_session(session);
+    }
+
+    /// Waits for the peer to show up on the socket before counting the
+    /// connection as established.
+    pub(crate) fn await_peer(&mut self) {
+        self.protocol.await_peer();
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.protocol.state()
+    }
+
+    /// Stops accepting new messages and closes the connection once the peer
+    /// acknowledged everything queued so far.
+    ///
+    /// `work()` reports [`ConnectionState::Closing`] and later
+    /// [`ConnectionState::Closed`], after telling the peer. If that last
+    /// datagram is lost the peer notices by its idle timeout.
+    pub fn close(&mut self) {
+        self.protocol.close();
+    }
+
+    /// Pings a timed out peer on the old session. The connection is
+    /// established again once the peer answers; messages queued before the
+    /// timeout stay expired.
+    pub(crate) fn resume(&mut self) {
+        self.protocol.resume(Instant::now());
+    }
+
+    /// Receives and sends whatever is due.
+    ///
+    /// Once the connection is closed or timed out, this only returns what
+    /// was reported meanwhile.
+    pub fn work(&mut self) -> Vec<Result<Event, ConnectionError>> {
+        let mut msgs = std::mem::take(&mut self.backlog);
+        if self.protocol.state().is_final() {
+            msgs.append(&mut self.protocol.take_events());
+            return msgs;
+        }
+
+        while self.receive() {}
+        let now = Instant::now();
+        self.protocol.handle_timeout(now);
+        self.send(now);
+
+        msgs.append(&mut self.protocol.take_events());
+        msgs.append(&mut self.backlog);
+        msgs
+    }
+
+    /// Queues a message for reliable delivery.
+    ///
+    /// Returns the ID assigned to the message, see
+    /// [`SocketWorker::delivery_status`] and [`Event::Delivered`]. Fails if
+    /// the connection is closing or over, if the (compressed) payload
+    /// exceeds [`SocketWorker::max_payload`], the payload 64 KiB, or if the
+    /// queue is full and [`Config::queue_policy`] says so.
+    pub fn send_message(&mut self, msg: impl Into<Bytes>) -> Result<MessageId, SendError> {
+        self.send_message_with_ttl(msg, self.config().ttl)
+    }
+
+    /// Like [`SocketWorker::send_message`], but gives up on the message as
+    /// `ttl` says instead of [`Config::ttl`], reporting it as
+    /// [`Event::Expired`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use std::time::Duration;
+    /// # use udp_connection::{send_handshake, Ttl};
+    /// let mut worker = send_handshake("127.0.0.1:8080".to_string(), |_| {})?;
+    /// // Superseded by the next reading anyway
+    /// let ttl = Ttl {
+    ///     deadline: Some(Duration::from_millis(500)),
+    ///     ..Ttl::default()
+    /// };
+    /// worker.send_message_with_ttl(&b"temperature 21.5"[..], ttl)?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn send_message_with_ttl(
+        &mut self,
+        msg: impl Into<Bytes>,
+        ttl: Ttl,
+    ) -> Result<MessageId, SendError> {
+        let msg = msg.into();
+        loop {
+            match self
+                .protocol
+                .send_message_with_ttl(Instant::now(), msg.clone(), ttl)
+            {
+                // Room only ever comes from acknowledgements
+                Err(SendError::QueueFull)
+                    if self.config().queue_policy == QueuePolicy::Block
+                        && self.queue_depth().messages > 0 =>
+                {
+                    let events = self.work();
+                    self.backlog.extend(events);
+                    thread::sleep(Duration::from_millis(1));
+                }
+                result => return result,
+            }
         }
     }
 
-    pub fn work(&mut self) -> Vec<Result<Box<[u8]>, String>> {
-        let mut msgs = Vec::new();
+    /// Messages and bytes waiting to be acknowledged by the peer.
+    pub fn queue_depth(&self) -> QueueDepth {
+        self.protocol.queue_depth()
+    }
+
+    /// Reports how far a message returned by `send_message` got.
+    pub fn delivery_status(&self, id: MessageId) -> DeliveryStatus {
+        self.protocol.delivery_status(id)
+    }
+
+    /// Stops sending a message, see [`Protocol::expire_message`].
+    pub fn expire_message(&mut self, id: MessageId) -> bool {
+        self.protocol.expire_message(id)
+    }
+
+    /// Keeps the connection working until the peer acknowledges `id` or
+    /// `timeout` passes.
+    ///
+    /// Anything received meanwhile is returned by the next call to `work`.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the message was acknowledged in time.
+    pub fn wait_delivered(&mut self, id: MessageId, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
         loop {
-            match self.receive() {
-                ReceiveResult::SomeRR(msg) => msgs.push(Ok(msg)),
-                ReceiveResult::NoneRR => break,
-                ReceiveResult::Error(e) => msgs.push(Err(e)),
-                _ => {}
+            match self.delivery_status(id) {
+                DeliveryStatus::Acked => return true,
+                DeliveryStatus::Queued | DeliveryStatus::InFlight => {}
+                DeliveryStatus::Expired | DeliveryStatus::Unknown => return false,
+            }
+
+            if Instant::now() >= deadline {
+                return false;
             }
+
+            let events = self.work();
+            self.backlog.extend(events);
+            thread::sleep(Duration::from_millis(1));
         }
-        self.send();
+    }
 
-        msgs
+    /// Starts sending everything `reader` yields as a byte stream.
+    ///
+    /// The stream is read as the receiver makes room for it, so it is never
+    /// held in memory as a whole. [`Event::StreamSent`] reports the receiver
+    /// got every byte.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use udp_connection::{send_handshake, Event};
+    /// let mut worker = send_handshake("127.0.0.1:8080".to_string(), |_| {}).unwrap();
+    /// let file = std::fs::File::open("snapshot.bin").unwrap();
+    /// let id = worker.send_stream(file).unwrap();
+    ///
+    /// loop {
+    ///     for event in worker.work() {
+    ///         if let Ok(Event::StreamSent(sent)) = event {
+    ///             assert_eq!(sent, id);
+    ///             return;
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// Fails if the connection is closing or over.
+    pub fn send_stream(
+        &mut self,
+        reader: impl Read + Send + 'static,
+    ) -> Result<StreamId, SendError> {
+        self.protocol.send_stream(reader)
     }
 
-    pub fn send_message(&mut self, msg: Box<[u8]>) {
-        let msg = Message::new(self.message_id, msg);
-        self.message_id += 1;
-        self.outgoing.push_back(msg);
+    /// Continues a stream from `offset`, typically on a new connection after
+    /// the old one broke. The receiver resumes with
+    /// [`SocketWorker::resume_recv_stream`] and learns the offset from its
+    /// [`StreamReader::offset`].
+    ///
+    /// The first `offset` bytes are read again to compute the stream hash.
+    pub fn resume_send_stream(
+        &mut self,
+        id: StreamId,
+        reader: impl Read + Seek + Send + 'static,
+        offset: u64,
+    ) -> io::Result<()> {
+        self.protocol.resume_send_stream(id, reader, offset)
     }
 
-    pub fn ping(){
-        todo!()
+    /// Returns a reader for the oldest incoming stream nobody reads yet.
+    ///
+    /// `work()` reports new streams as [`Event::StreamOpened`].
+    pub fn recv_stream(&mut self) -> Option<StreamReader<'_>> {
+        let id = self.take_recv_stream()?;
+        Some(StreamReader { worker: self, id })
     }
 
-    fn send_acc_message(&mut self, id: u64) {
-        let msg = Message::new_acc(id);
-        self.outgoing.push_front(msg);
+    /// Claims the oldest incoming stream nobody reads yet.
+    pub(crate) fn take_recv_stream(&mut self) -> Option<StreamId> {
+        self.protocol.take_recv_stream()
     }
 
-    fn receive(&mut self) -> ReceiveResult {
-        let mut buf = [0; 1024];
-        match &self.socket.recv_from(&mut buf) {
-            Ok((number_of_bytes, src_addr)) => {
-                let msg = Message::deserialize(&buf[..*number_of_bytes]);
-                println!(
-                    "Received {} bytes from {}: C({}) '{}'",
-                    number_of_bytes,
-                    src_addr,
-                    msg.check_hash(),
-                    msg
-                );
+    /// Continues receiving a stream of which `prefix` arrived before.
+    ///
+    /// `prefix` is read to the end to compute the stream hash; the reader
+    /// starts at its length.
+    pub fn resume_recv_stream(
+        &mut self,
+        id: StreamId,
+        prefix: impl Read,
+    ) -> io::Result<StreamReader<'_>> {
+        self.protocol.resume_recv_stream(id, prefix)?;
+        Ok(StreamReader { worker: self, id })
+    }
 
-                if msg.id == 0 {
-                    self.handle_ctrl(msg);
-                    return ReceiveResult::Ctrl;
-                }
+    pub(crate) fn stream_consumed(&self, id: StreamId) -> u64 {
+        self.protocol.stream_consumed(id)
+    }
 
-                if !msg.check_hash() {
-                    return ReceiveResult::Bad;
-                }
+    /// Blocking read for [`StreamReader`], working until data arrives.
+    pub(crate) fn read_stream(&mut self, id: StreamId, buf: &mut [u8]) -> io::Result<usize> {
+        let mut last_progress = Instant::now();
+        loop {
+            if let Some(result) = self.try_read_stream(id, buf) {
+                return result;
+            }
+            let state = self.state();
+            if state.is_final() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    format!("{id} ended early, the connection is {state}"),
+                ));
+            }
+
+            let offset = self.protocol.stream_offset(id);
+            let events = self.work();
+            self.backlog.extend(events);
+            let timeout = self.config().stream_timeout;
+            if self.protocol.stream_offset(id) != offset {
+                last_progress = Instant::now();
+            } else if last_progress.elapsed() > timeout {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("No data on {id} for {timeout:?}"),
+                ));
+            } else {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
 
-                self.send_acc_message(msg.id);
+    /// Copies what arrived of an incoming stream into `buf`, handing the
+    /// sender new credit as the reader frees the window.
+    ///
+    /// Returns `None` if the reader has to wait for more data.
+    pub(crate) fn try_read_stream(
+        &mut self,
+        id: StreamId,
+        buf: &mut [u8],
+    ) -> Option<io::Result<usize>> {
+        self.protocol.try_read_stream(id, buf)
+    }
 
-                if self.incoming.contains_key(&msg.id) {
-                    return ReceiveResult::Skip;
-                }
+    /// Frames of an outgoing stream the peer didn't acknowledge yet, `None`
+    /// once the stream was sent completely, aborted or cancelled.
+    pub(crate) fn send_stream_unacked(&self, id: StreamId) -> Option<usize> {
+        self.protocol.send_stream_unacked(id)
+    }
 
-                let msg = Rc::new(msg);
+    /// Forgets an incoming stream, cancelling it if it wasn't complete.
+    pub(crate) fn close_stream(&mut self, id: StreamId) {
+        self.protocol.close_stream(id);
+    }
+
+    /// Measures the round trip time to the peer.
+    ///
+    /// The answer is reported by `work()` as [`Event::Pong`]. Pings are not
+    /// retransmitted, a lost one is never answered.
+    pub fn ping(&mut self) {
+        self.protocol.ping(Instant::now());
+    }
 
-                _ = self.incoming.insert(msg.id, msg.clone());
-                (self.notify)(&msg.data);
+    /// Hands one batch of datagrams from the socket to the protocol.
+    ///
+    /// # Returns
+    ///
+    /// `false` once the socket has nothing more.
+    fn receive(&mut self) -> bool {
+        match batch_io::recv_batch(
+            &self.socket,
+            &mut self.recv_batch,
+            self.protocol.config().batch_syscalls,
+        ) {
+            Ok(count) => {
+                let now = Instant::now();
+                for i in 0..count {
+                    let (buf, src_addr) = self.recv_batch.get(i);
+                    // The only copy of the datagram; messages in it share this buffer
+                    let datagram = Bytes::copy_from_slice(buf);
+                    self.record(Direction::Received, src_addr, &datagram);
+                    self.protocol.handle_datagram(now, datagram);
+                }
 
-                ReceiveResult::SomeRR(msg.data.to_vec().into_boxed_slice())
+                true
             }
             Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                 // No data is available right now
-                ReceiveResult::NoneRR
+                false
             }
             Err(e) => {
-                ReceiveResult::Error(format!("Error receiving from socket {e}"))
+                self.backlog.push(Err(ConnectionError::Io(e)));
+                true
             }
         }
     }
 
-    fn send(&mut self) {
-        if let Some(msg) = self.outgoing.front() {
-            println!("Sending '{}'", msg);
-            self.socket
-                .send_to(&msg.serialize(), &self.address)
-                .unwrap();
-            let msg = self.outgoing.pop_front().expect("send wtf?");
-            if msg.id != 0 {
-                self.outgoing.push_back(msg);
+    /// Sends what the protocol has due. The datagrams are handed to the
+    /// kernel together, see [`Config::batch_syscalls`].
+    fn send(&mut self, now: Instant) {
+        let batch_syscalls = self.config().batch_syscalls;
+        let transmit = self.protocol.poll_transmit(now);
+        if transmit.is_empty() {
+            return;
+        }
+
+        let Some(addr) = self.peer_addr else {
+            self.backlog.push(Err(ConnectionError::Io(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("Can't resolve address {}", self.address),
+            ))));
+            return;
+        };
+
+        // Datagrams the socket had no room for are retransmitted later anyway
+        match batch_io::send_batch(
+            &self.socket,
+            transmit.buf,
+            transmit.ends,
+            addr,
+            batch_syscalls,
+        ) {
+            Ok(sent) => {
+                if let Some((capture, local)) = &self.capture {
+                    for datagram in transmit.iter().take(sent) {
+                        if let Err(e) = capture.record(Direction::Sent, *local, addr, datagram) {
+                            self.backlog.push(Err(ConnectionError::Io(e)));
+                        }
+                    }
+                }
             }
+            Err(e) => self.backlog.push(Err(ConnectionError::Io(e))),
         }
     }
 
-    fn handle_ctrl(&mut self, msg: Message) {
-        match msg.get_control() {
-            crate::control_message::ControlMessage::Acc { id } => {
-                if let Some(rem_ind) = self.outgoing.iter().position(|i| i.id == id) {
-                    self.outgoing.remove(rem_ind);
-                }
-            } /*msg => {
-                  panic!("Unknown control message ({:?})", msg);
-              }*/
+    /// Writes a datagram to the capture, if there is one.
+    fn record(&mut self, direction: Direction, peer: SocketAddr, datagram: &[u8]) {
+        if let Some((capture, local)) = &self.capture {
+            if let Err(e) = capture.record(direction, *local, peer, datagram) {
+                self.backlog.push(Err(ConnectionError::Io(e)));
+            }
         }
     }
 }
@@ -131,19 +556,58 @@ impl Debug for SocketWorker {
         f.debug_struct("SocketWorker")
             .field("socket", &self.socket)
             .field("address", &self.address)
-            .field("outgoing", &self.outgoing.len())
-            .field("incoming", &self.incoming.len())
-            .field("notify", &self.notify)
-            .field("message_id", &self.message_id)
+            .field("protocol", &self.protocol)
             .finish()
     }
 }
 
-enum ReceiveResult {
-    SomeRR(Box<[u8]>),
-    NoneRR,
-    Ctrl,
-    Bad,
-    Skip,
-    Error(String),
+#[cfg(unix)]
+impl AsFd for SocketWorker {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.socket.as_fd()
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for SocketWorker {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+/// Registers the socket for readability. The worker still only makes
+/// progress in `work()`, which reads the socket until it would block, as
+/// edge triggered readiness wants it.
+#[cfg(all(feature = "mio", unix))]
+impl mio::event::Source for SocketWorker {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        mio::unix::SourceFd(&self.socket.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        mio::unix::SourceFd(&self.socket.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        mio::unix::SourceFd(&self.socket.as_raw_fd()).deregister(registry)
+    }
+}
+
+/// Receive buffers hold the largest datagram we would send ourselves, larger
+/// probes arrive truncated and are never acknowledged.
+fn recv_slot(config: &Config) -> usize {
+    config
+        .max_datagram_size
+        .max(config.base_datagram_size)
+        .min(MAX_DATAGRAM_SIZE)
 }
@@ -1,14 +1,29 @@
 use std::{
-    io::Error,
-    net::{SocketAddr, UdpSocket},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    time::Duration,
 };
 
-use crate::socket_worker::SocketWorker;
+use crate::{
+    capture::{Capture, Direction},
+    compression::Compression,
+    config::Config,
+    error::ConnectionError,
+    header::{WireFormat, VERSION},
+    integrity::Integrity,
+    session::{self, Session, CONFIRMATION_LEN, MAX_ISN, NONCE_LEN},
+    socket_worker::SocketWorker,
+};
 
 /// Sets up a UDP server that waits for client handshake requests.
 ///
 /// Creates a socket on the specified address, waits for a "Hello" message,
 /// then creates a dedicated communication channel with the client.
+/// Payload compression is enabled when both sides support a common algorithm.
+/// Both sides pick a random first message ID and a nonce for the session
+/// key, see [`Message::seal`](crate::Message::seal). Messages are sealed with
+/// [`Integrity::Mac`], see [`receive_handshake_with_config`] to accept other
+/// checks.
 ///
 /// # Arguments
 ///
@@ -24,31 +39,75 @@ use crate::socket_worker::SocketWorker;
 ///     |msg| println!("Received: {:?}", msg)
 /// ).expect("Failed to start server");
 /// ```
-pub fn receive_handshake(address: String, notify: fn(&[u8])) -> std::io::Result<SocketWorker> {
+pub fn receive_handshake(
+    address: String,
+    notify: fn(&[u8]),
+) -> Result<SocketWorker, ConnectionError> {
     let socket = UdpSocket::bind(&address)?;
 
-    let (new_socket, new_adr) = expect_handshake(&socket)?;
+    let (new_socket, new_adr, compression, session) =
+        expect_handshake(&socket, &Config::default())?;
 
     new_socket.set_nonblocking(true)?;
 
-    Ok(SocketWorker::new(new_socket, new_adr, notify))
+    let mut worker = SocketWorker::new(new_socket, new_adr, notify);
+    worker.set_compression(compression);
+    worker.set_session(session);
+    worker.await_peer();
+
+    Ok(worker)
 }
 
 pub fn receive_handshake_nonblocking(
     socket: &UdpSocket,
     notify: fn(&[u8]),
-) -> std::io::Result<SocketWorker> {
-    let (new_sock, new_adr) = expect_handshake(socket)?;
+) -> Result<SocketWorker, ConnectionError> {
+    let (new_sock, new_adr, compression, session) =
+        expect_handshake(socket, &Config::default())?;
+
+    new_sock.set_nonblocking(true)?;
+
+    let mut worker = SocketWorker::new(new_sock, new_adr, notify);
+    worker.set_compression(compression);
+    worker.set_session(session);
+    worker.await_peer();
+
+    Ok(worker)
+}
+
+/// Like [`receive_handshake_nonblocking`], but accepts the integrity checks
+/// of [`Config::integrity`] and returns the worker with `config` applied.
+/// The handshake is recorded to [`Config::capture`], if set.
+///
+/// The first check the client offers that `config` accepts is used, the
+/// client is turned away if there is none.
+pub fn receive_handshake_with_config(
+    socket: &UdpSocket,
+    notify: fn(&[u8]),
+    config: Config,
+) -> Result<SocketWorker, ConnectionError> {
+    let (new_sock, new_adr, compression, session) = expect_handshake(socket, &config)?;
 
     new_sock.set_nonblocking(true)?;
 
-    Ok(SocketWorker::new(new_sock, new_adr, notify))
+    let mut worker = SocketWorker::new(new_sock, new_adr, notify).with_config(config);
+    worker.set_compression(compression);
+    worker.set_session(session);
+    worker.await_peer();
+
+    Ok(worker)
 }
 
 /// Initiates a handshake with a UDP server and establishes connection.
 ///
 /// Sends "Hello" to the server, receives connection details, and creates
-/// a SocketWorker for reliable message exchange.
+/// a SocketWorker for reliable message exchange. The "Hello" lists the
+/// compression algorithms this build supports, the server answers with the
+/// one it picked, if any. Both messages carry the sender's session nonce
+/// (`n=`) and first message ID (`i=`). The "Hello" also offers the integrity
+/// checks of [`Config::integrity`] (`h=`), the server answers with the one
+/// it picked, and the header [`VERSION`] (`v=`), which the server repeats if
+/// it speaks it. Otherwise both sides use the legacy header.
 ///
 /// # Arguments
 ///
@@ -64,51 +123,186 @@ pub fn receive_handshake_nonblocking(
 ///     |msg| println!("Received: {:?}", msg)
 /// ).expect("Failed to connect");
 /// ```
-pub fn send_handshake(address: String, notify: fn(&[u8])) -> std::io::Result<SocketWorker> {
-    let sock = UdpSocket::bind("127.0.0.1:0")?;
+pub fn send_handshake(
+    address: String,
+    notify: fn(&[u8]),
+) -> Result<SocketWorker, ConnectionError> {
+    handshake(address, notify, None, Config::default())
+}
+
+/// Like [`send_handshake`], but gives up with [`ConnectionError::Timeout`]
+/// when the server doesn't answer within `timeout`.
+pub fn send_handshake_timeout(
+    address: String,
+    notify: fn(&[u8]),
+    timeout: Duration,
+) -> Result<SocketWorker, ConnectionError> {
+    handshake(address, notify, Some(timeout), Config::default())
+}
+
+/// Like [`send_handshake`], but offers the integrity checks of
+/// [`Config::integrity`] and returns the worker with `config` applied.
+/// The handshake is recorded to [`Config::capture`], if set.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use udp_connection::{send_handshake_with_config, Config, Integrity};
+/// // Trusted network, a checksum is enough
+/// let config = Config {
+///     integrity: vec![Integrity::Xxh3, Integrity::Mac],
+///     ..Config::default()
+/// };
+/// let worker = send_handshake_with_config("127.0.0.1:8080".to_string(), |_| {}, config)?;
+/// # Ok::<(), udp_connection::ConnectionError>(())
+/// ```
+pub fn send_handshake_with_config(
+    address: String,
+    notify: fn(&[u8]),
+    config: Config,
+) -> Result<SocketWorker, ConnectionError> {
+    handshake(address, notify, None, config)
+}
+
+pub(crate) fn handshake(
+    address: String,
+    notify: fn(&[u8]),
+    timeout: Option<Duration>,
+    config: Config,
+) -> Result<SocketWorker, ConnectionError> {
+    let server = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| ConnectionError::Malformed(format!("No address for '{}'", address)))?;
+    // Any interface, the route to the server picks one
+    let any: IpAddr = match server {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let sock = UdpSocket::bind(SocketAddr::new(any, 0))?;
+    sock.set_read_timeout(timeout)?;
+    let capture = config.capture.as_ref();
 
-    let buf = "Hello".as_bytes();
-    sock.send_to(buf, address)?;
+    let nonce = session::random_nonce()?;
+    let isn = session::random_isn()?;
+    let offer = config.integrity.iter().map(Integrity::name).collect::<Vec<_>>();
+    let hello = Compression::supported().iter().fold(
+        format!(
+            "Hello n={} i={isn} h={} v={VERSION}",
+            session::to_hex(&nonce),
+            offer.join(",")
+        ),
+        |hello, c| hello + " " + c.name(),
+    );
+    sock.send_to(hello.as_bytes(), server)?;
+    record(capture, Direction::Sent, &sock, server, hello.as_bytes())?;
 
-    let mut buf = [0; 20];
+    let mut buf = [0; 128];
 
-    let (number_of_bytes, server_address) = sock.recv_from(&mut buf)?;
-    let msg = String::from_utf8_lossy(&buf[..number_of_bytes]).to_string();
+    let (number_of_bytes, server_address) =
+        sock.recv_from(&mut buf).map_err(|e| match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ConnectionError::Timeout,
+            _ => ConnectionError::Io(e),
+        })?;
+    let reply = &buf[..number_of_bytes];
+    record(capture, Direction::Received, &sock, server_address, reply)?;
+    let msg = String::from_utf8_lossy(reply).to_string();
 
+    if let Some(reason) = msg.strip_prefix("Reject ") {
+        return Err(ConnectionError::HandshakeRejected(reason.to_string()));
+    }
     if !msg.starts_with("Connect port ") {
-        return Err(Error::new(
-            std::io::ErrorKind::Unsupported,
-            format!("Unknown message '{}'", msg),
-        ));
+        return Err(ConnectionError::Malformed(format!("Unknown message '{}'", msg)));
     }
 
-    let num_str = &msg[13..].trim();
+    let mut args = msg[13..].split_whitespace();
+    let num_str = args.next().unwrap_or_default();
 
     let port = match num_str.parse::<u16>() {
         Ok(num) => num,
         Err(_) => {
-            return Err(Error::new(
-                std::io::ErrorKind::Unsupported,
-                format!("Unknown port format '{}'", msg),
-            ))
+            return Err(ConnectionError::Malformed(format!(
+                "Unknown port format '{}'",
+                msg
+            )))
         }
     };
 
+    let params = session_params(args, &msg)?;
+    // Servers that predate the negotiation seal with the MAC
+    let integrity = match params.integrity.as_deref() {
+        None => Integrity::Mac,
+        Some([integrity]) => *integrity,
+        Some(_) => {
+            return Err(ConnectionError::Malformed(format!(
+                "No single integrity check in '{}'",
+                msg
+            )))
+        }
+    };
+    if !config.integrity.contains(&integrity) {
+        return Err(ConnectionError::AuthFailure(format!(
+            "Integrity check {} was not offered",
+            integrity
+        )));
+    }
+    // Servers that predate the version speak the legacy header
+    let format = match params.version {
+        None => WireFormat::Legacy,
+        Some(VERSION) => WireFormat::V1,
+        Some(version) => {
+            return Err(ConnectionError::Malformed(format!(
+                "Header version {} was not offered",
+                version
+            )))
+        }
+    };
+    let compression = match params.rest.first() {
+        None => Compression::None,
+        Some(name) => Compression::from_name(name).ok_or_else(|| {
+            ConnectionError::Malformed(format!("Unknown compression '{}'", name))
+        })?,
+    };
+
     let socket_addr = SocketAddr::new(server_address.ip(), port);
 
     sock.set_nonblocking(true)?;
 
-    Ok(SocketWorker::new(sock, socket_addr.to_string(), notify))
+    let session = Session::new(
+        &nonce,
+        &params.nonce,
+        isn,
+        params.isn,
+        integrity,
+        format,
+        &config.secret,
+    );
+    if !config.secret.is_empty() && params.confirmation != Some(session.confirmation()) {
+        return Err(ConnectionError::AuthFailure(
+            "The server doesn't know the secret".to_string(),
+        ));
+    }
+
+    let mut worker = SocketWorker::new(sock, socket_addr.to_string(), notify).with_config(config);
+    worker.set_compression(compression);
+    worker.set_session(session);
+
+    Ok(worker)
 }
 
 /// Handles server-side handshake protocol.
 ///
-/// Waits for "Hello" message, creates a new dedicated socket,
-/// and sends "Connect port {port}" response to the client.
+/// Waits for "Hello" message, creates a new dedicated socket on the IP of
+/// `sock`, and sends "Connect port {port}" response to the client. If the client
+/// offered a compression algorithm this build supports, its name is appended
+/// to the response. So is the first integrity check the client offered that
+/// is in [`Config::integrity`]; a client without one is sent "Reject" and
+/// the reason.
+/// The header version is confirmed if the client offered it.
 ///
 /// # Returns
 ///
-/// Tuple of (dedicated_socket, client_address_string)
+/// Tuple of (dedicated_socket, client_address_string, compression, session)
 ///
 /// # Examples
 ///
@@ -117,27 +311,170 @@ pub fn send_handshake(address: String, notify: fn(&[u8])) -> std::io::Result<Soc
 /// let server_socket = UdpSocket::bind("127.0.0.1:8080").unwrap();
 /// // expect_handshake waits for "Hello" and creates dedicated channel
 /// ```
-fn expect_handshake(sock: &UdpSocket) -> std::io::Result<(UdpSocket, String)> {
-    let mut buf = [0; 5];
+fn expect_handshake(
+    sock: &UdpSocket,
+    config: &Config,
+) -> Result<(UdpSocket, String, Compression, Session), ConnectionError> {
+    let capture = config.capture.as_ref();
+    let mut buf = [0; 128];
 
     let (number_of_bytes, src_addr) = sock.recv_from(&mut buf)?;
-    let msg = String::from_utf8_lossy(&buf[..number_of_bytes]).to_string();
-    println!(
-        "Received {} bytes from {}: '{}'",
-        number_of_bytes, src_addr, msg
-    );
-    if msg == "Hello" {
-        let con = UdpSocket::bind("127.0.0.1:0")?;
+    let hello = &buf[..number_of_bytes];
+    record(capture, Direction::Received, sock, src_addr, hello)?;
+    let msg = String::from_utf8_lossy(hello).to_string();
+    if config.trace {
+        eprintln!(
+            "Received {} bytes from {}: '{}'",
+            number_of_bytes, src_addr, msg
+        );
+    }
+    let mut args = msg.split_whitespace();
+    if args.next() == Some("Hello") {
+        let params = session_params(args, &msg)?;
+        // Clients that predate the negotiation seal with the MAC
+        let integrity = params
+            .integrity
+            .as_deref()
+            .unwrap_or(&[Integrity::Mac])
+            .iter()
+            .copied()
+            .find(|i| config.integrity.contains(i));
+        let Some(integrity) = integrity else {
+            let reject = b"Reject no common integrity check";
+            sock.send_to(reject, src_addr)?;
+            record(capture, Direction::Sent, sock, src_addr, reject)?;
+            return Err(ConnectionError::AuthFailure(format!(
+                "No common integrity check in '{}'",
+                msg
+            )));
+        };
+        // Clients that predate the version get the legacy header, newer
+        // ones the version we speak
+        let format = match params.version {
+            Some(version) if version >= VERSION => WireFormat::V1,
+            _ => WireFormat::Legacy,
+        };
+        let compression = params
+            .rest
+            .iter()
+            .copied()
+            .find_map(Compression::from_name)
+            .unwrap_or(Compression::None);
+
+        let nonce = session::random_nonce()?;
+        let isn = session::random_isn()?;
+
+        // Reachable wherever the client reached the handshake socket
+        let con = UdpSocket::bind(SocketAddr::new(sock.local_addr()?.ip(), 0))?;
         let port = con.local_addr()?.port();
-        let buf = format!("Connect port {}", port);
+        let mut buf = format!("Connect port {} n={} i={}", port, session::to_hex(&nonce), isn);
+        if params.integrity.is_some() {
+            buf = format!("{} h={}", buf, integrity.name());
+        }
+        if format == WireFormat::V1 {
+            buf = format!("{} v={}", buf, VERSION);
+        }
+        let session = Session::new(
+            &params.nonce,
+            &nonce,
+            isn,
+            params.isn,
+            integrity,
+            format,
+            &config.secret,
+        );
+        if !config.secret.is_empty() {
+            buf = format!("{} k={}", buf, session::to_hex(&session.confirmation()));
+        }
+        if compression != Compression::None {
+            buf = format!("{} {}", buf, compression.name());
+        }
         sock.send_to(buf.as_bytes(), src_addr)?;
+        record(capture, Direction::Sent, sock, src_addr, buf.as_bytes())?;
         //echo "Hello" | nc -u -w1 127.0.0.1 8080
 
-        return Ok((con, src_addr.to_string()));
+        Ok((con, src_addr.to_string(), compression, session))
     } else {
-        Err(Error::new(
-            std::io::ErrorKind::Unsupported,
-            format!("Unknown message '{}'", msg),
-        ))
+        Err(ConnectionError::Malformed(format!("Unknown message '{}'", msg)))
+    }
+}
+
+/// Writes a handshake datagram to `capture`, if there is one.
+fn record(
+    capture: Option<&Capture>,
+    direction: Direction,
+    sock: &UdpSocket,
+    peer: SocketAddr,
+    datagram: &[u8],
+) -> io::Result<()> {
+    match capture {
+        Some(capture) => capture.record(direction, sock.local_addr()?, peer, datagram),
+        None => Ok(()),
+    }
+}
+
+/// Session parameters of a handshake message.
+struct SessionParams<'a> {
+    nonce: [u8; NONCE_LEN],
+    /// First message ID
+    isn: u64,
+    /// Integrity checks, if the sender negotiates them
+    integrity: Option<Vec<Integrity>>,
+    /// Header version, if the sender has one
+    version: Option<u8>,
+    /// Key confirmation of a server with a secret
+    confirmation: Option<[u8; CONFIRMATION_LEN]>,
+    /// Arguments that are none of the above
+    rest: Vec<&'a str>,
+}
+
+/// Picks the session nonce (`n=`), first message ID (`i=`), integrity
+/// checks (`h=`, comma separated), header version (`v=`) and key
+/// confirmation (`k=`) out of the arguments of a handshake message. Checks
+/// this build doesn't know are left out.
+fn session_params<'a>(
+    args: impl Iterator<Item = &'a str>,
+    msg: &str,
+) -> Result<SessionParams<'a>, ConnectionError> {
+    let mut nonce = None;
+    let mut isn = None;
+    let mut integrity = None;
+    let mut version = None;
+    let mut confirmation = None;
+    let mut rest = Vec::new();
+
+    for arg in args {
+        if let Some(hex) = arg.strip_prefix("n=") {
+            nonce = session::from_hex(hex);
+        } else if let Some(num) = arg.strip_prefix("i=") {
+            // Larger ones would run into the flags of the legacy header
+            isn = num
+                .parse::<u64>()
+                .ok()
+                .filter(|isn| (1..=MAX_ISN).contains(isn));
+        } else if let Some(names) = arg.strip_prefix("h=") {
+            integrity = Some(names.split(',').filter_map(Integrity::from_name).collect());
+        } else if let Some(num) = arg.strip_prefix("v=") {
+            version = num.parse::<u8>().ok();
+        } else if let Some(hex) = arg.strip_prefix("k=") {
+            confirmation = session::from_hex(hex);
+        } else {
+            rest.push(arg);
+        }
+    }
+
+    match (nonce, isn) {
+        (Some(nonce), Some(isn)) => Ok(SessionParams {
+            nonce,
+            isn,
+            integrity,
+            version,
+            confirmation,
+            rest,
+        }),
+        _ => Err(ConnectionError::Malformed(format!(
+            "Missing session parameters in '{}'",
+            msg
+        ))),
     }
 }
@@ -0,0 +1,70 @@
+use std::fmt;
+
+/// Lifecycle of a [`SocketWorker`](crate::SocketWorker), see
+/// [`SocketWorker::state`](crate::SocketWorker::state).
+///
+/// ```text
+/// Handshaking ──> Established ──> Closing ──> Closed
+///      │               │             │
+///      └───────────────┴─────────────┴──> Closed, TimedOut
+///
+/// TimedOut ──> Handshaking, resuming the session
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnectionState {
+    /// We answered the peer's handshake but nothing arrived from it on the
+    /// new socket yet, so it may not have received the answer.
+    Handshaking,
+    /// The peer is known to be there; messages flow both ways.
+    Established,
+    /// [`SocketWorker::close`](crate::SocketWorker::close) was called. No
+    /// new messages are accepted, queued ones are still delivered.
+    Closing,
+    /// Closed by us or the peer. Nothing is sent or received anymore.
+    Closed,
+    /// Nothing arrived from the peer for
+    /// [`Config::idle_timeout`](crate::Config::idle_timeout). A
+    /// [`ReconnectingConnection`](crate::ReconnectingConnection) may try to
+    /// resume the session, going back to `Handshaking`.
+    TimedOut,
+}
+
+impl ConnectionState {
+    /// Whether `work()` may move the connection from this state to `next`.
+    pub fn can_become(self, next: ConnectionState) -> bool {
+        use ConnectionState::*;
+
+        matches!(
+            (self, next),
+            (Handshaking, Established)
+                | (Handshaking | Established, Closing)
+                | (Handshaking | Established | Closing, Closed | TimedOut)
+                | (TimedOut, Handshaking)
+        )
+    }
+
+    /// Whether messages can still be queued for sending.
+    pub fn can_send(self) -> bool {
+        matches!(
+            self,
+            ConnectionState::Handshaking | ConnectionState::Established
+        )
+    }
+
+    /// `Closed` or `TimedOut`, the connection is over unless it is resumed.
+    pub fn is_final(self) -> bool {
+        matches!(self, ConnectionState::Closed | ConnectionState::TimedOut)
+    }
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConnectionState::Handshaking => "handshaking",
+            ConnectionState::Established => "established",
+            ConnectionState::Closing => "closing",
+            ConnectionState::Closed => "closed",
+            ConnectionState::TimedOut => "timed out",
+        })
+    }
+}
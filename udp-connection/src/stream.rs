@@ -0,0 +1,422 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    io::{self, Read},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use sha2::{Digest, Sha256};
+
+use crate::socket_worker::SocketWorker;
+
+/// Bytes in front of every stream frame: stream ID, offset and kind.
+pub(crate) const FRAME_HEADER_LEN: usize = 17;
+
+/// Identifier of a byte stream, chosen by its sender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StreamId(pub u64);
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stream {}", self.0)
+    }
+}
+
+/// Payload of a message with the stream flag set.
+///
+/// `Data`, `End` and `Abort` travel from the sender of the stream to its
+/// receiver, `Credit` and `Cancel` the other way.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Frame {
+    Data { stream: u64, offset: u64, data: Bytes },
+    /// The stream is `total` bytes long and hashes to `hash`.
+    End { stream: u64, total: u64, hash: [u8; 32] },
+    /// The sender failed to read the stream.
+    Abort { stream: u64 },
+    /// The receiver accepts bytes up to `limit`.
+    Credit { stream: u64, limit: u64 },
+    /// The receiver is no longer interested.
+    Cancel { stream: u64 },
+}
+
+impl Frame {
+    pub(crate) fn encode(&self) -> Bytes {
+        let (stream, offset, kind, data): (u64, u64, u8, &[u8]) = match self {
+            Frame::Data {
+                stream,
+                offset,
+                data,
+            } => (*stream, *offset, 0, data),
+            Frame::End {
+                stream,
+                total,
+                hash,
+            } => (*stream, *total, 1, hash),
+            Frame::Abort { stream } => (*stream, 0, 2, &[]),
+            Frame::Credit { stream, limit } => (*stream, *limit, 3, &[]),
+            Frame::Cancel { stream } => (*stream, 0, 4, &[]),
+        };
+
+        let mut buf = BytesMut::with_capacity(FRAME_HEADER_LEN + data.len());
+        buf.put_u64(stream);
+        buf.put_u64(offset);
+        buf.put_u8(kind);
+        buf.put_slice(data);
+        buf.freeze()
+    }
+
+    pub(crate) fn decode(mut data: Bytes) -> Result<Frame, String> {
+        if data.len() < FRAME_HEADER_LEN {
+            return Err(format!("Stream frame of {} bytes", data.len()));
+        }
+        let stream = data.get_u64();
+        let offset = data.get_u64();
+
+        match data.get_u8() {
+            0 => Ok(Frame::Data {
+                stream,
+                offset,
+                data,
+            }),
+            1 => Ok(Frame::End {
+                stream,
+                total: offset,
+                hash: data[..]
+                    .try_into()
+                    .map_err(|_| "Stream end without hash".to_string())?,
+            }),
+            2 => Ok(Frame::Abort { stream }),
+            3 => Ok(Frame::Credit {
+                stream,
+                limit: offset,
+            }),
+            4 => Ok(Frame::Cancel { stream }),
+            kind => Err(format!("Unknown stream frame kind {kind}")),
+        }
+    }
+}
+
+/// Outgoing stream, read lazily as the receiver hands out credit.
+pub(crate) struct SendStream {
+    pub(crate) id: u64,
+    reader: Box<dyn Read + Send>,
+    /// Next byte to read
+    offset: u64,
+    /// Bytes the receiver accepts, see [`Frame::Credit`]
+    limit: u64,
+    hasher: Sha256,
+    /// Frames sent but not acknowledged yet
+    pub(crate) unacked: usize,
+    /// `End` or `Abort` was queued, nothing more to read
+    pub(crate) ended: bool,
+    pub(crate) aborted: bool,
+}
+
+impl SendStream {
+    /// A stream whose first `offset` bytes, hashed into `hasher`, the
+    /// receiver already has.
+    pub(crate) fn new(
+        id: u64,
+        reader: Box<dyn Read + Send>,
+        offset: u64,
+        hasher: Sha256,
+        window: usize,
+    ) -> SendStream {
+        SendStream {
+            id,
+            reader,
+            offset,
+            limit: offset + window as u64,
+            hasher,
+            unacked: 0,
+            ended: false,
+            aborted: false,
+        }
+    }
+
+    pub(crate) fn on_credit(&mut self, limit: u64) {
+        self.limit = self.limit.max(limit);
+    }
+
+    /// Reads the next frame the receiver has credit for.
+    ///
+    /// A reader that would block has no frame yet. Any other read error
+    /// ends the stream, the caller sends `Abort`.
+    pub(crate) fn next_frame(&mut self, max_chunk: usize) -> Option<io::Result<Frame>> {
+        if self.ended || self.offset >= self.limit {
+            return None;
+        }
+
+        let len = max_chunk.min((self.limit - self.offset) as usize);
+        let mut buf = vec![0; len];
+        let n = loop {
+            match self.reader.read(&mut buf) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // Nothing to send for now, the stream goes on
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(e) => {
+                    self.ended = true;
+                    self.aborted = true;
+                    return Some(Err(e));
+                }
+            }
+        };
+
+        if n == 0 {
+            self.ended = true;
+            return Some(Ok(Frame::End {
+                stream: self.id,
+                total: self.offset,
+                hash: self.hasher.clone().finalize().into(),
+            }));
+        }
+
+        buf.truncate(n);
+        self.hasher.update(&buf);
+        let offset = self.offset;
+        self.offset += n as u64;
+
+        Some(Ok(Frame::Data {
+            stream: self.id,
+            offset,
+            data: buf.into(),
+        }))
+    }
+}
+
+enum RecvState {
+    Open,
+    /// Every byte arrived and the hash matched
+    Finished,
+    Failed(String),
+}
+
+/// Incoming stream, reassembled in order and hashed as the bytes arrive.
+pub(crate) struct RecvStream {
+    /// Bytes received in order so far
+    offset: u64,
+    /// Bytes handed to the reader so far
+    pub(crate) consumed: u64,
+    /// Credit last given to the sender, data beyond it fails the stream
+    advertised: u64,
+    /// Data starting below it fails the stream, see [`RecvStream::opened_at`]
+    lowest: u64,
+    /// Chunks that arrived ahead of `offset`
+    pending: BTreeMap<u64, Bytes>,
+    /// In order chunks waiting for the reader
+    ready: VecDeque<Bytes>,
+    hasher: Sha256,
+    end: Option<(u64, [u8; 32])>,
+    state: RecvState,
+    /// A `StreamReader` was handed out for this stream
+    pub(crate) taken: bool,
+}
+
+impl RecvStream {
+    /// A stream whose first `offset` bytes, hashed into `hasher`, were
+    /// received before.
+    pub(crate) fn new(offset: u64, hasher: Sha256, window: usize) -> RecvStream {
+        RecvStream {
+            offset,
+            consumed: offset,
+            advertised: offset + window as u64,
+            lowest: 0,
+            pending: BTreeMap::new(),
+            ready: VecDeque::new(),
+            hasher,
+            end: None,
+            state: RecvState::Open,
+            taken: false,
+        }
+    }
+
+    /// A stream the sender opened with a frame at `first` before anybody
+    /// resumed it here. A resumed sender starts with a window past its
+    /// offset, so the window is taken to start around `first`.
+    pub(crate) fn opened_at(first: u64, window: usize) -> RecvStream {
+        RecvStream {
+            advertised: first.saturating_add(window as u64),
+            lowest: first.saturating_sub(window as u64),
+            ..RecvStream::new(0, Default::default(), window)
+        }
+    }
+
+    pub(crate) fn is_open(&self) -> bool {
+        matches!(self.state, RecvState::Open)
+    }
+
+    /// Bytes received in order so far.
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Moves the chunks of `other` that are still missing here over.
+    pub(crate) fn adopt(&mut self, other: RecvStream) {
+        for (offset, data) in other.ready.into_iter().scan(other.consumed, |at, data| {
+            let offset = *at;
+            *at += data.len() as u64;
+            Some((offset, data))
+        }) {
+            self.on_data(offset, data);
+        }
+        for (offset, data) in other.pending {
+            self.on_data(offset, data);
+        }
+        if let Some((total, hash)) = other.end {
+            self.on_end(total, hash);
+        }
+    }
+
+    pub(crate) fn on_data(&mut self, offset: u64, mut data: Bytes) {
+        if !self.is_open() {
+            return;
+        }
+        let Some(end) = offset.checked_add(data.len() as u64) else {
+            self.state = RecvState::Failed(format!("Stream data at {offset} overflows"));
+            return;
+        };
+        // Only what the sender has credit for is buffered
+        if end > self.advertised || offset < self.lowest {
+            self.state = RecvState::Failed(format!(
+                "Stream data at {offset}..{end} outside the window up to {}",
+                self.advertised
+            ));
+            self.pending.clear();
+            return;
+        }
+        if end <= self.offset {
+            return;
+        }
+        if offset < self.offset {
+            data.advance((self.offset - offset) as usize);
+        }
+        self.pending.entry(offset.max(self.offset)).or_insert(data);
+
+        while let Some(entry) = self.pending.first_entry() {
+            if *entry.key() > self.offset {
+                break;
+            }
+            let (offset, mut data) = entry.remove_entry();
+            if offset + (data.len() as u64) <= self.offset {
+                continue;
+            }
+            data.advance((self.offset - offset) as usize);
+            self.hasher.update(&data);
+            self.offset += data.len() as u64;
+            self.ready.push_back(data);
+        }
+
+        self.check_end();
+    }
+
+    pub(crate) fn on_end(&mut self, total: u64, hash: [u8; 32]) {
+        self.end = Some((total, hash));
+        self.check_end();
+    }
+
+    pub(crate) fn on_abort(&mut self) {
+        if self.is_open() {
+            self.state = RecvState::Failed("Stream aborted by the sender".to_string());
+        }
+    }
+
+    fn check_end(&mut self) {
+        let Some((total, hash)) = self.end else {
+            return;
+        };
+        if !self.is_open() || self.offset < total {
+            return;
+        }
+
+        self.state = if self.offset > total {
+            RecvState::Failed(format!("Stream longer than {total} bytes"))
+        } else if <[u8; 32]>::from(self.hasher.clone().finalize()) != hash {
+            RecvState::Failed("Stream SHA-256 mismatch".to_string())
+        } else {
+            RecvState::Finished
+        };
+    }
+
+    /// Copies buffered bytes into `buf`.
+    ///
+    /// Returns `None` if the reader has to wait for more data.
+    pub(crate) fn read(&mut self, buf: &mut [u8]) -> Option<io::Result<usize>> {
+        if let Some(chunk) = self.ready.front_mut() {
+            let n = chunk.len().min(buf.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            chunk.advance(n);
+            if chunk.is_empty() {
+                self.ready.pop_front();
+            }
+            self.consumed += n as u64;
+            return Some(Ok(n));
+        }
+
+        match &self.state {
+            RecvState::Open => None,
+            RecvState::Finished => Some(Ok(0)),
+            RecvState::Failed(e) => Some(Err(io::Error::new(io::ErrorKind::InvalidData, e.clone()))),
+        }
+    }
+
+    /// New credit for the sender, once the reader freed half the window.
+    pub(crate) fn credit(&mut self, window: usize) -> Option<u64> {
+        let limit = self.consumed + window as u64;
+        if !self.is_open() || limit < self.advertised + window as u64 / 2 {
+            return None;
+        }
+
+        self.advertised = limit;
+        Some(limit)
+    }
+}
+
+/// Reads an incoming byte stream, see [`SocketWorker::recv_stream`].
+///
+/// Reading drives the worker while waiting for data; anything else `work()`
+/// reports meanwhile is returned by the next call to `work()`. The stream
+/// ends with `Ok(0)` once every byte arrived and its SHA-256 matched, a
+/// mismatch or an aborted stream is an `InvalidData` error.
+///
+/// Dropping the reader before the end cancels the stream at the sender.
+pub struct StreamReader<'a> {
+    pub(crate) worker: &'a mut SocketWorker,
+    pub(crate) id: StreamId,
+}
+
+impl StreamReader<'_> {
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    /// Bytes of the stream read so far, including a resumed prefix.
+    ///
+    /// After a reconnect, this is where the sender has to resume.
+    pub fn offset(&self) -> u64 {
+        self.worker.stream_consumed(self.id)
+    }
+}
+
+impl Read for StreamReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.worker.read_stream(self.id, buf)
+    }
+}
+
+impl Drop for StreamReader<'_> {
+    fn drop(&mut self) {
+        self.worker.close_stream(self.id);
+    }
+}
+
+/// Hashes everything `reader` yields.
+///
+/// # Returns
+///
+/// The hasher and the number of bytes read.
+pub(crate) fn hash_prefix(mut reader: impl Read) -> io::Result<(Sha256, u64)> {
+    let mut hasher = Sha256::new();
+    let len = io::copy(&mut reader, &mut hasher)?;
+    Ok((hasher, len))
+}
@@ -1,4 +1,8 @@
 use super::*;
+use crate::delivery::ExpiredIds;
+use crate::replay::{ReplayWindow, Verdict};
+use crate::session::Session;
+use crate::stream::RecvStream;
 
 #[test]
 fn test_new() {
@@ -6,7 +10,7 @@ fn test_new() {
     let message = Message::new(1, data.clone());
     
     assert_eq!(message.id, 1);
-    assert_eq!(message.data, data);
+    assert_eq!(&message.data[..], &data[..]);
     assert_eq!(message.hash.len(), 32); // SHA-256 produces 32 bytes
 }
 
@@ -17,11 +21,11 @@ fn test_deserialize() {
     buffer.extend_from_slice(&[0u8; 32]); // dummy hash
     buffer.extend_from_slice(b"test");
     
-    let message = Message::deserialize(&buffer);
+    let message = Message::deserialize(buffer);
     
     assert_eq!(message.id, 100);
     assert_eq!(message.hash.len(), 32);
-    assert_eq!(message.data, b"test".to_vec().into_boxed_slice());
+    assert_eq!(&message.data[..], b"test");
 }
 
 #[test]
@@ -45,7 +49,8 @@ fn test_display() {
 #[test]
 fn test_serialize_format() {
     let data = b"test".to_vec().into_boxed_slice();
-    let message = Message::new(0x123456789ABCDEF0, data);
+    let mut message = Message::new(0x123456789ABCDEF0, data);
+    message.format = WireFormat::Legacy;
     let serialized = message.serialize();
     
     // Check total length: 8 (id) + 32 (hash) + 4 (data) = 44 bytes
@@ -64,6 +69,97 @@ fn test_serialize_format() {
     assert_eq!(data_bytes, b"test");
 }
 
+#[test]
+fn test_serialize_v1_format() {
+    let mut message = Message::new(300, &b"test"[..]);
+    message.fragment = true;
+    message.unreliable = true;
+    message.connection_id = Some(0xDEADBEEF);
+    message.seal_with(Integrity::Crc32c, &[0; 32]);
+    let serialized = message.serialize();
+
+    // version, type, flags, connection ID, 2 byte ID, 4 byte tag, data
+    assert_eq!(serialized.len(), 3 + 4 + 2 + 4 + 4);
+    assert_eq!(&serialized[..3], &[VERSION, PacketType::Data as u8, 0b0101_0101]);
+    assert_eq!(&serialized[3..7], &0xDEADBEEFu32.to_be_bytes());
+    assert_eq!(&serialized[7..9], &[0xAC, 0x02]);
+    assert_eq!(&serialized[13..], b"test");
+
+    let received = Message::deserialize(serialized);
+    assert_eq!(received.id, 300);
+    assert_eq!(received.format, WireFormat::V1);
+    assert!(received.fragment && received.unreliable && !received.stream);
+    assert_eq!(received.connection_id, Some(0xDEADBEEF));
+    assert!(received.check_seal_with(Integrity::Crc32c, &[0; 32]));
+
+    // The flags are covered by the tag
+    let mut flipped = received.clone();
+    flipped.unreliable = false;
+    assert!(!flipped.check_seal_with(Integrity::Crc32c, &[0; 32]));
+}
+
+#[test]
+fn test_deserialize_rejects_bad_headers() {
+    let valid = Message::new(1, &b"x"[..]).serialize();
+
+    let mut version = valid.to_vec();
+    version[0] = 2;
+    assert!(Message::try_deserialize(version).is_err());
+
+    let mut kind = valid.to_vec();
+    kind[1] = 42;
+    assert!(Message::try_deserialize(kind).is_err());
+
+    let mut flags = valid.to_vec();
+    flags[2] |= 0x80;
+    assert!(Message::try_deserialize(flags).is_err());
+
+    // An ID spelled with more bytes than needed
+    let overlong = [&[VERSION, 0, 0, 0x81, 0x00][..], &[0; 32]].concat();
+    assert!(Message::try_deserialize(overlong).is_err());
+
+    assert!(Message::try_deserialize(valid.slice(..20)).is_err());
+    assert!(Message::try_deserialize(valid).is_ok());
+}
+
+#[test]
+fn test_legacy_header_is_still_understood() {
+    let key = [3; 32];
+    let mut data = Message::new(77, &b"old"[..]);
+    data.format = WireFormat::Legacy;
+    data.seal(&key);
+    let serialized = data.serialize();
+    assert_eq!(&serialized[..8], &77u64.to_be_bytes());
+
+    let received = Message::deserialize(serialized);
+    assert_eq!(received.format, WireFormat::Legacy);
+    assert_eq!(received.id, 77);
+    assert!(received.check_seal(&key));
+
+    // Control messages put their type after the tag
+    let mut ack = Message::new_acc(9);
+    ack.format = WireFormat::Legacy;
+    let serialized = ack.serialize();
+    assert_eq!(&serialized[..40], &[0; 40]);
+    assert_eq!(serialized[40], PacketType::Ack as u8);
+    match Message::deserialize(serialized).get_control() {
+        ControlMessage::Acc { id } => assert_eq!(id, 9),
+        control => panic!("Expected ACK, got {:?}", control),
+    }
+
+    let mut batch = bytes::BytesMut::new();
+    Message::begin_batch_for(&mut batch, WireFormat::Legacy, 8);
+    data.serialize_batched_into(&mut batch);
+    assert_eq!(batch[16], PacketType::Batch as u8);
+    match Message::deserialize(batch.freeze()).get_control() {
+        ControlMessage::Batch { messages } => {
+            assert_eq!(messages.len(), 1);
+            assert!(messages[0].check_seal(&key));
+        }
+        control => panic!("Expected batch, got {:?}", control),
+    }
+}
+
 #[test]
 fn test_serialize_deserialize_roundtrip() {
     let original_data = b"Round trip test data".to_vec().into_boxed_slice();
@@ -73,7 +169,7 @@ fn test_serialize_deserialize_roundtrip() {
     let serialized = original_message.serialize();
     
     // Deserialize it back
-    let deserialized_message = Message::deserialize(&serialized);
+    let deserialized_message = Message::deserialize(serialized.clone());
     
     // Verify all fields match
     assert_eq!(original_message.id, deserialized_message.id);
@@ -91,9 +187,9 @@ fn test_serialize_empty_data() {
     let message = Message::new(0, data);
     
     let serialized = message.serialize();
-    assert_eq!(serialized.len(), 40); // 8 + 32 + 0
+    assert_eq!(serialized.len(), 36); // 3 + 1 + 32 + 0
     
-    let deserialized = Message::deserialize(&serialized);
+    let deserialized = Message::deserialize(serialized.clone());
     assert_eq!(deserialized.data.len(), 0);
     assert!(deserialized.check_hash());
 }
@@ -104,10 +200,2149 @@ fn test_serialize_max_size_data() {
     let message = Message::new(1, data);
     
     let serialized = message.serialize();
-    assert_eq!(serialized.len(), 540); // 8 + 32 + 500
+    assert_eq!(serialized.len(), 536); // 3 + 1 + 32 + 500
     
     // Verify data section contains the expected pattern
-    let data_bytes = &serialized[40..];
+    let data_bytes = &serialized[36..];
     assert_eq!(data_bytes.len(), 500);
     assert!(data_bytes.iter().all(|&b| b == 0x42));
 }
+
+#[test]
+fn test_batch_roundtrip() {
+    let first = Message::new(1, b"first".to_vec().into_boxed_slice());
+    let second = Message::new(2, b"second".to_vec().into_boxed_slice());
+    let batch = Message::new_batch(&[
+        first.serialize(),
+        Message::new_acc(9).serialize(),
+        second.serialize(),
+    ]);
+
+    let deserialized = Message::deserialize(batch.serialize());
+
+    match deserialized.get_control() {
+        ControlMessage::Batch { messages } => {
+            assert_eq!(messages.len(), 3);
+            assert_eq!(messages[0].id, 1);
+            assert!(messages[0].check_hash());
+            assert_eq!(&messages[0].data[..], b"first");
+            assert_eq!(messages[1].id, 0);
+            assert_eq!(messages[2].id, 2);
+            assert!(messages[2].check_hash());
+            assert_eq!(&messages[2].data[..], b"second");
+        }
+        control => panic!("Expected batch, got {:?}", control),
+    }
+}
+
+/// Two workers talking over loopback, with a config for each side.
+struct Pair {
+    client: Config,
+    server: Config,
+}
+
+impl Pair {
+    fn new() -> Pair {
+        Pair {
+            client: Config::default(),
+            server: Config::default(),
+        }
+    }
+
+    /// Both sides use `config`.
+    fn with_config(self, config: Config) -> Pair {
+        Pair {
+            client: config.clone(),
+            server: config,
+        }
+    }
+
+    fn with_client_config(self, client: Config) -> Pair {
+        Pair { client, ..self }
+    }
+
+    fn with_server_config(self, server: Config) -> Pair {
+        Pair { server, ..self }
+    }
+
+    /// Points two sockets at each other, without a handshake.
+    fn connect(self) -> (SocketWorker, SocketWorker) {
+        let a = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        a.set_nonblocking(true).unwrap();
+        b.set_nonblocking(true).unwrap();
+        let a_addr = a.local_addr().unwrap().to_string();
+        let b_addr = b.local_addr().unwrap().to_string();
+
+        (
+            SocketWorker::new(a, b_addr, |_| {}).with_config(self.client),
+            SocketWorker::new(b, a_addr, |_| {}).with_config(self.server),
+        )
+    }
+
+    /// Runs both sides of a handshake, returning how it went for the client
+    /// and for the server.
+    fn try_handshake(
+        self,
+    ) -> (
+        Result<SocketWorker, ConnectionError>,
+        Result<SocketWorker, ConnectionError>,
+    ) {
+        let listener = std::sync::Arc::new(std::net::UdpSocket::bind("127.0.0.1:0").unwrap());
+        let address = listener.local_addr().unwrap().to_string();
+        let server = accept(&listener, self.server);
+        let client = send_handshake_with_config(address, |_| {}, self.client);
+        (client, server.join().unwrap())
+    }
+
+    fn handshake(self) -> (SocketWorker, SocketWorker) {
+        let (client, server) = self.try_handshake();
+        (client.unwrap(), server.unwrap())
+    }
+
+    /// A client with short timeouts, and the server worker its handshake
+    /// created on `listener`.
+    fn reconnecting(
+        self,
+        listener: &std::sync::Arc<std::net::UdpSocket>,
+    ) -> (ReconnectingConnection, SocketWorker) {
+        let address = listener.local_addr().unwrap().to_string();
+        let server = accept(listener, self.server);
+        let policy = ReconnectPolicy {
+            resume_timeout: std::time::Duration::from_millis(100),
+            initial_backoff: std::time::Duration::from_millis(10),
+            ..ReconnectPolicy::default()
+        };
+        let client = ReconnectingConnection::connect(address, |_| {}, policy)
+            .unwrap()
+            .with_config(self.client);
+
+        (client, server.join().unwrap().unwrap())
+    }
+}
+
+/// Answers one handshake on `listener` on another thread.
+fn accept(
+    listener: &std::sync::Arc<std::net::UdpSocket>,
+    config: Config,
+) -> std::thread::JoinHandle<Result<SocketWorker, ConnectionError>> {
+    let listener = listener.clone();
+    std::thread::spawn(move || receive_handshake_with_config(&listener, |_| {}, config))
+}
+
+/// How long tests wait for the network before giving up.
+const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Calls `done`, which works whatever the test needs worked, until it holds
+/// or `timeout` passed.
+///
+/// # Returns
+///
+/// `false` on timeout.
+fn drive_until(mut done: impl FnMut() -> bool, timeout: std::time::Duration) -> bool {
+    let end = std::time::Instant::now() + timeout;
+    while !done() {
+        if std::time::Instant::now() >= end {
+            return false;
+        }
+        std::thread::sleep(std::time::Duration::from_micros(200));
+    }
+    true
+}
+
+fn messages(events: Vec<Result<Event, ConnectionError>>) -> Vec<bytes::Bytes> {
+    events
+        .into_iter()
+        .filter_map(|e| match e {
+            Ok(Event::Message(msg)) => Some(msg),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn test_small_messages_share_one_datagram() {
+    let (mut sender, mut receiver) = Pair::new().connect();
+
+    for i in 0..5 {
+        sender.send_message(format!("msg {i}").into_bytes().into_boxed_slice()).unwrap();
+    }
+    // A single tick sends a single datagram.
+    sender.work();
+
+    let mut received = Vec::new();
+    let done = drive_until(
+        || {
+            received.extend(messages(receiver.work()));
+            received.len() == 5
+        },
+        TIMEOUT,
+    );
+    assert!(done);
+    let received: Vec<_> = received
+        .iter()
+        .map(|m| String::from_utf8_lossy(m).to_string())
+        .collect();
+    assert_eq!(received, ["msg 0", "msg 1", "msg 2", "msg 3", "msg 4"]);
+}
+
+#[test]
+fn test_compressed_flag_roundtrip() {
+    let message = Message::new_compressed(7, b"packed".to_vec().into_boxed_slice());
+    let serialized = message.serialize();
+
+    // The flag travels in the flags byte of the header
+    assert_eq!(serialized[2] & 0x02, 0x02);
+
+    let deserialized = Message::deserialize(serialized.clone());
+    assert_eq!(deserialized.id, 7);
+    assert!(deserialized.compressed);
+    assert!(deserialized.check_hash());
+
+    // Dropping the flag in transit breaks the hash
+    let mut tampered = serialized.to_vec();
+    tampered[2] &= !0x02;
+    assert!(!Message::deserialize(tampered).check_hash());
+}
+
+#[test]
+fn test_compression_none_never_compresses() {
+    assert!(Compression::None.compress(&[b'a'; 400]).is_none());
+    assert!(Compression::None.decompress(b"anything").is_err());
+}
+
+#[cfg(feature = "lz4")]
+#[test]
+fn test_lz4_compression_roundtrip() {
+    let data = br#"{"key":"A","client_id":1,"version":3,"value":"AAAAAAAAAAAAAAAAAAAAAAAA"}"#;
+    let compressed = Compression::Lz4.compress(data).expect("repetitive JSON should shrink");
+    assert!(compressed.len() < data.len());
+    assert_eq!(&Compression::Lz4.decompress(&compressed).unwrap()[..], &data[..]);
+
+    // Incompressible payloads are left alone
+    assert!(Compression::Lz4.compress(b"xyz").is_none());
+}
+
+#[test]
+fn test_handshake_negotiates_compression() {
+    let (client, server) = Pair::new().handshake();
+    let expected = Compression::supported()
+        .first()
+        .copied()
+        .unwrap_or(Compression::None);
+
+    assert_eq!(client.compression(), expected);
+    assert_eq!(server.compression(), expected);
+}
+
+#[test]
+fn test_integrity_checks_roundtrip() {
+    let key = [7; 32];
+    for &integrity in Integrity::supported() {
+        let mut message = Message::new_compressed(5, &b"payload"[..]);
+        message.seal_with(integrity, &key);
+        let serialized = message.serialize();
+        assert_eq!(serialized.len(), 3 + 1 + integrity.tag_len() + 7, "{integrity}");
+
+        let received = Message::deserialize(serialized.clone());
+        assert_eq!(received.id, 5);
+        assert!(received.compressed);
+        assert!(received.check_seal_with(integrity, &key), "{integrity}");
+
+        // Every other check has a different tag length or value
+        for &other in Integrity::supported() {
+            if other != integrity {
+                assert!(!received.check_seal_with(other, &key), "{integrity} as {other}");
+            }
+        }
+
+        if integrity != Integrity::None {
+            let mut corrupted = serialized.to_vec();
+            *corrupted.last_mut().unwrap() ^= 1;
+            assert!(!Message::deserialize(corrupted).check_seal_with(integrity, &key));
+        }
+    }
+}
+
+fn integrity_config(integrity: &[Integrity]) -> Config {
+    Config {
+        integrity: integrity.to_vec(),
+        trace: false,
+        ..Config::default()
+    }
+}
+
+#[test]
+fn test_handshake_negotiates_integrity() {
+    // The client's preference wins among the checks the server accepts
+    let (mut client, mut server) = Pair::new()
+        .with_client_config(integrity_config(&[
+            Integrity::Mac,
+            Integrity::Xxh3,
+            Integrity::Crc32c,
+        ]))
+        .with_server_config(integrity_config(&[Integrity::Crc32c, Integrity::Xxh3]))
+        .handshake();
+
+    assert_eq!(client.integrity(), Integrity::Xxh3);
+    assert_eq!(server.integrity(), Integrity::Xxh3);
+    assert_eq!(client.max_payload(), client.mtu() - 21);
+
+    client.send_message(b"tagged with xxh3".to_vec()).unwrap();
+    client.work();
+    let mut received = Vec::new();
+    let done = drive_until(
+        || {
+            received.extend(messages(server.work()));
+            !received.is_empty()
+        },
+        TIMEOUT,
+    );
+    assert!(done);
+    assert_eq!(received, [&b"tagged with xxh3"[..]]);
+}
+
+#[test]
+fn test_handshake_negotiates_wire_format() {
+    let (client, server) = Pair::new().handshake();
+    assert_eq!(client.wire_format(), WireFormat::V1);
+    assert_eq!(server.wire_format(), WireFormat::V1);
+
+    // A server that predates the version answers without one
+    let listener = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let server = std::thread::spawn(move || {
+        let mut buf = [0; 128];
+        let (_, client) = listener.recv_from(&mut buf).unwrap();
+        let con = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = con.local_addr().unwrap().port();
+        let connect = format!("Connect port {port} n={} i=5", session::to_hex(&[1; 16]));
+        listener.send_to(connect.as_bytes(), client).unwrap();
+
+        // Skip the path MTU probes
+        loop {
+            let len = con.recv(&mut buf).unwrap();
+            let msg = Message::deserialize(buf[..len].to_vec());
+            if !msg.is_control() {
+                return msg;
+            }
+        }
+    });
+    let mut client = send_handshake_with_config(address, |_| {}, Config::default()).unwrap();
+    assert_eq!(client.wire_format(), WireFormat::Legacy);
+
+    client.send_message(b"old style".to_vec()).unwrap();
+    client.work();
+    let datagram = server.join().unwrap();
+    assert_eq!(datagram.format, WireFormat::Legacy);
+    assert_eq!(&datagram.data[..], b"old style");
+}
+
+#[test]
+fn test_handshake_rejects_ids_outside_the_isn_range() {
+    for isn in [0, (1u64 << 31) + 1, 1 << 56] {
+        let listener = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let mut buf = [0; 128];
+            let (_, client) = listener.recv_from(&mut buf).unwrap();
+            let connect = format!(
+                "Connect port {} n={} i={isn}",
+                listener.local_addr().unwrap().port(),
+                session::to_hex(&[1; 16])
+            );
+            listener.send_to(connect.as_bytes(), client).unwrap();
+        });
+
+        let error = send_handshake_with_config(address, |_| {}, Config::default()).unwrap_err();
+        assert!(matches!(error, ConnectionError::Malformed(_)), "{isn}: {error}");
+        server.join().unwrap();
+    }
+}
+
+#[test]
+fn test_handshake_without_common_integrity_is_rejected() {
+    let (client, server) = Pair::new()
+        .with_client_config(integrity_config(&[Integrity::None]))
+        .with_server_config(integrity_config(&[Integrity::Mac]))
+        .try_handshake();
+    let error = client.unwrap_err();
+
+    assert!(matches!(error, ConnectionError::HandshakeRejected(_)));
+    assert!(matches!(server, Err(ConnectionError::AuthFailure(_))));
+    let error = std::io::Error::from(error);
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionRefused);
+}
+
+#[test]
+fn test_handshake_with_silent_server_times_out() {
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = silent.local_addr().unwrap().to_string();
+
+    let timeout = std::time::Duration::from_millis(50);
+    let error = send_handshake_timeout(address, |_| {}, timeout).unwrap_err();
+
+    assert!(matches!(error, ConnectionError::Timeout));
+    assert!(silent.recv(&mut [0; 128]).is_ok());
+}
+
+#[test]
+fn test_probe_has_requested_size() {
+    let probe = Message::new_probe(1400);
+    let serialized = probe.serialize();
+    assert_eq!(serialized.len(), 1400);
+
+    match Message::deserialize(serialized.clone()).get_control() {
+        ControlMessage::Probe { size, received } => {
+            assert_eq!(size, 1400);
+            assert_eq!(received, 1400);
+        }
+        control => panic!("Expected probe, got {:?}", control),
+    }
+}
+
+#[test]
+fn test_mtu_discovery_bisects_after_lost_probes() {
+    use std::time::{Duration, Instant};
+
+    let mut mtu = mtu::MtuDiscovery::new(1024, 1472, true);
+    let mut now = Instant::now();
+    assert_eq!(mtu.mtu(), 1024);
+
+    // The largest size is tried first, and retried before giving up on it
+    assert_eq!(mtu.poll(now), Some(1472));
+    for _ in 0..2 {
+        now += Duration::from_secs(1);
+        assert_eq!(mtu.poll(now), Some(1472));
+    }
+
+    now += Duration::from_secs(1);
+    let size = mtu.poll(now).unwrap();
+    assert!(size > 1024 && size < 1472);
+
+    mtu.on_probe_ack(size);
+    assert_eq!(mtu.mtu(), size);
+}
+
+#[test]
+fn test_mtu_probing_disabled_uses_max_size() {
+    let mtu = mtu::MtuDiscovery::new(1024, 9000, false);
+    assert_eq!(mtu.mtu(), 9000);
+}
+
+#[test]
+fn test_workers_discover_loopback_mtu() {
+    let config = Config {
+        max_datagram_size: 9000,
+        ..Config::default()
+    };
+    let (mut sender, mut receiver) = Pair::new().with_config(config).connect();
+    assert_eq!(sender.mtu(), 1024);
+
+    let done = drive_until(
+        || {
+            sender.work();
+            receiver.work();
+            sender.mtu() == 9000
+        },
+        TIMEOUT,
+    );
+
+    assert!(done);
+    // Room for the longest ID
+    assert_eq!(sender.max_payload(), 9000 - 3 - 10 - 32);
+}
+
+#[test]
+fn test_delivery_status_and_wait_delivered() {
+    let (mut sender, mut receiver) = Pair::new().connect();
+
+    let id = sender.send_message(b"replicate me".to_vec().into_boxed_slice()).unwrap();
+    assert_eq!(sender.delivery_status(id), DeliveryStatus::Queued);
+    assert_eq!(sender.delivery_status(MessageId(42)), DeliveryStatus::Unknown);
+
+    sender.work();
+    assert_eq!(sender.delivery_status(id), DeliveryStatus::InFlight);
+
+    // Receiving the message also sends the ACK back
+    assert!(drive_until(|| !messages(receiver.work()).is_empty(), TIMEOUT));
+
+    assert!(sender.wait_delivered(id, TIMEOUT));
+    assert_eq!(sender.delivery_status(id), DeliveryStatus::Acked);
+
+    // The delivery event is kept for the next call to work
+    let delivered: Vec<_> = sender
+        .work()
+        .into_iter()
+        .filter_map(|e| match e.unwrap() {
+            Event::Delivered(id) => Some(id),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(delivered, [id]);
+}
+
+/// The messages `side` reported delivered.
+fn delivered(sim: &sim::Simulation, side: sim::Side) -> Vec<MessageId> {
+    sim.events(side)
+        .iter()
+        .filter_map(|(_, e)| match e {
+            Ok(Event::Delivered(id)) => Some(*id),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn test_full_queue_rejects_until_acked() {
+    use sim::Side;
+    use std::time::Duration;
+
+    let config = Config {
+        max_queued_messages: Some(2),
+        max_queued_bytes: Some(10),
+        queue_policy: QueuePolicy::Reject,
+        ..sim_config()
+    };
+    let mut sim = sim::Simulation::new(config, sim::Link::default(), 6);
+    let sender = sim.endpoint_mut(Side::Client);
+
+    let first = sender.send_message(&b"four"[..]).unwrap();
+    assert_eq!(sender.send_message(&b"seven!!"[..]), Err(SendError::QueueFull));
+    let second = sender.send_message(&b"four"[..]).unwrap();
+    assert_eq!(sender.send_message(&b"1"[..]), Err(SendError::QueueFull));
+    assert_eq!(sender.queue_depth(), QueueDepth { messages: 2, bytes: 8 });
+
+    let done = sim.run_until(Duration::from_secs(10), |sim| {
+        delivered(sim, Side::Client).len() == 2
+    });
+    assert!(done);
+    let mut acked = delivered(&sim, Side::Client);
+    acked.sort();
+    assert_eq!(acked, [first, second]);
+    let sender = sim.endpoint_mut(Side::Client);
+    assert_eq!(sender.queue_depth(), QueueDepth::default());
+    assert!(sender.send_message(&b"room again"[..]).is_ok());
+}
+
+#[test]
+fn test_drop_oldest_expires_the_oldest_message() {
+    use sim::Side;
+    use std::time::Duration;
+
+    let config = Config {
+        max_queued_messages: Some(2),
+        queue_policy: QueuePolicy::DropOldest,
+        ..sim_config()
+    };
+    let mut sim = sim::Simulation::new(config, sim::Link::default(), 7);
+    let sender = sim.endpoint_mut(Side::Client);
+
+    let first = sender.send_message(&b"first"[..]).unwrap();
+    let second = sender.send_message(&b"second"[..]).unwrap();
+    let third = sender.send_message(&b"third"[..]).unwrap();
+    assert_eq!(sender.delivery_status(first), DeliveryStatus::Expired);
+    assert_eq!(sender.queue_depth().messages, 2);
+
+    // The placeholder is acknowledged too, but not reported
+    let done = sim.run_until(Duration::from_secs(10), |sim| {
+        format!("{:?}", sim.endpoint(Side::Client)).contains("outgoing: 0,")
+    });
+    assert!(done);
+    let mut acked = delivered(&sim, Side::Client);
+    acked.sort();
+    assert_eq!(acked, [second, third]);
+    let received: Vec<_> = sim
+        .events(Side::Server)
+        .iter()
+        .filter_map(|(_, e)| match e {
+            Ok(Event::Message(msg)) => Some(msg.clone()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(received, [&b"second"[..], &b"third"[..]]);
+    assert_eq!(sim.endpoint(Side::Client).delivery_status(first), DeliveryStatus::Expired);
+    let replay_base = format!("replay_base: {},", third.0 + 1);
+    assert!(format!("{:?}", sim.endpoint(Side::Server)).contains(&replay_base));
+}
+
+#[test]
+fn test_blocking_send_waits_for_acks() {
+    let config = Config {
+        max_queued_messages: Some(1),
+        queue_policy: QueuePolicy::Block,
+        ..Config::default()
+    };
+    let (mut sender, mut receiver) = Pair::new().with_client_config(config).connect();
+
+    let peer = std::thread::spawn(move || {
+        let mut received = Vec::new();
+        drive_until(
+            || {
+                received.extend(messages(receiver.work()));
+                received.len() == 3
+            },
+            TIMEOUT,
+        );
+        received
+    });
+    for msg in [&b"one"[..], b"two", b"three"] {
+        sender.send_message(msg.to_vec()).unwrap();
+        assert!(sender.queue_depth().messages <= 1);
+    }
+    let drained = drive_until(
+        || {
+            sender.work();
+            sender.queue_depth().messages == 0
+        },
+        TIMEOUT,
+    );
+    assert!(drained);
+
+    assert_eq!(peer.join().unwrap(), [&b"one"[..], b"two", b"three"]);
+}
+
+#[test]
+fn test_connection_runs_worker_in_background() {
+    let (a, b) = Pair::new().connect();
+    let a = Connection::spawn(a);
+    let b = Connection::spawn(b);
+
+    let senders: Vec<_> = (0..3)
+        .map(|i| {
+            let sender = a.sender();
+            std::thread::spawn(move || {
+                sender
+                    .send(format!("from thread {i}").into_bytes().into_boxed_slice())
+                    .unwrap()
+            })
+        })
+        .collect();
+    let ids: Vec<_> = senders.into_iter().map(|t| t.join().unwrap()).collect();
+
+    let mut received = Vec::new();
+    while received.len() < 3 {
+        let event = b
+            .receiver()
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .unwrap();
+        if let Event::Message(msg) = event.unwrap() {
+            received.push(String::from_utf8(msg.to_vec()).unwrap());
+        }
+    }
+    received.sort();
+    assert_eq!(received, ["from thread 0", "from thread 1", "from thread 2"]);
+
+    let mut delivered = Vec::new();
+    while delivered.len() < 3 {
+        let event = a
+            .receiver()
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .unwrap();
+        if let Event::Delivered(id) = event.unwrap() {
+            delivered.push(id);
+        }
+    }
+    delivered.sort();
+    let mut ids = ids;
+    ids.sort();
+    assert_eq!(delivered, ids);
+
+    let sender = a.sender();
+    let worker = a.shutdown();
+    assert_eq!(worker.delivery_status(ids[0]), DeliveryStatus::Acked);
+    assert!(sender.send(b"too late".to_vec().into_boxed_slice()).is_err());
+    drop(b);
+}
+
+#[test]
+fn test_pacer_spaces_datagrams() {
+    use std::time::{Duration, Instant};
+
+    let start = Instant::now();
+    let mut pacer = pacer::Pacer::new(Some(1000), 1000, start);
+
+    assert!(pacer.can_send(start));
+    pacer.on_sent(1200);
+    assert!(!pacer.can_send(start));
+    assert!(!pacer.can_send(start + Duration::from_millis(100)));
+    assert!(pacer.can_send(start + Duration::from_millis(300)));
+
+    pacer.on_sent(1000);
+    pacer.set_rate(None, 1000, start + Duration::from_millis(300));
+    assert!(pacer.can_send(start + Duration::from_millis(300)));
+}
+
+#[test]
+fn test_rate_limit_caps_datagrams_per_tick() {
+    let config = Config {
+        send_window: 100,
+        mtu_probing: false,
+        max_datagram_size: 1024,
+        rate_limit: Some(1_000),
+        burst: 2_000,
+        ..Config::default()
+    };
+    let (mut sender, _receiver) = Pair::new().with_client_config(config).connect();
+
+    for _ in 0..20 {
+        sender.send_message(vec![0u8; 900].into_boxed_slice()).unwrap();
+    }
+    sender.work();
+    let in_flight = (1..=20)
+        .filter(|&id| sender.delivery_status(MessageId(id)) == DeliveryStatus::InFlight)
+        .count();
+    // 2000 bytes of burst let at most three 940 byte datagrams through
+    assert!((1..=3).contains(&in_flight), "{in_flight} datagrams sent");
+
+    sender.set_rate_limit(None, 2_000);
+    sender.work();
+    assert!((1..=20).all(|id| sender.delivery_status(MessageId(id)) == DeliveryStatus::InFlight));
+}
+
+#[test]
+fn test_deserialize_shares_datagram_buffer() {
+    let datagram = Message::new(5, &b"no copies"[..]).serialize();
+    let message = Message::deserialize(datagram.clone());
+
+    assert_eq!(message.data.as_ptr(), datagram[36..].as_ptr());
+
+    let batch = Message::new_batch(&[datagram.clone(), datagram]).serialize();
+    match Message::deserialize(batch.clone()).get_control() {
+        ControlMessage::Batch { messages } => {
+            let inner = &batch[4 + 2 + 36..];
+            assert_eq!(messages[0].data.as_ptr(), inner.as_ptr());
+        }
+        control => panic!("Expected batch, got {:?}", control),
+    }
+}
+
+#[test]
+fn test_batched_syscalls_move_a_window_of_datagrams() {
+    for batch_syscalls in [true, false] {
+        let config = Config {
+            send_window: 10,
+            mtu_probing: false,
+            max_datagram_size: 1024,
+            batch_syscalls,
+            ..Config::default()
+        };
+        let (mut sender, mut receiver) = Pair::new().with_config(config).connect();
+
+        // Each message fills a datagram of its own
+        for i in 0..10u8 {
+            sender.send_message(vec![i; 900].into_boxed_slice()).unwrap();
+        }
+        sender.work();
+        assert!((1..=10).all(|id| sender.delivery_status(MessageId(id)) == DeliveryStatus::InFlight));
+
+        let mut received = Vec::new();
+        drive_until(
+            || {
+                received.extend(messages(receiver.work()));
+                received.len() == 10
+            },
+            TIMEOUT,
+        );
+        let mut firsts: Vec<u8> = received.iter().map(|msg| msg[0]).collect();
+        firsts.sort();
+        assert_eq!(firsts, (0..10).collect::<Vec<_>>(), "batch_syscalls: {batch_syscalls}");
+
+        let acked = drive_until(
+            || {
+                sender.work();
+                (1..=10).all(|id| sender.delivery_status(MessageId(id)) == DeliveryStatus::Acked)
+            },
+            TIMEOUT,
+        );
+        assert!(acked);
+    }
+}
+
+#[test]
+fn test_capture_records_both_directions() {
+    let path = std::env::temp_dir().join(format!("udpc-capture-{}.udpc", std::process::id()));
+    let capture = Capture::create(&path).unwrap();
+
+    let (sender, receiver) = Pair::new().connect();
+    let mut sender = sender.with_capture(capture.clone());
+    let mut receiver = receiver.with_capture(capture.clone());
+
+    sender.send_message(b"captured".to_vec().into_boxed_slice()).unwrap();
+    sender.work();
+    assert!(drive_until(|| !messages(receiver.work()).is_empty(), TIMEOUT));
+    capture.flush().unwrap();
+
+    let records: Vec<_> = CaptureReader::open(&path)
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let is_data = |r: &CaptureRecord| {
+        let msg = Message::deserialize(r.data.clone());
+        msg.id == 1 && &msg.data[..] == b"captured"
+    };
+    let sent = records
+        .iter()
+        .find(|r| r.direction == Direction::Sent && is_data(r))
+        .expect("sent datagram recorded");
+    let received = records
+        .iter()
+        .find(|r| r.direction == Direction::Received && is_data(r))
+        .expect("received datagram recorded");
+    assert_eq!(sent.local, received.peer);
+    assert_eq!(sent.peer, received.local);
+    assert!(sent.time <= received.time);
+}
+
+#[test]
+fn test_ping_reports_round_trip() {
+    use sim::Side;
+    use std::time::Duration;
+
+    let link = sim::Link::default();
+    let mut sim = sim::Simulation::new(sim_config(), link, 8);
+    let now = sim.now();
+    sim.endpoint_mut(Side::Client).ping(now);
+
+    let answered = sim.run_until(Duration::from_secs(1), |sim| {
+        sim.events(Side::Client)
+            .iter()
+            .any(|(_, e)| matches!(e, Ok(Event::Pong(_))))
+    });
+    assert!(answered);
+    let rtt = sim
+        .events(Side::Client)
+        .iter()
+        .find_map(|(_, e)| match e {
+            Ok(Event::Pong(rtt)) => Some(*rtt),
+            _ => None,
+        });
+    assert_eq!(rtt, Some(link.delay * 2));
+}
+
+fn stream_config(window: usize) -> Config {
+    Config {
+        stream_window: window,
+        trace: false,
+        ..Config::default()
+    }
+}
+
+/// Keeps the worker going on another thread until it reports the stream sent.
+fn send_in_background(
+    mut worker: SocketWorker,
+    id: StreamId,
+) -> std::thread::JoinHandle<SocketWorker> {
+    std::thread::spawn(move || {
+        let sent = drive_until(
+            || {
+                worker
+                    .work()
+                    .iter()
+                    .any(|e| matches!(e, Ok(Event::StreamSent(sent)) if *sent == id))
+            },
+            TIMEOUT,
+        );
+        assert!(sent, "{id} never completed");
+        worker
+    })
+}
+
+fn stream_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+#[test]
+fn test_stream_roundtrip_with_flow_control() {
+    use std::io::Read;
+
+    // A window much smaller than the stream needs several credit updates
+    let (mut sender, mut receiver) = Pair::new().with_config(stream_config(16 * 1024)).connect();
+    let data = stream_data(200_000);
+
+    let id = sender.send_stream(std::io::Cursor::new(data.clone())).unwrap();
+    let sender = send_in_background(sender, id);
+
+    let opened = drive_until(
+        || {
+            receiver
+                .work()
+                .iter()
+                .any(|e| matches!(e, Ok(Event::StreamOpened(opened)) if *opened == id))
+        },
+        TIMEOUT,
+    );
+    assert!(opened);
+
+    let mut reader = receiver.recv_stream().expect("incoming stream");
+    assert_eq!(reader.id(), id);
+    let mut received = Vec::new();
+    reader.read_to_end(&mut received).unwrap();
+    assert_eq!(reader.offset(), data.len() as u64);
+    drop(reader);
+    assert!(received == data);
+
+    sender.join().unwrap();
+}
+
+#[test]
+fn test_stream_resumes_from_offset() {
+    use std::io::Read;
+
+    let (mut sender, mut receiver) = Pair::new().with_config(stream_config(64 * 1024)).connect();
+    let data = stream_data(100_000);
+    let resume_at = 30_000;
+
+    sender
+        .resume_send_stream(StreamId(9), std::io::Cursor::new(data.clone()), resume_at)
+        .unwrap();
+    let sender = send_in_background(sender, StreamId(9));
+
+    let mut reader = receiver
+        .resume_recv_stream(StreamId(9), &data[..resume_at as usize])
+        .unwrap();
+    assert_eq!(reader.offset(), resume_at);
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    drop(reader);
+    assert!(rest[..] == data[resume_at as usize..]);
+
+    sender.join().unwrap();
+}
+
+#[test]
+fn test_stream_hash_mismatch_fails_the_read() {
+    use std::io::Read;
+
+    let (mut sender, mut receiver) = Pair::new().with_config(stream_config(64 * 1024)).connect();
+    let data = stream_data(10_000);
+
+    // The receiver claims a prefix the sender never had
+    sender
+        .resume_send_stream(StreamId(3), std::io::Cursor::new(data.clone()), 100)
+        .unwrap();
+    let sender = send_in_background(sender, StreamId(3));
+
+    let mut reader = receiver
+        .resume_recv_stream(StreamId(3), &[0u8; 100][..])
+        .unwrap();
+    let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    drop(reader);
+
+    sender.join().unwrap();
+}
+
+#[test]
+fn test_stream_data_outside_the_window_fails_the_stream() {
+    use bytes::Bytes;
+
+    let mut buf = [0; 8];
+    let mut stream = RecvStream::new(0, Default::default(), 16);
+    stream.on_data(u64::MAX - 1, Bytes::from_static(b"wrap"));
+    assert!(stream.read(&mut buf).unwrap().is_err());
+
+    // Bytes that arrived in order can still be read
+    let mut stream = RecvStream::new(0, Default::default(), 16);
+    stream.on_data(0, Bytes::from_static(b"in"));
+    stream.on_data(12, Bytes::from_static(b"beyond"));
+    assert_eq!(stream.read(&mut buf).unwrap().unwrap(), 2);
+    assert!(stream.read(&mut buf).unwrap().is_err());
+
+    // A resumed sender has credit from where it starts
+    let mut stream = RecvStream::opened_at(1000, 16);
+    stream.on_data(1000, Bytes::from_static(&[0; 16]));
+    assert!(stream.is_open());
+    stream.on_data(0, Bytes::from_static(b"early"));
+    assert!(!stream.is_open());
+}
+
+#[test]
+fn test_connection_stream_reads_and_writes_in_order() {
+    use std::io::{BufRead, Read, Write};
+
+    let (client, server) = Pair::new().with_config(stream_config(16 * 1024)).connect();
+    let data = stream_data(100_000);
+
+    let expected = data.clone();
+    let server = std::thread::spawn(move || {
+        let mut stream = ConnectionStream::new(server).unwrap();
+        let mut line = String::new();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!(line, "hello\n");
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest == expected);
+
+        stream.write_all(b"done").unwrap();
+        stream.close().unwrap()
+    });
+
+    let mut stream = ConnectionStream::new(client).unwrap();
+    stream.write_all(b"hello\n").unwrap();
+    std::io::copy(&mut &data[..], &mut stream).unwrap();
+    stream.flush().unwrap();
+    assert_eq!(stream.worker().queue_depth(), QueueDepth::default());
+    stream.finish().unwrap();
+    assert!(stream.write(b"late").is_err());
+
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).unwrap();
+    assert_eq!(answer, "done");
+
+    // The server closed the connection once we had everything
+    assert_eq!(server.join().unwrap().state(), ConnectionState::Closed);
+    assert_eq!(stream.close().unwrap().state(), ConnectionState::Closed);
+}
+
+#[test]
+fn test_connection_stream_survives_garbled_datagrams() {
+    use std::io::{Read, Write};
+
+    let (client, server) = Pair::new().with_config(stream_config(16 * 1024)).connect();
+    // Truncated control messages, which the server reports but can't check
+    let attacker = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let acc = Message::new_acc(5).serialize();
+    for len in 0..acc.len() {
+        attacker.send_to(&acc[..len], &client.address).unwrap();
+    }
+
+    let server = std::thread::spawn(move || {
+        let mut stream = ConnectionStream::new(server).unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+        stream.close().unwrap();
+        received
+    });
+    let mut stream = ConnectionStream::new(client).unwrap();
+    stream.write_all(b"still here").unwrap();
+    stream.close().unwrap();
+
+    assert_eq!(server.join().unwrap(), "still here");
+}
+
+#[test]
+fn test_replay_window_accepts_each_id_once() {
+    let mut window = ReplayWindow::new(100, 64);
+
+    assert_eq!(window.check(99), Verdict::Seen);
+    assert_eq!(window.check(164), Verdict::Ahead);
+    assert_eq!(window.check(101), Verdict::New);
+
+    window.insert(101);
+    assert_eq!(window.check(101), Verdict::Seen);
+    assert_eq!(window.base(), 100);
+
+    window.insert(100);
+    assert_eq!(window.base(), 102);
+    assert_eq!(window.check(165), Verdict::New);
+    assert_eq!(window.check(166), Verdict::Ahead);
+}
+
+#[test]
+fn test_capture_records_the_handshake() {
+    let path = std::env::temp_dir().join(format!("udpc-handshake-{}.udpc", std::process::id()));
+    let capture = Capture::create(&path).unwrap();
+    let config = Config {
+        trace: false,
+        capture: Some(capture.clone()),
+        ..Config::default()
+    };
+
+    let (mut client, mut server) = Pair::new().with_config(config).handshake();
+    client.send_message(b"after".to_vec()).unwrap();
+    client.work();
+    assert!(drive_until(|| !messages(server.work()).is_empty(), TIMEOUT));
+    capture.flush().unwrap();
+
+    let records: Vec<_> = CaptureReader::open(&path)
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let starts_with = |direction, prefix: &[u8]| {
+        records
+            .iter()
+            .filter(|r| r.direction == direction && r.data.starts_with(prefix))
+            .count()
+    };
+    assert_eq!(starts_with(Direction::Sent, b"Hello"), 1);
+    assert_eq!(starts_with(Direction::Received, b"Hello"), 1);
+    assert_eq!(starts_with(Direction::Sent, b"Connect port"), 1);
+    assert_eq!(starts_with(Direction::Received, b"Connect port"), 1);
+    // The workers carry on recording
+    assert!(records.len() > 4);
+}
+
+#[test]
+fn test_shared_secret_authenticates_the_server() {
+    let handshake = |server_secret: &[u8], client_secret: &[u8]| {
+        let config = |secret: &[u8]| Config {
+            secret: secret.to_vec(),
+            ..Config::default()
+        };
+        let (client, server) = Pair::new()
+            .with_client_config(config(client_secret))
+            .with_server_config(config(server_secret))
+            .try_handshake();
+        server.unwrap();
+        client
+    };
+    assert!(handshake(b"swordfish", b"swordfish").is_ok());
+
+    for server_secret in [&b"marlin"[..], b""] {
+        match handshake(server_secret, b"swordfish") {
+            Err(ConnectionError::AuthFailure(_)) => {}
+            other => panic!("expected AuthFailure, got {:?}", other.map(|_| ())),
+        }
+    }
+}
+
+#[test]
+fn test_secret_changes_the_session_key() {
+    let session = |secret: &[u8]| {
+        Session::new(&[1; 16], &[2; 16], 0, 0, Integrity::Mac, WireFormat::V1, secret)
+    };
+    assert_eq!(session(b"").confirmation(), session(b"").confirmation());
+    assert_eq!(session(b"a").confirmation(), session(b"a").confirmation());
+    assert_ne!(session(b"").confirmation(), session(b"a").confirmation());
+    assert_ne!(session(b"a").confirmation(), session(b"b").confirmation());
+}
+
+#[test]
+fn test_replayed_datagram_is_rejected() {
+    let path = std::env::temp_dir().join(format!("udpc-replay-{}.udpc", std::process::id()));
+    let capture = Capture::create(&path).unwrap();
+
+    let (client, mut server) = Pair::new().handshake();
+    let mut client = client.with_capture(capture.clone());
+    let id = client.send_message(b"transfer 100".to_vec()).unwrap();
+    client.work();
+    assert!(drive_until(|| !messages(server.work()).is_empty(), TIMEOUT));
+    capture.flush().unwrap();
+
+    let replayed = CaptureReader::open(&path)
+        .unwrap()
+        .map(Result::unwrap)
+        .find(|r| r.direction == Direction::Sent && Message::deserialize(r.data.clone()).id == id.0)
+        .expect("data datagram captured")
+        .data;
+    std::fs::remove_file(&path).unwrap();
+    let attacker = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let quiet = std::time::Duration::from_millis(40);
+
+    // Within the session the ID was already received
+    attacker.send_to(&replayed, &client.address).unwrap();
+    assert!(!drive_until(|| !messages(server.work()).is_empty(), quiet));
+
+    // A new session uses a different key
+    let (mut client, mut server) = Pair::new().handshake();
+    attacker.send_to(&replayed, &client.address).unwrap();
+    assert!(!drive_until(|| !messages(server.work()).is_empty(), quiet));
+
+    client.send_message(b"fresh".to_vec()).unwrap();
+    client.work();
+    let mut received = Vec::new();
+    let done = drive_until(
+        || {
+            received.extend(messages(server.work()));
+            !received.is_empty()
+        },
+        TIMEOUT,
+    );
+    assert!(done);
+    assert_eq!(received, vec![bytes::Bytes::from_static(b"fresh")]);
+}
+
+fn discovery_config(secret: &[u8]) -> DiscoveryConfig {
+    DiscoveryConfig {
+        // A port of its own so parallel test runs don't see each other
+        group: "239.255.85.68:17645".parse().unwrap(),
+        interface: std::net::Ipv4Addr::LOCALHOST,
+        interval: std::time::Duration::from_millis(20),
+        expiry: std::time::Duration::from_millis(300),
+        secret: secret.to_vec(),
+    }
+}
+
+#[test]
+fn test_discovery_on_loopback_multicast() {
+    let address = "127.0.0.1:4001".parse().unwrap();
+    let mut first = Discovery::new(1, "test", address, discovery_config(b"secret")).unwrap();
+    let unspecified = "0.0.0.0:4002".parse().unwrap();
+    let mut second = Discovery::new(2, "test", unspecified, discovery_config(b"secret")).unwrap();
+    let mut other_cluster = Discovery::new(3, "other", address, discovery_config(b"secret")).unwrap();
+    let mut wrong_secret = Discovery::new(4, "test", address, discovery_config(b"guess")).unwrap();
+
+    let expected = Beacon {
+        node_id: 2,
+        cluster: "test".to_string(),
+        // The unspecified IP is taken from the beacon's source
+        address: "127.0.0.1:4002".parse().unwrap(),
+    };
+    let mut events = Vec::new();
+    let done = drive_until(
+        || {
+            for node in [&mut second, &mut other_cluster, &mut wrong_secret] {
+                node.work();
+            }
+            events.extend(first.work());
+            events.iter().any(|e| e.is_err()) && events.iter().any(|e| e.is_ok())
+        },
+        TIMEOUT,
+    );
+    assert!(done, "discovery did not converge: {events:?}");
+    assert!(events.contains(&Ok(DiscoveryEvent::Discovered(expected.clone()))));
+    assert!(events.iter().all(|e| match e {
+        Ok(DiscoveryEvent::Discovered(beacon)) => beacon.node_id == 2,
+        Ok(DiscoveryEvent::Expired(_)) => false,
+        Err(e) => e.ends_with("Bad signature"),
+    }));
+    assert_eq!(first.peers().collect::<Vec<_>>(), vec![&expected]);
+
+    drop(second);
+    let mut events = Vec::new();
+    let done = drive_until(
+        || {
+            events.extend(first.work());
+            !events.is_empty()
+        },
+        TIMEOUT,
+    );
+    assert!(done);
+    assert_eq!(events, vec![Ok(DiscoveryEvent::Expired(expected))]);
+    assert_eq!(first.peers().count(), 0);
+}
+
+fn state_changes(events: Vec<Result<Event, ConnectionError>>) -> Vec<ConnectionState> {
+    events
+        .into_iter()
+        .filter_map(|e| match e {
+            Ok(Event::StateChanged(state)) => Some(state),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn test_connection_state_lifecycle() {
+    let (mut client, mut server) = Pair::new().handshake();
+    assert_eq!(client.state(), ConnectionState::Established);
+    // The client may not have received the server's answer yet
+    assert_eq!(server.state(), ConnectionState::Handshaking);
+
+    client.send_message(b"hi".to_vec()).unwrap();
+    let mut states = (Vec::new(), Vec::new());
+    let done = drive_until(
+        || {
+            states.0.extend(state_changes(client.work()));
+            states.1.extend(state_changes(server.work()));
+            server.state() == ConnectionState::Established
+        },
+        TIMEOUT,
+    );
+    assert!(done);
+    assert_eq!(states, (vec![], vec![ConnectionState::Established]));
+
+    let id = client.send_message(b"last words".to_vec()).unwrap();
+    client.close();
+    assert_eq!(client.state(), ConnectionState::Closing);
+    assert!(client.send_message(b"too late".to_vec()).is_err());
+
+    let mut states = (Vec::new(), Vec::new());
+    let done = drive_until(
+        || {
+            states.0.extend(state_changes(client.work()));
+            states.1.extend(state_changes(server.work()));
+            client.state().is_final() && server.state().is_final()
+        },
+        TIMEOUT,
+    );
+    assert!(done);
+    assert_eq!(
+        states,
+        (
+            vec![ConnectionState::Closing, ConnectionState::Closed],
+            vec![ConnectionState::Closed]
+        )
+    );
+    // Closing waits for the queue to drain
+    assert_eq!(client.delivery_status(id), DeliveryStatus::Acked);
+    assert!(server.send_message(b"anyone?".to_vec()).is_err());
+    assert!(client.work().is_empty());
+}
+
+#[test]
+fn test_silent_peer_times_out_while_keepalive_holds() {
+    use sim::Side;
+    use std::time::Duration;
+
+    let config = Config {
+        keepalive_interval: Duration::from_millis(20),
+        idle_timeout: Duration::from_millis(200),
+        ..sim_config()
+    };
+    let mut sim = sim::Simulation::new(config, sim::Link::default(), 9);
+    let state_changes = |sim: &sim::Simulation, side| -> Vec<ConnectionState> {
+        sim.events(side)
+            .iter()
+            .filter_map(|(_, e)| match e {
+                Ok(Event::StateChanged(state)) => Some(*state),
+                _ => None,
+            })
+            .collect()
+    };
+
+    // Idle but alive, keepalive pings answer for both
+    sim.run_for(Duration::from_millis(400));
+    for side in [Side::Client, Side::Server] {
+        assert!(state_changes(&sim, side).is_empty());
+        assert_eq!(sim.endpoint(side).state(), ConnectionState::Established);
+    }
+
+    // The server stops answering
+    sim.set_link(sim::Link {
+        loss: 1.0,
+        ..sim::Link::default()
+    });
+    let id = sim
+        .endpoint_mut(Side::Client)
+        .send_message(&b"into the void"[..])
+        .unwrap();
+    let timed_out = sim.run_until(Duration::from_secs(1), |sim| {
+        sim.endpoint(Side::Client).state().is_final()
+    });
+    assert!(timed_out);
+
+    assert_eq!(state_changes(&sim, Side::Client), [ConnectionState::TimedOut]);
+    assert!(sim
+        .events(Side::Client)
+        .iter()
+        .any(|(_, event)| matches!(event, Ok(Event::Expired(expired)) if *expired == id)));
+    let client = sim.endpoint_mut(Side::Client);
+    assert_eq!(client.delivery_status(id), DeliveryStatus::Expired);
+    assert!(client.send_message(&b"again"[..]).is_err());
+}
+
+fn reconnect_config() -> Config {
+    Config {
+        keepalive_interval: std::time::Duration::from_millis(20),
+        idle_timeout: std::time::Duration::from_millis(150),
+        ..Config::default()
+    }
+}
+
+#[test]
+fn test_reconnect_requeues_unacked_messages_on_a_new_session() {
+    let listener = std::sync::Arc::new(std::net::UdpSocket::bind("127.0.0.1:0").unwrap());
+    let (mut client, mut server) = Pair::new()
+        .with_client_config(reconnect_config())
+        .reconnecting(&listener);
+
+    assert_eq!(client.send_message(b"one".to_vec()).unwrap(), MessageId(1));
+    let (mut events, mut received) = (Vec::new(), Vec::new());
+    let done = drive_until(
+        || {
+            received.extend(messages(server.work()));
+            events.extend(client.work().into_iter().map(Result::unwrap));
+            events
+                .iter()
+                .any(|e| matches!(e, Event::Delivered(MessageId(1))))
+        },
+        TIMEOUT,
+    );
+    assert!(done, "{events:?}");
+    assert_eq!(received, vec![bytes::Bytes::from_static(b"one")]);
+
+    // The server goes away for good, a new one takes over the listener
+    drop(server);
+    let next = accept(&listener, Config::default());
+    assert_eq!(client.send_message(b"two".to_vec()).unwrap(), MessageId(2));
+
+    let mut events = Vec::new();
+    let done = drive_until(
+        || {
+            events.extend(client.work().into_iter().map(Result::unwrap));
+            events
+                .iter()
+                .any(|e| matches!(e, Event::Reconnect(ReconnectStatus::Reconnected { .. })))
+        },
+        TIMEOUT,
+    );
+    assert!(done, "{events:?}");
+    let statuses: Vec<_> = events
+        .iter()
+        .filter_map(|e| match e {
+            Event::Reconnect(ReconnectStatus::Scheduled { attempt, .. }) => {
+                Some(format!("scheduled {attempt}"))
+            }
+            Event::Reconnect(status) => Some(format!("{status:?}")),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        statuses,
+        ["Resuming", "scheduled 1", "Reconnected { attempt: 1, requeued: 1 }"]
+    );
+    assert!(client.is_connected());
+
+    let mut server = next.join().unwrap().unwrap();
+    let (mut events, mut received) = (Vec::new(), Vec::new());
+    let done = drive_until(
+        || {
+            received.extend(messages(server.work()));
+            events.extend(client.work().into_iter().map(Result::unwrap));
+            events
+                .iter()
+                .any(|e| matches!(e, Event::Delivered(MessageId(2))))
+        },
+        TIMEOUT,
+    );
+    assert!(done, "{events:?}");
+    assert_eq!(received, vec![bytes::Bytes::from_static(b"two")]);
+    assert_eq!(client.unacked(), 0);
+}
+
+#[test]
+fn test_reconnect_resumes_a_session_the_peer_still_holds() {
+    let listener = std::sync::Arc::new(std::net::UdpSocket::bind("127.0.0.1:0").unwrap());
+    let (mut client, mut server) = Pair::new()
+        .with_client_config(reconnect_config())
+        .reconnecting(&listener);
+
+    // The server stops working for longer than the client's idle timeout
+    client.send_message(b"during the outage".to_vec()).unwrap();
+    let mut events = Vec::new();
+    let done = drive_until(
+        || {
+            events.extend(client.work().into_iter().map(Result::unwrap));
+            events
+                .iter()
+                .any(|e| matches!(e, Event::Reconnect(ReconnectStatus::Resuming)))
+        },
+        TIMEOUT,
+    );
+    assert!(done, "{events:?}");
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, Event::StateChanged(ConnectionState::TimedOut))));
+    // Taken while there is no session, refused by the resumed one
+    let too_large = client.send_message(vec![0u8; 100 * 1024]).unwrap();
+
+    // The server's idle timeout is the default, far longer than the client's
+    let (mut events, mut received) = (Vec::new(), Vec::new());
+    let done = drive_until(
+        || {
+            received.extend(messages(server.work()));
+            events.extend(client.work());
+            events
+                .iter()
+                .any(|e| matches!(e, Ok(Event::Delivered(MessageId(1)))))
+        },
+        TIMEOUT,
+    );
+    assert!(done, "{events:?}");
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, Ok(Event::Reconnect(ReconnectStatus::Resumed { requeued: 1 })))));
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, Err(ConnectionError::TooLarge { .. }))));
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, Ok(Event::Expired(id)) if *id == too_large)));
+    assert_eq!(client.unacked(), 0);
+    // The original datagram waited in the server's socket, so it may arrive twice
+    assert!(!received.is_empty());
+    assert!(received.iter().all(|msg| &msg[..] == b"during the outage"));
+    assert_eq!(server.state(), ConnectionState::Established);
+}
+
+fn sim_config() -> Config {
+    Config {
+        trace: false,
+        send_window: 4,
+        ..Config::default()
+    }
+}
+
+fn lossy_link() -> sim::Link {
+    sim::Link {
+        loss: 0.2,
+        delay: std::time::Duration::from_millis(20),
+        jitter: std::time::Duration::from_millis(15),
+    }
+}
+
+/// Sends 50 messages from the client and runs until all are acknowledged.
+fn run_lossy_transfer(seed: u64) -> sim::Simulation {
+    use sim::Side;
+
+    let mut sim = sim::Simulation::new(sim_config(), lossy_link(), seed);
+    for i in 0..50u32 {
+        sim.endpoint_mut(Side::Client)
+            .send_message(i.to_be_bytes().to_vec())
+            .unwrap();
+    }
+
+    let done = sim.run_until(std::time::Duration::from_secs(60), |sim| {
+        sim.endpoint(Side::Client).queue_depth() == QueueDepth::default()
+    });
+    assert!(done, "Not delivered after {:?}", sim.elapsed());
+    sim
+}
+
+#[test]
+fn test_simulated_lossy_link_delivers_everything() {
+    let sim = run_lossy_transfer(1);
+
+    let mut received: Vec<u32> = sim
+        .events(sim::Side::Server)
+        .iter()
+        .filter_map(|(_, e)| match e {
+            Ok(Event::Message(msg)) => Some(u32::from_be_bytes(msg[..].try_into().unwrap())),
+            _ => None,
+        })
+        .collect();
+    received.sort();
+    assert_eq!(received, (0..50).collect::<Vec<_>>());
+    assert!(sim.stats().lost > 0);
+    // Virtual time, the retransmissions took far longer than the test
+    assert!(sim.elapsed() >= std::time::Duration::from_millis(100));
+}
+
+#[test]
+fn test_simulation_is_reproducible() {
+    let trace = |sim: &sim::Simulation| {
+        [sim::Side::Client, sim::Side::Server]
+            .map(|side| format!("{:?}", sim.events(side)))
+    };
+
+    let first = run_lossy_transfer(7);
+    let second = run_lossy_transfer(7);
+    assert_eq!(first.stats(), second.stats());
+    assert_eq!(first.elapsed(), second.elapsed());
+    assert_eq!(trace(&first), trace(&second));
+}
+
+#[test]
+fn test_simulated_cut_link_times_out() {
+    use sim::Side;
+    use std::time::Duration;
+
+    let config = sim_config();
+    let idle_timeout = config.idle_timeout;
+    let mut sim = sim::Simulation::new(config, sim::Link::default(), 3);
+    sim.run_for(Duration::from_secs(12));
+    assert_eq!(sim.endpoint(Side::Client).state(), ConnectionState::Established);
+
+    let cut = sim.elapsed();
+    sim.set_link(sim::Link {
+        loss: 1.0,
+        ..sim::Link::default()
+    });
+    let timed_out = sim.run_until(Duration::from_secs(120), |sim| {
+        sim.endpoint(Side::Client).state() == ConnectionState::TimedOut
+    });
+    assert!(timed_out);
+
+    // Keepalives kept the connection up until the cut
+    let at = sim.elapsed();
+    assert!(at <= cut + idle_timeout, "Timed out at {at:?}");
+    assert!(at > cut + idle_timeout - sim_config().keepalive_interval, "Timed out at {at:?}");
+    assert_eq!(sim.endpoint(Side::Client).poll_timeout(), None);
+}
+
+/// Sends a message over a cut link and runs until it expires.
+fn run_expiry(config: Config, ttl: Ttl) -> (sim::Simulation, MessageId) {
+    use sim::Side;
+    use std::time::Duration;
+
+    let cut = sim::Link {
+        loss: 1.0,
+        ..sim::Link::default()
+    };
+    let mut sim = sim::Simulation::new(config, cut, 4);
+    let now = sim.now();
+    let id = sim
+        .endpoint_mut(Side::Client)
+        .send_message_with_ttl(now, &b"stale"[..], ttl)
+        .unwrap();
+
+    let expired = sim.run_until(Duration::from_secs(10), |sim| {
+        sim.events(Side::Client)
+            .iter()
+            .any(|(_, event)| matches!(event, Ok(Event::Expired(expired)) if *expired == id))
+    });
+    assert!(expired, "Not expired after {:?}", sim.elapsed());
+    (sim, id)
+}
+
+#[test]
+fn test_message_expires_after_deadline() {
+    use sim::Side;
+    use std::time::Duration;
+
+    let ttl = Ttl {
+        deadline: Some(Duration::from_secs(1)),
+        ..Ttl::default()
+    };
+    let (mut sim, id) = run_expiry(sim_config(), ttl);
+    assert_eq!(sim.elapsed(), Duration::from_secs(1));
+    let client = sim.endpoint(Side::Client);
+    assert_eq!(client.delivery_status(id), DeliveryStatus::Expired);
+    assert_eq!(client.queue_depth(), QueueDepth::default());
+
+    // The peer isn't left waiting for the expired message
+    sim.set_link(sim::Link::default());
+    sim.endpoint_mut(Side::Client)
+        .send_message(&b"fresh"[..])
+        .unwrap();
+    let received = sim.run_until(Duration::from_secs(10), |sim| {
+        sim.events(Side::Server)
+            .iter()
+            .any(|(_, event)| matches!(event, Ok(Event::Message(msg)) if msg == "fresh"))
+    });
+    assert!(received);
+    assert!(!sim
+        .events(Side::Server)
+        .iter()
+        .any(|(_, event)| matches!(event, Ok(Event::Message(msg)) if msg == "stale")));
+}
+
+#[test]
+fn test_message_expires_after_retransmits() {
+    use std::time::Duration;
+
+    let config = Config {
+        ttl: Ttl {
+            max_retransmits: Some(2),
+            ..Ttl::default()
+        },
+        ..sim_config()
+    };
+    let rto = config.retransmit_timeout;
+    let ttl = config.ttl;
+    let (sim, _) = run_expiry(config, ttl);
+
+    // Sent three times, given up on when due a fourth time
+    assert_eq!(sim.elapsed(), rto * 3);
+    assert!(sim.stats().lost >= 3);
+    assert!(sim.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn test_idle_worker_sleeps_until_readable() {
+    use std::time::{Duration, Instant};
+
+    let config = Config {
+        mtu_probing: false,
+        trace: false,
+        ..Config::default()
+    };
+    let (mut sender, mut receiver) = Pair::new().with_config(config.clone()).connect();
+
+    // Nothing to do but keep the connection alive
+    receiver.work();
+    let timeout = receiver.next_timeout().unwrap();
+    assert!(timeout > Duration::from_secs(4) && timeout <= config.keepalive_interval);
+    let start = Instant::now();
+    assert!(!wait_readable(&[receiver.socket()], Some(Duration::from_millis(20))).unwrap());
+    assert!(start.elapsed() >= Duration::from_millis(20));
+
+    sender.send_message(b"wake up".to_vec()).unwrap();
+    assert_eq!(sender.next_timeout(), Some(Duration::ZERO));
+    sender.work();
+    // Waiting for the acknowledgement now
+    assert!(sender.next_timeout().unwrap() <= config.retransmit_timeout);
+
+    assert!(wait_readable(&[receiver.socket()], receiver.next_timeout()).unwrap());
+    let mut received = Vec::new();
+    let done = drive_until(
+        || {
+            received.extend(messages(receiver.work()));
+            !received.is_empty()
+        },
+        TIMEOUT,
+    );
+    assert!(done);
+    assert_eq!(received, [&b"wake up"[..]]);
+
+    receiver.close();
+    receiver.work();
+    assert_eq!(receiver.next_timeout(), None);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_typed_connection_survives_undecodable_messages() {
+    let (a, b) = Pair::new().connect();
+    let mut sender = TypedConnection::<(String, u32), _>::new(a, Json);
+    let mut receiver = TypedConnection::<(String, u32), _>::new(b, Json);
+
+    sender.send(&("first".to_string(), 1)).unwrap();
+    sender.worker_mut().send_message(&b"not json"[..]).unwrap();
+    sender.send(&("second".to_string(), 2)).unwrap();
+
+    let mut received = Vec::new();
+    let mut errors = Vec::new();
+    let done = drive_until(
+        || {
+            sender.work();
+            for event in receiver.work() {
+                match event {
+                    Ok(TypedEvent::Message(value)) => received.push(value),
+                    Ok(TypedEvent::Other(_)) => {}
+                    Err(e) => errors.push(e),
+                }
+            }
+            received.len() == 2
+        },
+        TIMEOUT,
+    );
+    assert!(done);
+
+    assert_eq!(received, [("first".to_string(), 1), ("second".to_string(), 2)]);
+    assert!(matches!(errors[..], [ConnectionError::Codec(_)]));
+    assert_eq!(receiver.worker().state(), ConnectionState::Established);
+}
+
+#[cfg(any(feature = "json", feature = "bincode", feature = "postcard"))]
+#[test]
+fn test_codecs_roundtrip() {
+    fn roundtrip(codec: impl Codec) {
+        let value = (String::from("key"), 7u64, vec![1u8, 2, 3]);
+        let data = codec.encode(&value).unwrap();
+        assert_eq!(codec.decode::<(String, u64, Vec<u8>)>(&data).unwrap(), value);
+        assert!(codec.decode::<(String, u64, Vec<u8>)>(&data[..2]).is_err());
+    }
+
+    #[cfg(feature = "json")]
+    roundtrip(Json);
+    #[cfg(feature = "bincode")]
+    roundtrip(Bincode);
+    #[cfg(feature = "postcard")]
+    roundtrip(Postcard);
+}
+
+#[test]
+fn test_rpc_routes_responses_to_callers() {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    let (client, server) = Pair::new().connect();
+    let (mut client, mut server) = (Rpc::new(client), Rpc::new(server));
+    server.register("echo", Ok).unwrap();
+    server.register("fail", |_| Err("no such key".to_string())).unwrap();
+
+    let echo = client.call("echo", &b"ping"[..]).unwrap();
+    let unknown = client.call("nope", &b""[..]).unwrap();
+    let answer = Arc::new(Mutex::new(None));
+    let slot = answer.clone();
+    client
+        .call_with("fail", &b"key"[..], Duration::from_secs(5), move |result| {
+            *slot.lock().unwrap() = Some(result);
+        })
+        .unwrap();
+
+    let mut responses = HashMap::new();
+    let done = drive_until(
+        || {
+            server.work();
+            for event in client.work() {
+                if let Ok(RpcEvent::Response(id, result)) = event {
+                    responses.insert(id, result);
+                }
+            }
+            responses.len() == 2 && answer.lock().unwrap().is_some()
+        },
+        TIMEOUT,
+    );
+    assert!(done);
+
+    assert_eq!(responses[&echo].as_ref().unwrap(), &b"ping"[..]);
+    assert!(matches!(&responses[&unknown], Err(RpcError::UnknownMethod(m)) if m == "nope"));
+    assert!(matches!(
+        answer.lock().unwrap().take(),
+        Some(Err(RpcError::Remote(e))) if e == "no such key"
+    ));
+    assert_eq!(client.pending_calls(), 0);
+
+    // Waiting for the answer, with the peer on a thread of its own
+    let server = std::thread::spawn(move || {
+        drive_until(
+            || {
+                server.work();
+                false
+            },
+            Duration::from_secs(1),
+        )
+    });
+    let answer = client.call_wait("echo", &b"again"[..], Duration::from_secs(1));
+    assert_eq!(answer.unwrap(), &b"again"[..]);
+    server.join().unwrap();
+}
+
+#[test]
+fn test_rpc_calls_time_out_or_are_cancelled() {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    // The server never answers
+    let (client, _server) = Pair::new().connect();
+    let mut client = Rpc::new(client);
+    let outcomes = Arc::new(Mutex::new(Vec::new()));
+
+    let record = |outcomes: &Arc<Mutex<Vec<RpcError>>>| {
+        let outcomes = outcomes.clone();
+        move |result: Result<bytes::Bytes, RpcError>| {
+            outcomes.lock().unwrap().push(result.unwrap_err());
+        }
+    };
+    let slow = client
+        .call_with("slow", &b""[..], Duration::from_millis(20), record(&outcomes))
+        .unwrap();
+    let cancelled = client
+        .call_with("slow", &b""[..], Duration::from_secs(5), record(&outcomes))
+        .unwrap();
+
+    assert_eq!(client.worker().queue_depth().messages, 2);
+    assert!(client.cancel(cancelled));
+    assert!(!client.cancel(cancelled));
+    // The request is not sent any more
+    assert_eq!(client.worker().queue_depth().messages, 1);
+    let timed_out = drive_until(
+        || {
+            client.work();
+            outcomes.lock().unwrap().len() == 2
+        },
+        TIMEOUT,
+    );
+    assert!(timed_out);
+
+    let outcomes = outcomes.lock().unwrap();
+    assert!(matches!(outcomes[..], [RpcError::Cancelled, RpcError::Timeout]));
+    assert!(!client.cancel(slow));
+    assert_eq!(client.pending_calls(), 0);
+
+    let waited = client.call_wait("slow", &b""[..], Duration::from_millis(20));
+    assert!(matches!(waited, Err(RpcError::Timeout)));
+
+    let long = "m".repeat(256);
+    assert!(matches!(client.call(&long, &b""[..]), Err(RpcError::MethodTooLong(_))));
+    assert!(matches!(client.register(long, Ok), Err(RpcError::MethodTooLong(_))));
+    assert!(client.call(&"m".repeat(255), &b""[..]).is_ok());
+}
+
+#[test]
+fn test_truncated_control_packets_are_reported_not_fatal() {
+    let now = std::time::Instant::now();
+    let mut protocol = Protocol::new("peer".to_string(), |_| {}, now);
+
+    let controls = [
+        Message::new_acc(5),
+        Message::new_ping(7),
+        Message::new_pong(7),
+        Message::new_probe(64),
+        Message::new_probe_ack(64),
+    ];
+    for msg in controls {
+        let ser = msg.serialize();
+        for len in 0..ser.len() {
+            protocol.handle_datagram(now, ser.slice(..len));
+        }
+    }
+    protocol.handle_datagram(now, bytes::Bytes::from_static(&[0xff; 16]));
+
+    assert!(protocol
+        .take_events()
+        .iter()
+        .any(|event| matches!(event, Err(ConnectionError::Malformed(_)))));
+    assert!(!protocol.state().is_final());
+}
+
+#[test]
+fn test_garbage_batches_are_dropped() {
+    let now = std::time::Instant::now();
+    let mut protocol = Protocol::new("peer".to_string(), |_| {}, now);
+
+    // A batch announcing more bytes than it carries
+    protocol.handle_datagram(now, bytes::Bytes::from_static(&[1, 2, 32, 0, 0xff]));
+    let batch = Message::new_batch(&[
+        Message::new(1, &b"a"[..]).serialize(),
+        Message::new_acc(7).serialize(),
+    ])
+    .serialize();
+    for len in 0..batch.len() {
+        protocol.handle_datagram(now, batch.slice(..len));
+    }
+    let nested = Message::new_batch(&[batch]).serialize();
+    protocol.handle_datagram(now, nested);
+    // Dropped like any datagram that doesn't decode
+    assert!(!protocol.take_events().iter().any(Result::is_err));
+    assert!(!protocol.state().is_final());
+
+    // A forged message spoils the whole batch
+    let mut protocol = Protocol::new("peer".to_string(), |_| {}, now);
+    let mut forged = Message::new(1, &b"a"[..]);
+    forged.hash = Message::new(2, &b"b"[..]).hash;
+    let batch = Message::new_batch(&[Message::new(0, &b"ok"[..]).serialize(), forged.serialize()]);
+    protocol.handle_datagram(now, batch.serialize());
+    let events = protocol.take_events();
+    assert!(!events
+        .iter()
+        .any(|event| matches!(event, Ok(Event::Message(_)))));
+}
+
+#[test]
+fn test_dropped_messages_share_one_placeholder() {
+    let now = std::time::Instant::now();
+    let mut protocol = Protocol::new("peer".to_string(), |_| {}, now);
+    let config = Config {
+        trace: false,
+        max_queued_messages: Some(10),
+        queue_policy: QueuePolicy::DropOldest,
+        ..Config::default()
+    };
+    protocol.set_config(config, now);
+
+    for i in 0..10_000u32 {
+        protocol.send_message(i.to_be_bytes().to_vec()).unwrap();
+    }
+    assert_eq!(protocol.queue_depth().messages, 10);
+    // Ten messages and the placeholder for all the others
+    assert!(
+        format!("{protocol:?}").contains("outgoing: 11,"),
+        "{protocol:?}"
+    );
+    assert_eq!(
+        protocol.delivery_status(MessageId(1)),
+        DeliveryStatus::Expired
+    );
+    assert_eq!(
+        protocol.delivery_status(MessageId(9_990)),
+        DeliveryStatus::Expired
+    );
+    assert_eq!(
+        protocol.delivery_status(MessageId(9_991)),
+        DeliveryStatus::Queued
+    );
+}
+
+#[test]
+fn test_expired_messages_share_one_placeholder() {
+    use std::time::Duration;
+
+    let now = std::time::Instant::now();
+    let mut protocol = Protocol::new("peer".to_string(), |_| {}, now);
+    protocol.set_config(sim_config(), now);
+    let ttl = Ttl {
+        deadline: Some(Duration::from_millis(100)),
+        ..Ttl::default()
+    };
+    for i in 0..1000u32 {
+        protocol
+            .send_message_with_ttl(now, i.to_be_bytes().to_vec(), ttl)
+            .unwrap();
+    }
+
+    protocol.handle_timeout(now + Duration::from_millis(200));
+    assert_eq!(protocol.queue_depth(), QueueDepth::default());
+    assert!(
+        format!("{protocol:?}").contains("outgoing: 1,"),
+        "{protocol:?}"
+    );
+    assert_eq!(
+        protocol.delivery_status(MessageId(500)),
+        DeliveryStatus::Expired
+    );
+}
+
+#[test]
+fn test_peer_skips_a_run_of_dropped_messages() {
+    use sim::Side;
+    use std::time::Duration;
+
+    let config = Config {
+        max_queued_messages: Some(2),
+        queue_policy: QueuePolicy::DropOldest,
+        ..sim_config()
+    };
+    let cut = sim::Link {
+        loss: 1.0,
+        ..sim::Link::default()
+    };
+    let mut sim = sim::Simulation::new(config, cut, 5);
+    for i in 0..500u32 {
+        sim.endpoint_mut(Side::Client)
+            .send_message(i.to_string().into_bytes())
+            .unwrap();
+    }
+
+    sim.set_link(sim::Link::default());
+    let done = sim.run_until(Duration::from_secs(10), |sim| {
+        format!("{:?}", sim.endpoint(Side::Client)).contains("outgoing: 0,")
+    });
+    assert!(done, "{:?}", sim.endpoint(Side::Client));
+    let received: Vec<_> = sim
+        .events(Side::Server)
+        .iter()
+        .filter_map(|(_, event)| match event {
+            Ok(Event::Message(msg)) => Some(msg.clone()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(received, [&b"498"[..], &b"499"[..]]);
+}
+
+#[test]
+fn test_expired_ids_merge_runs_and_forget_old_ones() {
+    let mut expired = ExpiredIds::new(2);
+    for id in [3, 5, 4] {
+        assert_eq!(expired.insert(id), None);
+    }
+    assert!((3..=5).all(|id| expired.contains(id)));
+    assert!(!expired.contains(2) && !expired.contains(6));
+
+    assert_eq!(expired.insert(8), None);
+    assert_eq!(expired.insert(10), Some(6));
+    assert!(!expired.contains(4));
+    assert!(expired.contains(8) && expired.contains(10));
+}
+
+#[test]
+fn test_zero_rate_pauses_the_pacer() {
+    use std::time::{Duration, Instant};
+
+    let start = Instant::now();
+    let mut pacer = pacer::Pacer::new(Some(0), 1000, start);
+    assert!(!pacer.can_send(start + Duration::from_secs(60)));
+    assert_eq!(pacer.ready_at(), None);
+
+    // No pacer deadline to wait for while paused
+    let now = Instant::now();
+    let mut protocol = Protocol::new("peer".to_string(), |_| {}, now);
+    protocol.set_rate_limit(Some(0), 1000, now);
+    protocol.send_message(&b"paused"[..]).unwrap();
+    protocol.poll_timeout();
+    assert!(protocol.poll_transmit(now).is_empty());
+
+    protocol.set_rate_limit(Some(1000), 1000, now);
+    assert_eq!(protocol.poll_transmit(now).len(), 1);
+}
+
+#[test]
+fn test_zero_burst_still_sends_one_datagram_at_a_time() {
+    use std::time::{Duration, Instant};
+
+    let start = Instant::now();
+    let mut pacer = pacer::Pacer::new(Some(1000), 0, start);
+    assert!(pacer.can_send(start));
+    pacer.on_sent(500);
+    assert!(!pacer.can_send(start + Duration::from_millis(100)));
+    let ready = pacer.ready_at().expect("not paused");
+    assert!(pacer.can_send(ready));
+}
+
+#[test]
+fn test_oversized_message_is_refused() {
+    let now = std::time::Instant::now();
+    let mut protocol = Protocol::new("peer".to_string(), |_| {}, now);
+    let max = protocol.max_payload();
+
+    assert_eq!(
+        protocol.send_message(vec![0u8; max + 1]),
+        Err(SendError::TooLarge { size: max + 1, max })
+    );
+    assert_eq!(protocol.queue_depth(), QueueDepth::default());
+    assert!(protocol.send_message(vec![0u8; max]).is_ok());
+}
+
+#[test]
+fn test_rpc_oversized_response_fails_the_call() {
+    let (client, server) = Pair::new().connect();
+    let (mut client, mut server) = (Rpc::new(client), Rpc::new(server));
+    // Too large for any datagram, even compressed
+    server.register("big", |_| Ok(vec![0u8; 1 << 20].into())).unwrap();
+
+    let call = client.call("big", &b""[..]).unwrap();
+    let mut outcome = None;
+    drive_until(
+        || {
+            server.work();
+            for event in client.work() {
+                if let Ok(RpcEvent::Response(id, result)) = event {
+                    assert_eq!(id, call);
+                    outcome = Some(result);
+                }
+            }
+            outcome.is_some()
+        },
+        TIMEOUT,
+    );
+
+    assert!(matches!(outcome, Some(Err(RpcError::Remote(_)))), "{outcome:?}");
+}
+
+#[cfg(feature = "lz4")]
+#[test]
+fn test_compressible_payload_past_the_limit_is_refused_not_lost() {
+    let now = std::time::Instant::now();
+    let config = Config {
+        mtu_probing: false,
+        ..Config::default()
+    };
+    let [mut sender, mut receiver] = [(); 2].map(|_| {
+        let mut protocol = Protocol::new("peer".to_string(), |_| {}, now);
+        protocol.set_config(config.clone(), now);
+        protocol.set_compression(Compression::Lz4);
+        protocol
+    });
+    let relay = |from: &mut Protocol, to: &mut Protocol| {
+        let datagrams: Vec<_> = from
+            .poll_transmit(now)
+            .iter()
+            .map(bytes::Bytes::copy_from_slice)
+            .collect();
+        for datagram in datagrams {
+            to.handle_datagram(now, datagram);
+        }
+    };
+
+    // Compressed it would fit a datagram, but the peer won't expand it
+    let zeros = vec![0u8; 200 * 1024];
+    assert_eq!(
+        sender.send_message(zeros.clone()),
+        Err(SendError::TooLarge {
+            size: 200 * 1024,
+            max: 64 * 1024
+        })
+    );
+
+    let id = sender.send_message(vec![0u8; 64 * 1024]).unwrap();
+    relay(&mut sender, &mut receiver);
+    relay(&mut receiver, &mut sender);
+    assert_eq!(sender.delivery_status(id), DeliveryStatus::Acked);
+    assert!(receiver
+        .take_events()
+        .iter()
+        .any(|event| matches!(event, Ok(Event::Message(msg)) if msg.len() == 64 * 1024)));
+
+    // Nor does it acknowledge a message it couldn't expand
+    let forged = Message::new_compressed(id.0 + 1, lz4_flex::compress_prepend_size(&zeros));
+    receiver.handle_datagram(now, forged.serialize());
+    assert!(receiver
+        .take_events()
+        .iter()
+        .any(|event| matches!(event, Err(ConnectionError::Malformed(_)))));
+    assert!(receiver.poll_transmit(now).is_empty());
+}
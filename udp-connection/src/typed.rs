@@ -0,0 +1,161 @@
+use std::marker::PhantomData;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    delivery::{MessageId, Ttl},
+    error::ConnectionError,
+    event::Event,
+    socket_worker::SocketWorker,
+};
+
+/// Turns values into message payloads and back, see [`TypedConnection`].
+///
+/// [`Json`], [`Bincode`] and [`Postcard`] come with the features of the
+/// same name. Both ends of a connection must use the same codec.
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String>;
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, String>;
+}
+
+/// JSON via `serde_json`, readable in a capture.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(value).map_err(|e| e.to_string())
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, String> {
+        serde_json::from_slice(data).map_err(|e| e.to_string())
+    }
+}
+
+/// `bincode` with its default options, compact and fast.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        bincode::serialize(value).map_err(|e| e.to_string())
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, String> {
+        bincode::deserialize(data).map_err(|e| e.to_string())
+    }
+}
+
+/// `postcard`, the most compact of the three.
+#[cfg(feature = "postcard")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        postcard::to_allocvec(value).map_err(|e| e.to_string())
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, String> {
+        postcard::from_bytes(data).map_err(|e| e.to_string())
+    }
+}
+
+/// Something that happened on a [`TypedConnection`].
+#[derive(Debug)]
+pub enum TypedEvent<T> {
+    /// A message from the peer, decoded.
+    Message(T),
+    /// Anything but a message, as [`SocketWorker::work`] reported it.
+    Other(Event),
+}
+
+/// A [`SocketWorker`] that sends and receives values of `T` instead of
+/// bytes, encoded with a [`Codec`].
+///
+/// A message that doesn't decode is reported as
+/// [`ConnectionError::Codec`] and the connection carries on.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use udp_connection::{send_handshake, Json, TypedConnection, TypedEvent};
+/// let worker = send_handshake("127.0.0.1:8080".to_string(), |_| {})?;
+/// let mut connection = TypedConnection::<(String, u64), _>::new(worker, Json);
+/// connection.send(&("answer".to_string(), 42))?;
+///
+/// loop {
+///     for event in connection.work() {
+///         match event {
+///             Ok(TypedEvent::Message((key, value))) => println!("{key} = {value}"),
+///             Ok(TypedEvent::Other(event)) => println!("{event:?}"),
+///             Err(e) => eprintln!("{e}"),
+///         }
+///     }
+/// }
+/// # Ok::<(), udp_connection::ConnectionError>(())
+/// ```
+pub struct TypedConnection<T, C> {
+    worker: SocketWorker,
+    codec: C,
+    _values: PhantomData<fn(T) -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned, C: Codec> TypedConnection<T, C> {
+    pub fn new(worker: SocketWorker, codec: C) -> TypedConnection<T, C> {
+        TypedConnection {
+            worker,
+            codec,
+            _values: PhantomData,
+        }
+    }
+
+    pub fn worker(&self) -> &SocketWorker {
+        &self.worker
+    }
+
+    /// For everything but typed messages, like streams and closing.
+    pub fn worker_mut(&mut self) -> &mut SocketWorker {
+        &mut self.worker
+    }
+
+    pub fn into_inner(self) -> SocketWorker {
+        self.worker
+    }
+
+    /// Encodes `value` and queues it, see [`SocketWorker::send_message`].
+    pub fn send(&mut self, value: &T) -> Result<MessageId, ConnectionError> {
+        let data = self.codec.encode(value).map_err(ConnectionError::Codec)?;
+        Ok(self.worker.send_message(data)?)
+    }
+
+    /// Like [`TypedConnection::send`], but gives up on the message as
+    /// `ttl` says, see [`SocketWorker::send_message_with_ttl`].
+    pub fn send_with_ttl(&mut self, value: &T, ttl: Ttl) -> Result<MessageId, ConnectionError> {
+        let data = self.codec.encode(value).map_err(ConnectionError::Codec)?;
+        Ok(self.worker.send_message_with_ttl(data, ttl)?)
+    }
+
+    /// Works the connection, see [`SocketWorker::work`], decoding the
+    /// messages received.
+    pub fn work(&mut self) -> Vec<Result<TypedEvent<T>, ConnectionError>> {
+        self.worker
+            .work()
+            .into_iter()
+            .map(|event| match event? {
+                Event::Message(data) => self
+                    .codec
+                    .decode(&data)
+                    .map(TypedEvent::Message)
+                    .map_err(ConnectionError::Codec),
+                event => Ok(TypedEvent::Other(event)),
+            })
+            .collect()
+    }
+}